@@ -0,0 +1,265 @@
+//! BRIN operator class for `hex`.
+//!
+//! Each block range is summarized by the `CubeBounds` of its hexes, stored as
+//! six `int4` values: the lower bounds of q, r and s, then their upper bounds.
+//! Nulls are tracked in the summary's own flags, as every supported Postgres
+//! version passes them to the opclass.
+
+use std::mem::size_of;
+
+use pgrx::prelude::*;
+use pgrx::Internal;
+
+use crate::gist::{consistent, datum};
+use crate::hex_alg::CubeBounds;
+use crate::Hex;
+
+/// Number of values stored in each summary
+const N_STORED: usize = 6;
+
+unsafe fn summary_bounds(column: &pg_sys::BrinValues) -> CubeBounds {
+    let values = unsafe { std::slice::from_raw_parts(column.bv_values, N_STORED) };
+    let value = |i: usize| values[i].value() as i32;
+    CubeBounds::new(
+        [value(0), value(1), value(2)],
+        [value(3), value(4), value(5)],
+    )
+}
+
+unsafe fn set_summary_bounds(column: &mut pg_sys::BrinValues, bounds: CubeBounds) {
+    let values = unsafe { std::slice::from_raw_parts_mut(column.bv_values, N_STORED) };
+    for (value, component) in values
+        .iter_mut()
+        .zip(bounds.min().iter().chain(&bounds.max()))
+    {
+        *value = pg_sys::Datum::from(*component);
+    }
+}
+
+#[pg_extern(immutable, strict, parallel_safe, sql = false)]
+fn hex_brin_opcinfo(_typoid: Internal) -> Internal {
+    unsafe {
+        let size =
+            size_of::<pg_sys::BrinOpcInfo>() + N_STORED * size_of::<*mut pg_sys::TypeCacheEntry>();
+        let info = pg_sys::palloc0(size) as *mut pg_sys::BrinOpcInfo;
+        (*info).oi_nstored = N_STORED as u16;
+        let int4 = pg_sys::lookup_type_cache(pg_sys::INT4OID, 0);
+        for entry in (*info).oi_typcache.as_mut_slice(N_STORED) {
+            *entry = int4;
+        }
+        Internal::from(Some(pg_sys::Datum::from(info)))
+    }
+}
+
+/// Widen the summary to include a new value, returning whether it changed
+#[pg_extern(immutable, strict, parallel_safe, sql = false)]
+fn hex_brin_add_value(
+    _bdesc: Internal,
+    mut column: Internal,
+    value: Internal,
+    isnull: bool,
+) -> bool {
+    unsafe {
+        let column = column
+            .get_mut::<pg_sys::BrinValues>()
+            .expect("column is not null");
+
+        if isnull {
+            if column.bv_hasnulls {
+                return false;
+            }
+            column.bv_hasnulls = true;
+            return true;
+        }
+
+        let hex = Hex::from_datum_bits(datum(value).value() as u64);
+        let point = CubeBounds::from_point(hex.into());
+        if column.bv_allnulls {
+            set_summary_bounds(column, point);
+            column.bv_allnulls = false;
+            return true;
+        }
+
+        let bounds = summary_bounds(column);
+        let union = bounds.union(point);
+        if union == bounds {
+            return false;
+        }
+        set_summary_bounds(column, union);
+        true
+    }
+}
+
+/// Whether the block range could contain rows matching the scan key
+#[pg_extern(immutable, strict, parallel_safe, sql = false)]
+fn hex_brin_consistent(_bdesc: Internal, column: Internal, key: Internal) -> bool {
+    unsafe {
+        let column = column
+            .get::<pg_sys::BrinValues>()
+            .expect("column is not null");
+        let key = key.get::<pg_sys::ScanKeyData>().expect("key is not null");
+
+        if key.sk_flags & pg_sys::SK_ISNULL as i32 != 0 {
+            if key.sk_flags & pg_sys::SK_SEARCHNULL as i32 != 0 {
+                return column.bv_allnulls || column.bv_hasnulls;
+            }
+            if key.sk_flags & pg_sys::SK_SEARCHNOTNULL as i32 != 0 {
+                return !column.bv_allnulls;
+            }
+            // The operators are all strict, so nothing matches a null query
+            return false;
+        }
+
+        if column.bv_allnulls {
+            return false;
+        }
+        consistent(&summary_bounds(column), key.sk_argument, key.sk_strategy)
+    }
+}
+
+/// Merge the summary in `b` into `a`
+#[pg_extern(immutable, strict, parallel_safe, sql = false)]
+fn hex_brin_union(_bdesc: Internal, mut a: Internal, b: Internal) -> bool {
+    unsafe {
+        let a = a.get_mut::<pg_sys::BrinValues>().expect("a is not null");
+        let b = b.get::<pg_sys::BrinValues>().expect("b is not null");
+
+        a.bv_hasnulls |= b.bv_hasnulls;
+        if b.bv_allnulls {
+            return true;
+        }
+        // An all-null summary's values are garbage, so take b's as they are
+        if a.bv_allnulls {
+            a.bv_allnulls = false;
+            set_summary_bounds(a, summary_bounds(b));
+            return true;
+        }
+        set_summary_bounds(a, summary_bounds(a).union(summary_bounds(b)));
+        true
+    }
+}
+
+extension_sql!(
+    r#"
+CREATE FUNCTION hex_brin_opcinfo(internal) RETURNS internal
+    IMMUTABLE STRICT PARALLEL SAFE
+    LANGUAGE c AS 'MODULE_PATHNAME', 'hex_brin_opcinfo_wrapper';
+
+CREATE FUNCTION hex_brin_add_value(internal, internal, internal, internal) RETURNS boolean
+    IMMUTABLE STRICT PARALLEL SAFE
+    LANGUAGE c AS 'MODULE_PATHNAME', 'hex_brin_add_value_wrapper';
+
+CREATE FUNCTION hex_brin_consistent(internal, internal, internal) RETURNS boolean
+    IMMUTABLE STRICT PARALLEL SAFE
+    LANGUAGE c AS 'MODULE_PATHNAME', 'hex_brin_consistent_wrapper';
+
+CREATE FUNCTION hex_brin_union(internal, internal, internal) RETURNS boolean
+    IMMUTABLE STRICT PARALLEL SAFE
+    LANGUAGE c AS 'MODULE_PATHNAME', 'hex_brin_union_wrapper';
+
+CREATE OPERATOR CLASS hex_brin_ops
+    DEFAULT FOR TYPE hex USING brin AS
+        OPERATOR 8 <@ (hex, hexrange),
+        OPERATOR 18 = (hex, hex),
+        FUNCTION 1 hex_brin_opcinfo(internal),
+        FUNCTION 2 hex_brin_add_value(internal, internal, internal, internal),
+        FUNCTION 3 hex_brin_consistent(internal, internal, internal),
+        FUNCTION 4 hex_brin_union(internal, internal, internal);
+"#,
+    name = "hex_brin_ops",
+    requires = ["hex_type", "hexrange_type", hex_eq, hex_within],
+);
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use crate::tests::{explain, explain_with};
+    use pgrx::prelude::*;
+
+    /// A table of positions logged as a unit walks steadily across the grid,
+    /// so rows inserted together are close together
+    fn create_indexed_telemetry() {
+        Spi::run("create table telemetry (tick int, position hex)").unwrap();
+        Spi::run(
+            "insert into telemetry
+            select i, format('(%s,%s)', i / 50, -(i / 100))::hex
+            from generate_series(0, 99999) i",
+        )
+        .unwrap();
+        Spi::run(
+            "create index telemetry_position_brin on telemetry
+            using brin (position) with (pages_per_range = 4)",
+        )
+        .unwrap();
+        Spi::run("analyze telemetry").unwrap();
+        Spi::run("set local enable_seqscan = off").unwrap();
+        // Parallel bitmap scans only report the blocks the leader read
+        Spi::run("set local max_parallel_workers_per_gather = 0").unwrap();
+    }
+
+    /// The number of heap blocks a bitmap scan for the query read
+    fn heap_blocks_read(query: &str) -> i64 {
+        let plan = explain_with("analyze, costs off, timing off, summary off", query);
+        let lossy = plan
+            .split("lossy=")
+            .nth(1)
+            .expect("a BRIN bitmap scan only reads lossy blocks");
+        lossy
+            .split(|c: char| !c.is_ascii_digit())
+            .next()
+            .unwrap()
+            .parse()
+            .unwrap()
+    }
+
+    #[pg_test]
+    fn test_brin_within() {
+        create_indexed_telemetry();
+
+        let query = "select count(*) from telemetry where position <@ hexrange('(1000,-500)', 3)";
+        assert!(explain(query).contains("telemetry_position_brin"));
+        let value = Spi::get_one::<i64>(query).unwrap().unwrap();
+        // Ticks 49850 to 50199 pass within 3 of (1000,-500)
+        assert_eq!(value, 350);
+
+        let total = Spi::get_one::<i64>(
+            "select relpages::bigint from pg_class where relname = 'telemetry'",
+        )
+        .unwrap()
+        .unwrap();
+        assert!(heap_blocks_read(query) < total / 20);
+    }
+
+    #[pg_test]
+    fn test_brin_equal() {
+        create_indexed_telemetry();
+
+        let query = "select count(*) from telemetry where position = '(1000,-500)'";
+        assert!(explain(query).contains("telemetry_position_brin"));
+        let value = Spi::get_one::<i64>(query).unwrap().unwrap();
+        assert_eq!(value, 50);
+
+        let value = Spi::get_one::<i64>("select count(*) from telemetry where position = '(0,5)'")
+            .unwrap()
+            .unwrap();
+        assert_eq!(value, 0);
+    }
+
+    #[pg_test]
+    fn test_brin_nulls() {
+        create_indexed_telemetry();
+        Spi::run("insert into telemetry values (100000, null), (100001, '(2000,-1000)')").unwrap();
+        Spi::run("select brin_summarize_new_values('telemetry_position_brin')").unwrap();
+
+        let value = Spi::get_one::<i64>("select count(*) from telemetry where position is null")
+            .unwrap()
+            .unwrap();
+        assert_eq!(value, 1);
+
+        let value =
+            Spi::get_one::<i64>("select count(*) from telemetry where position = '(2000,-1000)'")
+                .unwrap()
+                .unwrap();
+        assert_eq!(value, 1);
+    }
+}
@@ -0,0 +1,238 @@
+//! Named directions, for stepping to neighbors and diagonals and for telling
+//! which way one hex is from another.
+
+use pgrx::prelude::*;
+
+use crate::hex_alg::{self, CubeCoord, DIRECTIONS};
+use crate::Hex;
+
+/// One of the six directions to neighbors or the six to diagonals, in order
+/// counterclockwise from the direction of `(1,0)`. Each is named for where it
+/// points on a map of pointy-top hexes with north up; `hexdir_name` names
+/// them for flat-top hexes.
+#[derive(PostgresEnum, PartialEq, Eq, Debug, Copy, Clone)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum HexDir {
+    E,
+    ENE,
+    NE,
+    N,
+    NW,
+    WNW,
+    W,
+    WSW,
+    SW,
+    S,
+    SE,
+    ESE,
+}
+
+const HEX_DIRS: [HexDir; DIRECTIONS] = [
+    HexDir::E,
+    HexDir::ENE,
+    HexDir::NE,
+    HexDir::N,
+    HexDir::NW,
+    HexDir::WNW,
+    HexDir::W,
+    HexDir::WSW,
+    HexDir::SW,
+    HexDir::S,
+    HexDir::SE,
+    HexDir::ESE,
+];
+
+/// The name of each direction on a map of flat-top hexes, where every
+/// direction points 30° clockwise of where it does on a map of pointy-top
+/// hexes
+const FLAT_TOP_NAMES: [&str; DIRECTIONS] = [
+    "SE", "E", "NE", "NNE", "N", "NNW", "NW", "W", "SW", "SSW", "S", "SSE",
+];
+
+impl HexDir {
+    /// The number of this direction among the directions in `hex_alg`
+    pub fn index(self) -> usize {
+        self as usize
+    }
+
+    /// The direction numbered `index`, counting round again past the last
+    pub fn from_index(index: usize) -> Self {
+        HEX_DIRS[index % DIRECTIONS]
+    }
+
+    pub fn is_diagonal(self) -> bool {
+        self.index() % 2 == 1
+    }
+
+    /// This direction turned by `steps` sixths of a turn, counterclockwise
+    /// for positive steps and clockwise for negative ones
+    pub fn rotate(self, steps: i32) -> Self {
+        let index = (self.index() as i64 + 2 * steps as i64).rem_euclid(DIRECTIONS as i64);
+        Self::from_index(index as usize)
+    }
+
+    pub fn offset(self) -> CubeCoord {
+        hex_alg::direction_offset(self.index())
+    }
+}
+
+/// Raise an error unless `direction` of the argument `name` is one of the six
+/// directions to neighbors
+pub fn towards_neighbor(name: &str, direction: HexDir) -> HexDir {
+    if direction.is_diagonal() {
+        ereport!(
+            ERROR,
+            PgSqlErrorCode::ERRCODE_INVALID_PARAMETER_VALUE,
+            format!(
+                "{} must be towards a neighbor, but {:?} is towards a diagonal",
+                name, direction
+            )
+        );
+    }
+    direction
+}
+
+/// The neighbor of `coord` in `direction`, which must be one of the six
+/// directions to neighbors
+#[pg_extern(immutable, strict, parallel_safe)]
+fn hex_neighbor(coord: Hex, direction: HexDir) -> Hex {
+    (CubeCoord::from(coord) + towards_neighbor("direction", direction).offset()).into()
+}
+
+/// The diagonal of `coord` in `direction`, which must be one of the six
+/// directions to diagonals
+#[pg_extern(immutable, strict, parallel_safe)]
+fn hex_diagonal(coord: Hex, direction: HexDir) -> Hex {
+    if !direction.is_diagonal() {
+        ereport!(
+            ERROR,
+            PgSqlErrorCode::ERRCODE_INVALID_PARAMETER_VALUE,
+            format!(
+                "direction must be towards a diagonal, but {:?} is towards a neighbor",
+                direction
+            )
+        );
+    }
+    (CubeCoord::from(coord) + direction.offset()).into()
+}
+
+/// The direction from `coord` to `other`, or the nearest one if `other`
+/// isn't exactly in any direction, unless `exact_only`. There's no direction
+/// from a hex to itself.
+#[pg_extern(immutable, strict, parallel_safe)]
+fn direction_between(coord: Hex, other: Hex, exact_only: default!(bool, false)) -> Option<HexDir> {
+    let (direction, exact) = hex_alg::direction_between(coord.into(), other.into())?;
+    (exact || !exact_only).then(|| HexDir::from_index(direction))
+}
+
+/// `direction` turned by `steps` sixths of a turn, counterclockwise for
+/// positive steps and clockwise for negative ones, so that directions to
+/// neighbors stay directions to neighbors
+#[pg_extern(immutable, strict, parallel_safe)]
+fn hexdir_rotate(direction: HexDir, steps: i32) -> HexDir {
+    direction.rotate(steps)
+}
+
+/// The name of `direction` on a map of `pointy` or `flat` top hexes with north
+/// up and `(1,-1)` to the northeast
+#[pg_extern(immutable, strict, parallel_safe)]
+fn hexdir_name(direction: HexDir, orientation: default!(&str, "'pointy'")) -> String {
+    match orientation {
+        "pointy" => format!("{:?}", direction),
+        "flat" => FLAT_TOP_NAMES[direction.index()].to_string(),
+        _ => ereport!(
+            ERROR,
+            PgSqlErrorCode::ERRCODE_INVALID_PARAMETER_VALUE,
+            format!("orientation must be pointy or flat, but is {}", orientation)
+        ),
+    }
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use crate::*;
+
+    fn text(query: &str) -> Option<String> {
+        Spi::get_one::<String>(&format!("select ({})::text", query)).unwrap()
+    }
+
+    #[pg_test]
+    fn test_hexdir_order() {
+        assert_eq!(
+            text("array_to_string(enum_range(null::hexdir), ' ')").as_deref(),
+            Some("E ENE NE N NW WNW W WSW SW S SE ESE")
+        );
+    }
+
+    #[pg_test]
+    fn test_hex_neighbor() {
+        assert_eq!(
+            text("hex_neighbor('(0,0)', 'NE')").as_deref(),
+            Some("(1,-1)")
+        );
+        let same = Spi::get_one::<bool>(
+            "select array(
+                select hex_neighbor('(2,3)', direction)
+                from unnest(array['E', 'NE', 'NW', 'W', 'SW', 'SE']::hexdir[]) direction
+            ) = array(select neighbors('(2,3)'))",
+        )
+        .unwrap();
+        assert_eq!(same, Some(true));
+    }
+
+    #[pg_test]
+    fn test_hex_diagonal() {
+        assert_eq!(
+            text("hex_diagonal('(0,0)', 'N')").as_deref(),
+            Some("(1,-2)")
+        );
+        let same = Spi::get_one::<bool>(
+            "select array(
+                select hex_diagonal('(2,3)', direction)
+                from unnest(array['ENE', 'N', 'WNW', 'WSW', 'S', 'ESE']::hexdir[]) direction
+            ) = array(select diagonals('(2,3)'))",
+        )
+        .unwrap();
+        assert_eq!(same, Some(true));
+    }
+
+    #[pg_test(error = "direction must be towards a neighbor, but N is towards a diagonal")]
+    fn test_hex_neighbor_diagonal() {
+        Spi::run("select hex_neighbor('(0,0)', 'N')").unwrap();
+    }
+
+    #[pg_test(error = "direction must be towards a diagonal, but SE is towards a neighbor")]
+    fn test_hex_diagonal_neighbor() {
+        Spi::run("select hex_diagonal('(0,0)', 'SE')").unwrap();
+    }
+
+    #[pg_test]
+    fn test_direction_between() {
+        let between = |query: &str| text(&format!("direction_between({})", query));
+        assert_eq!(between("'(0,0)', '(3,-3)'").as_deref(), Some("NE"));
+        assert_eq!(between("'(1,1)', '(-1,5)'").as_deref(), Some("S"));
+        assert_eq!(between("'(0,0)', '(5,-1)'").as_deref(), Some("E"));
+        assert_eq!(between("'(0,0)', '(5,-1)', true"), None);
+        assert_eq!(between("'(2,2)', '(2,2)'"), None);
+    }
+
+    #[pg_test]
+    fn test_hexdir_rotate() {
+        assert_eq!(text("hexdir_rotate('E', 1)").as_deref(), Some("NE"));
+        assert_eq!(text("hexdir_rotate('ENE', -1)").as_deref(), Some("ESE"));
+        assert_eq!(text("hexdir_rotate('W', 9)").as_deref(), Some("E"));
+    }
+
+    #[pg_test]
+    fn test_hexdir_name() {
+        assert_eq!(text("hexdir_name('WSW')").as_deref(), Some("WSW"));
+        assert_eq!(text("hexdir_name('E', 'flat')").as_deref(), Some("SE"));
+        assert_eq!(text("hexdir_name('N', 'flat')").as_deref(), Some("NNE"));
+    }
+
+    #[pg_test(error = "orientation must be pointy or flat, but is sideways")]
+    fn test_hexdir_name_orientation() {
+        Spi::run("select hexdir_name('E', 'sideways')").unwrap();
+    }
+}
@@ -0,0 +1,247 @@
+//! Units that face one of their neighbors, and which way other hexes are from
+//! them: in front, on a flank or behind.
+
+use pgrx::prelude::*;
+
+use crate::direction::{towards_neighbor, HexDir};
+use crate::hex_alg::{self, CubeCoord};
+use crate::Hex;
+
+/// The 60° sectors around a unit, each facing one of its neighbors, in order
+/// counterclockwise from the one it faces
+const SECTORS: [&str; 6] = [
+    "front",
+    "front_left",
+    "rear_left",
+    "rear",
+    "rear_right",
+    "front_right",
+];
+
+/// The order in which to pick the sector of a hex on the line between two,
+/// from the front round to the rear
+const SECTORS_FRONT_FIRST: [usize; 6] = [0, 1, 5, 2, 4, 3];
+
+/// A unit's position and the direction it faces, which is always towards one
+/// of its neighbors.
+///
+/// In Postgres this is the composite type `hexfacing`.
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub struct HexFacing {
+    pub(crate) position: Hex,
+    pub(crate) facing: HexDir,
+}
+
+impl HexFacing {
+    fn new(position: Hex, facing: HexDir) -> Self {
+        Self {
+            position,
+            facing: towards_neighbor("facing", facing),
+        }
+    }
+
+    pub fn from_tuple(tuple: pgrx::composite_type!("hexfacing")) -> Self {
+        Self::new(field(&tuple, "position"), field(&tuple, "facing"))
+    }
+
+    fn into_tuple(self) -> pgrx::composite_type!('static, "hexfacing") {
+        let mut tuple = PgHeapTuple::new_composite_type("hexfacing")
+            .unwrap_or_else(|e| error!("could not make a hexfacing: {}", e));
+        tuple
+            .set_by_name("position", self.position)
+            .and_then(|()| tuple.set_by_name("facing", self.facing))
+            .unwrap_or_else(|e| error!("could not make a hexfacing: {}", e));
+        tuple
+    }
+
+    /// Whether `target` is in the sector numbered `sector`, edges included
+    fn in_sector(&self, sector: usize, target: Hex) -> bool {
+        let direction = (self.facing.index() / 2 + sector) % 6;
+        hex_alg::in_wedge(self.position.into(), direction, CubeCoord::from(target))
+    }
+}
+
+/// The field `name` of the `hexfacing` in `tuple`, which must not be null
+fn field<T: FromDatum + IntoDatum>(tuple: &pgrx::composite_type!("hexfacing"), name: &str) -> T {
+    match tuple.get_by_name::<T>(name) {
+        Ok(Some(value)) => value,
+        Ok(None) => ereport!(
+            ERROR,
+            PgSqlErrorCode::ERRCODE_NULL_VALUE_NOT_ALLOWED,
+            format!("hexfacing {} must not be null", name)
+        ),
+        Err(e) => error!("could not read hexfacing {}: {}", name, e),
+    }
+}
+
+/// The sectors named in `arc_spec`, as a bit for each of `SECTORS`
+fn arc_sectors(arc_spec: &str) -> u8 {
+    arc_spec.split(',').map(str::trim).fold(0, |sectors, name| {
+        sectors
+            | match name {
+                "left" => 0b000110,
+                "right" => 0b110000,
+                "flank" => 0b110110,
+                _ => match SECTORS.iter().position(|&sector| sector == name) {
+                    Some(sector) => 1 << sector,
+                    None => ereport!(
+                        ERROR,
+                        PgSqlErrorCode::ERRCODE_INVALID_PARAMETER_VALUE,
+                        format!(
+                            "arcs in arc_spec must be front, front_left, front_right, rear_left, \
+                            rear_right, rear, left, right or flank, but one is {}",
+                            name
+                        )
+                    ),
+                },
+            }
+    })
+}
+
+extension_sql!(
+    r#"
+CREATE TYPE hexfacing AS (
+    position hex,
+    facing hexdir
+);
+"#,
+    name = "hexfacing_type",
+    requires = ["hex_type", HexDir],
+);
+
+/// A unit at `position` facing `facing`, which must be one of the six
+/// directions to neighbors
+#[pg_extern(immutable, strict, parallel_safe, requires = ["hexfacing_type"])]
+fn hexfacing(position: Hex, facing: HexDir) -> pgrx::composite_type!('static, "hexfacing") {
+    HexFacing::new(position, facing).into_tuple()
+}
+
+/// `facing` turned in place by `steps` sixths of a turn, counterclockwise for
+/// positive steps and clockwise for negative ones
+#[pg_extern(immutable, strict, parallel_safe, requires = ["hexfacing_type"])]
+fn rotate(
+    facing: pgrx::composite_type!("hexfacing"),
+    steps: i32,
+) -> pgrx::composite_type!('static, "hexfacing") {
+    let facing = HexFacing::from_tuple(facing);
+    HexFacing::new(facing.position, facing.facing.rotate(steps)).into_tuple()
+}
+
+/// Whether `target` is in any of the sectors around `facing` listed in
+/// `arc_spec`, separated by commas. The sectors are the 60° wedges facing each
+/// neighbor, bounded by the lines through the corners of the unit's hex:
+/// `front`, `front_left`, `rear_left`, `rear`, `rear_right` and
+/// `front_right`, with `left`, `right` and `flank` for the sectors on the
+/// left, on the right and on both. A hex on the line between two sectors is
+/// in both, and the unit's own hex is in every arc.
+#[pg_extern(immutable, strict, parallel_safe, requires = ["hexfacing_type"])]
+fn in_arc(facing: pgrx::composite_type!("hexfacing"), target: Hex, arc_spec: &str) -> bool {
+    let sectors = arc_sectors(arc_spec);
+    let facing = HexFacing::from_tuple(facing);
+    (0..SECTORS.len()).any(|sector| sectors & 1 << sector != 0 && facing.in_sector(sector, target))
+}
+
+/// Which of the sectors around `facing` that `in_arc` names `target` is in. A
+/// hex on the line between two sectors is in the one nearer the front, and
+/// the unit's own hex is in none.
+#[pg_extern(immutable, strict, parallel_safe, requires = ["hexfacing_type"])]
+fn relative_sector(facing: pgrx::composite_type!("hexfacing"), target: Hex) -> Option<String> {
+    let facing = HexFacing::from_tuple(facing);
+    if target == facing.position {
+        return None;
+    }
+    SECTORS_FRONT_FIRST
+        .into_iter()
+        .find(|&sector| facing.in_sector(sector, target))
+        .map(|sector| SECTORS[sector].to_string())
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use crate::*;
+
+    fn text(query: &str) -> Option<String> {
+        Spi::get_one::<String>(&format!("select ({})::text", query)).unwrap()
+    }
+
+    fn in_arc(target: &str, arc_spec: &str) -> bool {
+        Spi::get_one::<bool>(&format!(
+            "select in_arc(hexfacing('(0,0)', 'E'), '{}', '{}')",
+            target, arc_spec
+        ))
+        .unwrap()
+        .unwrap()
+    }
+
+    #[pg_test]
+    fn test_hexfacing() {
+        assert_eq!(
+            text("hexfacing('(1,2)', 'SW')").as_deref(),
+            Some("(\"(1,2)\",SW)")
+        );
+        assert_eq!(
+            text("(rotate(hexfacing('(1,2)', 'E'), 2)).facing").as_deref(),
+            Some("NW")
+        );
+        assert_eq!(
+            text("rotate(row('(1,2)', 'E')::hexfacing, -7)").as_deref(),
+            Some("(\"(1,2)\",SE)")
+        );
+    }
+
+    #[pg_test(error = "facing must be towards a neighbor, but N is towards a diagonal")]
+    fn test_hexfacing_diagonal() {
+        Spi::run("select hexfacing('(0,0)', 'N')").unwrap();
+    }
+
+    #[pg_test(error = "hexfacing position must not be null")]
+    fn test_hexfacing_null_position() {
+        Spi::run("select rotate(row(null, 'E')::hexfacing, 1)").unwrap();
+    }
+
+    #[pg_test]
+    fn test_relative_sector() {
+        let sector = |target: &str| {
+            text(&format!(
+                "relative_sector(hexfacing('(0,0)', 'E'), '{}')",
+                target
+            ))
+        };
+        assert_eq!(sector("(3,0)").as_deref(), Some("front"));
+        assert_eq!(sector("(1,-1)").as_deref(), Some("front_left"));
+        assert_eq!(sector("(0,-1)").as_deref(), Some("rear_left"));
+        assert_eq!(sector("(-1,0)").as_deref(), Some("rear"));
+        assert_eq!(sector("(-1,1)").as_deref(), Some("rear_right"));
+        assert_eq!(sector("(0,1)").as_deref(), Some("front_right"));
+        // On the lines between sectors, the one nearer the front
+        assert_eq!(sector("(2,-1)").as_deref(), Some("front"));
+        assert_eq!(sector("(1,-2)").as_deref(), Some("front_left"));
+        assert_eq!(sector("(-2,1)").as_deref(), Some("rear_right"));
+        assert_eq!(sector("(2000,-1000)").as_deref(), Some("front"));
+        assert_eq!(sector("(2000,-1001)").as_deref(), Some("front_left"));
+        assert_eq!(sector("(0,0)"), None);
+    }
+
+    #[pg_test]
+    fn test_in_arc() {
+        assert!(in_arc("(3,0)", "front"));
+        assert!(!in_arc("(3,0)", "flank"));
+        assert!(in_arc("(0,-1)", "flank"));
+        assert!(in_arc("(0,-1)", "left"));
+        assert!(!in_arc("(0,-1)", "right"));
+        assert!(in_arc("(-1,0)", "left, rear"));
+        // Both sectors either side of the line between them
+        assert!(in_arc("(2000,-1000)", "front"));
+        assert!(in_arc("(2000,-1000)", "front_left"));
+        assert!(!in_arc("(2000,-1000)", "rear_left,front_right"));
+        assert!(in_arc("(0,0)", "rear"));
+    }
+
+    #[pg_test(
+        error = "arcs in arc_spec must be front, front_left, front_right, rear_left, rear_right, rear, left, right or flank, but one is behind"
+    )]
+    fn test_in_arc_unknown() {
+        in_arc("(1,0)", "front,behind");
+    }
+}
@@ -0,0 +1,400 @@
+//! GiST operator class for `hex`.
+//!
+//! Index keys are `CubeBounds` boxes, stored as the `hexbox` type. Leaf keys
+//! bound exactly one hex, so every check made against a leaf is exact and
+//! nothing needs rechecking.
+
+use core::ffi::CStr;
+use std::mem::size_of;
+
+use pgrx::ffi::CString;
+use pgrx::prelude::*;
+use pgrx::Internal;
+
+use crate::hex_alg::CubeBounds;
+use crate::hexrange::HexRange;
+use crate::Hex;
+
+/// GiST numbers the entries passed to picksplit from 1, like page offsets
+const FIRST_OFFSET_NUMBER: usize = 1;
+
+/// The datum an `Internal` argument was called with
+pub(crate) fn datum(internal: Internal) -> pg_sys::Datum {
+    internal
+        .unwrap()
+        .expect("strict function called with a null argument")
+}
+
+unsafe fn bounds_from_datum(datum: pg_sys::Datum) -> CubeBounds {
+    unsafe { datum.cast_mut_ptr::<CubeBounds>().read_unaligned() }
+}
+
+fn bounds_into_datum(bounds: CubeBounds) -> pg_sys::Datum {
+    unsafe {
+        let ptr = pg_sys::palloc(size_of::<CubeBounds>()) as *mut CubeBounds;
+        ptr.write(bounds);
+        pg_sys::Datum::from(ptr)
+    }
+}
+
+/// A copy of `original` with its key replaced
+unsafe fn replace_key(original: &pg_sys::GISTENTRY, key: pg_sys::Datum) -> Internal {
+    unsafe {
+        let entry = pg_sys::palloc(size_of::<pg_sys::GISTENTRY>()) as *mut pg_sys::GISTENTRY;
+        entry.write(pg_sys::GISTENTRY {
+            key,
+            rel: original.rel,
+            page: original.page,
+            offset: original.offset,
+            leafkey: false,
+        });
+        Internal::from(Some(pg_sys::Datum::from(entry)))
+    }
+}
+
+/// Whether anything inside `bounds` could match the query. Also used by the
+/// SP-GiST and BRIN operator classes, which share these strategy numbers.
+pub(crate) unsafe fn consistent(bounds: &CubeBounds, query: pg_sys::Datum, strategy: u16) -> bool {
+    match strategy as u32 {
+        pg_sys::RTEqualStrategyNumber => {
+            bounds.contains(Hex::from_datum_bits(query.value() as u64).into())
+        }
+        pg_sys::RTContainedByStrategyNumber => {
+            let range =
+                unsafe { HexRange::from_datum(query, false) }.expect("hexrange query is not null");
+            bounds.min_dist(range.center.into()) <= range.radius as i64
+        }
+        _ => error!("unrecognized hex GiST strategy number: {}", strategy),
+    }
+}
+
+#[pg_extern(immutable, strict, parallel_safe, sql = false)]
+fn hexbox_in(_input: &CStr) -> Internal {
+    ereport!(
+        ERROR,
+        PgSqlErrorCode::ERRCODE_FEATURE_NOT_SUPPORTED,
+        "hexbox is only used as the storage type of the hex GiST index, and can't be input"
+    )
+}
+
+#[pg_extern(immutable, strict, parallel_safe, sql = false)]
+fn hexbox_out(value: Internal) -> CString {
+    let bounds = unsafe { bounds_from_datum(datum(value)) };
+    CString::new(bounds.to_string()).expect("hexbox text form contains no nul bytes")
+}
+
+#[pg_extern(immutable, strict, parallel_safe, sql = false)]
+fn hex_gist_consistent(
+    entry: Internal,
+    query: Internal,
+    strategy: i16,
+    _subtype: pg_sys::Oid,
+    mut recheck: Internal,
+) -> bool {
+    unsafe {
+        let entry = entry.get::<pg_sys::GISTENTRY>().expect("entry is not null");
+        *recheck.get_mut::<bool>().expect("recheck is not null") = false;
+        consistent(&bounds_from_datum(entry.key), datum(query), strategy as u16)
+    }
+}
+
+#[pg_extern(immutable, strict, parallel_safe, sql = false)]
+fn hex_gist_union(entryvec: Internal, mut size: Internal) -> Internal {
+    unsafe {
+        let entryvec = entryvec
+            .get::<pg_sys::GistEntryVector>()
+            .expect("entryvec is not null");
+        let union = entryvec
+            .vector
+            .as_slice(entryvec.n as usize)
+            .iter()
+            .map(|entry| bounds_from_datum(entry.key))
+            .reduce(CubeBounds::union)
+            .expect("GiST never takes the union of no entries");
+
+        *size.get_mut::<i32>().expect("size is not null") = size_of::<CubeBounds>() as i32;
+        Internal::from(Some(bounds_into_datum(union)))
+    }
+}
+
+#[pg_extern(immutable, strict, parallel_safe, sql = false)]
+fn hex_gist_compress(entry: Internal) -> Internal {
+    unsafe {
+        let original = entry.get::<pg_sys::GISTENTRY>().expect("entry is not null");
+        if !original.leafkey {
+            return entry;
+        }
+
+        let hex = Hex::from_datum_bits(original.key.value() as u64);
+        replace_key(
+            original,
+            bounds_into_datum(CubeBounds::from_point(hex.into())),
+        )
+    }
+}
+
+/// Reconstruct the indexed hex from a leaf key, for index-only scans
+#[pg_extern(immutable, strict, parallel_safe, sql = false)]
+fn hex_gist_fetch(entry: Internal) -> Internal {
+    unsafe {
+        let original = entry.get::<pg_sys::GISTENTRY>().expect("entry is not null");
+        let Some(coord) = bounds_from_datum(original.key).as_point() else {
+            error!("hex GiST leaf key doesn't bound exactly one hex");
+        };
+
+        replace_key(
+            original,
+            pg_sys::Datum::from(Hex::from(coord).to_datum_bits()),
+        )
+    }
+}
+
+/// How much the bounds of `original` have to grow to fit `new`
+#[pg_extern(immutable, strict, parallel_safe, sql = false)]
+fn hex_gist_penalty(original: Internal, new: Internal, mut penalty: Internal) -> Internal {
+    unsafe {
+        let original = bounds_from_datum(
+            original
+                .get::<pg_sys::GISTENTRY>()
+                .expect("original is not null")
+                .key,
+        );
+        let new = bounds_from_datum(new.get::<pg_sys::GISTENTRY>().expect("new is not null").key);
+
+        *penalty.get_mut::<f32>().expect("penalty is not null") =
+            (original.union(new).extent() - original.extent()) as f32;
+    }
+    penalty
+}
+
+#[pg_extern(immutable, strict, parallel_safe, sql = false)]
+fn hex_gist_picksplit(entryvec: Internal, mut splitvec: Internal) -> Internal {
+    unsafe {
+        let entryvec = entryvec
+            .get::<pg_sys::GistEntryVector>()
+            .expect("entryvec is not null");
+        let entries = entryvec.vector.as_slice(entryvec.n as usize);
+        let offsets = (FIRST_OFFSET_NUMBER..entries.len()).collect::<Vec<_>>();
+        let bounds = offsets
+            .iter()
+            .map(|&offset| bounds_from_datum(entries[offset].key))
+            .collect::<Vec<_>>();
+
+        let (left, right) = CubeBounds::split(&bounds);
+
+        let split = splitvec
+            .get_mut::<pg_sys::GIST_SPLITVEC>()
+            .expect("splitvec is not null");
+        (split.spl_left, split.spl_nleft, split.spl_ldatum) = split_side(&left, &offsets, &bounds);
+        (split.spl_right, split.spl_nright, split.spl_rdatum) =
+            split_side(&right, &offsets, &bounds);
+    }
+    splitvec
+}
+
+/// The offsets, count and union of the entries on one side of a split
+unsafe fn split_side(
+    side: &[usize],
+    offsets: &[usize],
+    bounds: &[CubeBounds],
+) -> (*mut pg_sys::OffsetNumber, i32, pg_sys::Datum) {
+    let list = unsafe {
+        pg_sys::palloc(side.len() * size_of::<pg_sys::OffsetNumber>()) as *mut pg_sys::OffsetNumber
+    };
+    for (i, &entry) in side.iter().enumerate() {
+        unsafe { list.add(i).write(offsets[entry] as pg_sys::OffsetNumber) };
+    }
+
+    let union = side
+        .iter()
+        .map(|&entry| bounds[entry])
+        .reduce(CubeBounds::union)
+        .expect("neither side of a split is empty");
+
+    (list, side.len() as i32, bounds_into_datum(union))
+}
+
+#[pg_extern(immutable, strict, parallel_safe, sql = false)]
+fn hex_gist_same(left: Internal, right: Internal, mut result: Internal) -> Internal {
+    unsafe {
+        *result.get_mut::<bool>().expect("result is not null") =
+            bounds_from_datum(datum(left)) == bounds_from_datum(datum(right));
+    }
+    result
+}
+
+/// The hex distance from the query to the entry, or a lower bound on the
+/// distance to anything under it for internal entries
+#[pg_extern(immutable, strict, parallel_safe, sql = false)]
+fn hex_gist_distance(
+    entry: Internal,
+    query: Internal,
+    strategy: i16,
+    _subtype: pg_sys::Oid,
+    mut recheck: Internal,
+) -> f64 {
+    unsafe {
+        let entry = entry.get::<pg_sys::GISTENTRY>().expect("entry is not null");
+        *recheck.get_mut::<bool>().expect("recheck is not null") = false;
+
+        match strategy as u32 {
+            pg_sys::RTKNNSearchStrategyNumber => {
+                let query = Hex::from_datum_bits(datum(query).value() as u64);
+                bounds_from_datum(entry.key).min_dist(query.into()) as f64
+            }
+            _ => error!("unrecognized hex GiST strategy number: {}", strategy),
+        }
+    }
+}
+
+extension_sql!(
+    r#"
+CREATE TYPE hexbox;
+
+CREATE FUNCTION hexbox_in(cstring) RETURNS hexbox
+    IMMUTABLE STRICT PARALLEL SAFE
+    LANGUAGE c AS 'MODULE_PATHNAME', 'hexbox_in_wrapper';
+
+CREATE FUNCTION hexbox_out(hexbox) RETURNS cstring
+    IMMUTABLE STRICT PARALLEL SAFE
+    LANGUAGE c AS 'MODULE_PATHNAME', 'hexbox_out_wrapper';
+
+CREATE TYPE hexbox (
+    INPUT = hexbox_in,
+    OUTPUT = hexbox_out,
+    INTERNALLENGTH = 24,
+    ALIGNMENT = int4,
+    STORAGE = plain
+);
+
+CREATE FUNCTION hex_gist_consistent(internal, hex, smallint, oid, internal) RETURNS boolean
+    IMMUTABLE STRICT PARALLEL SAFE
+    LANGUAGE c AS 'MODULE_PATHNAME', 'hex_gist_consistent_wrapper';
+
+CREATE FUNCTION hex_gist_union(internal, internal) RETURNS hexbox
+    IMMUTABLE STRICT PARALLEL SAFE
+    LANGUAGE c AS 'MODULE_PATHNAME', 'hex_gist_union_wrapper';
+
+CREATE FUNCTION hex_gist_compress(internal) RETURNS internal
+    IMMUTABLE STRICT PARALLEL SAFE
+    LANGUAGE c AS 'MODULE_PATHNAME', 'hex_gist_compress_wrapper';
+
+CREATE FUNCTION hex_gist_penalty(internal, internal, internal) RETURNS internal
+    IMMUTABLE STRICT PARALLEL SAFE
+    LANGUAGE c AS 'MODULE_PATHNAME', 'hex_gist_penalty_wrapper';
+
+CREATE FUNCTION hex_gist_picksplit(internal, internal) RETURNS internal
+    IMMUTABLE STRICT PARALLEL SAFE
+    LANGUAGE c AS 'MODULE_PATHNAME', 'hex_gist_picksplit_wrapper';
+
+CREATE FUNCTION hex_gist_same(hexbox, hexbox, internal) RETURNS internal
+    IMMUTABLE STRICT PARALLEL SAFE
+    LANGUAGE c AS 'MODULE_PATHNAME', 'hex_gist_same_wrapper';
+
+CREATE FUNCTION hex_gist_distance(internal, hex, smallint, oid, internal) RETURNS double precision
+    IMMUTABLE STRICT PARALLEL SAFE
+    LANGUAGE c AS 'MODULE_PATHNAME', 'hex_gist_distance_wrapper';
+
+CREATE FUNCTION hex_gist_fetch(internal) RETURNS internal
+    IMMUTABLE STRICT PARALLEL SAFE
+    LANGUAGE c AS 'MODULE_PATHNAME', 'hex_gist_fetch_wrapper';
+
+CREATE OPERATOR CLASS hex_gist_ops
+    DEFAULT FOR TYPE hex USING gist AS
+        OPERATOR 8 <@ (hex, hexrange),
+        OPERATOR 15 <-> (hex, hex) FOR ORDER BY pg_catalog.integer_ops,
+        OPERATOR 18 = (hex, hex),
+        FUNCTION 1 hex_gist_consistent(internal, hex, smallint, oid, internal),
+        FUNCTION 2 hex_gist_union(internal, internal),
+        FUNCTION 3 hex_gist_compress(internal),
+        FUNCTION 5 hex_gist_penalty(internal, internal, internal),
+        FUNCTION 6 hex_gist_picksplit(internal, internal),
+        FUNCTION 7 hex_gist_same(hexbox, hexbox, internal),
+        FUNCTION 8 hex_gist_distance(internal, hex, smallint, oid, internal),
+        FUNCTION 9 hex_gist_fetch(internal),
+        STORAGE hexbox;
+"#,
+    name = "hex_gist_ops",
+    requires = [
+        "hex_type",
+        "hexrange_type",
+        hex_eq,
+        hex_within,
+        hex_distance
+    ],
+);
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use crate::tests::explain;
+    use pgrx::prelude::*;
+
+    fn create_indexed_tiles() {
+        Spi::run("create table tiles (coord hex)").unwrap();
+        Spi::run("insert into tiles select hexes_in_range('(0,0)', 30)").unwrap();
+        Spi::run("create index tiles_coord_gist on tiles using gist (coord)").unwrap();
+        Spi::run("analyze tiles").unwrap();
+        Spi::run("set local enable_seqscan = off").unwrap();
+    }
+
+    #[pg_test]
+    fn test_gist_within() {
+        create_indexed_tiles();
+
+        let query = "select count(*) from tiles where coord <@ hexrange('(3,-7)', 4)";
+        assert!(explain(query).contains("tiles_coord_gist"));
+        let value = Spi::get_one::<i64>(query).unwrap().unwrap();
+        assert_eq!(value, 61);
+
+        // Partly off the edge of the indexed tiles
+        let value =
+            Spi::get_one::<i64>("select count(*) from tiles where coord <@ hexrange('(30,0)', 1)")
+                .unwrap()
+                .unwrap();
+        assert_eq!(value, 4);
+    }
+
+    #[pg_test]
+    fn test_gist_equal() {
+        create_indexed_tiles();
+
+        let query = "select count(*) from tiles where coord = '(-12,5)'";
+        assert!(explain(query).contains("tiles_coord_gist"));
+        let value = Spi::get_one::<i64>(query).unwrap().unwrap();
+        assert_eq!(value, 1);
+    }
+
+    #[pg_test]
+    fn test_gist_knn() {
+        create_indexed_tiles();
+
+        let query = "select coord from tiles order by coord <-> '(5,5)' limit 19";
+        assert!(explain(query).contains("tiles_coord_gist"));
+        let value = Spi::get_one::<i64>(&format!(
+            "select count(*) from ({}) nearest where hex_distance(coord, '(5,5)') <= 2",
+            query
+        ))
+        .unwrap()
+        .unwrap();
+        assert_eq!(value, 19);
+
+        let value = Spi::get_one::<String>(&format!(
+            "select string_agg(hex_distance(coord, '(5,5)')::text, ',') from ({}) nearest",
+            query
+        ))
+        .unwrap()
+        .unwrap();
+        assert_eq!(value, "0,1,1,1,1,1,1,2,2,2,2,2,2,2,2,2,2,2,2");
+    }
+
+    #[pg_test(
+        error = "conflicting key value violates exclusion constraint \"units_position_excl\""
+    )]
+    fn test_gist_exclusion_constraint() {
+        Spi::run("create table units (position hex, exclude using gist (position with =))")
+            .unwrap();
+        Spi::run("insert into units values ('(1,2)'), ('(2,1)')").unwrap();
+        Spi::run("insert into units values ('[1,2]')").unwrap();
+    }
+}
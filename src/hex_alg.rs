@@ -1,7 +1,28 @@
-use std::ops::{Add, AddAssign, Mul, Sub};
+use std::cmp::Ordering;
+use std::fmt;
+use std::ops::{Add, AddAssign, Div, Mul, Neg, Rem, Sub};
+
+use pgrx::{ereport, PgSqlErrorCode};
 
 use crate::Hex;
 
+mod direction;
+mod histogram;
+mod search;
+mod shapes;
+mod supercover;
+mod visibility;
+
+pub use direction::{direction_between, direction_offset, DIRECTIONS};
+pub use histogram::HexHistogram;
+pub use search::{astar, astar_facing, reachable, Maneuvering, Terrain};
+pub use shapes::{cone, in_wedge, range_intersection, range_union, ranges_intersect, wedge};
+pub use supercover::supercover;
+pub use visibility::{
+    field_of_view, field_of_view_elevated, line_of_sight_elevated, obscurement,
+    obscurement_in_range,
+};
+
 #[derive(PartialEq, Debug, Copy, Clone, Eq, Hash)]
 pub struct CubeCoord {
     q: i32,
@@ -16,31 +37,108 @@ pub struct FloatCubeCoord {
     s: f64,
 }
 
-const EPSILON_HEX: FloatCubeCoord = FloatCubeCoord {
-    q: 1e-6,
-    r: 2e-6,
-    s: -3e-6,
-};
+/// Which of two hexes a line takes when it passes exactly along the edge
+/// between them
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub enum TieBreak {
+    /// Always the same one, whichever end the line is drawn from, so that a
+    /// line is the reverse of the line drawn back
+    Symmetric,
+    /// The one on the left, looking from the start of the line to its end,
+    /// with `r` increasing down the page
+    Left,
+    /// The one on the right
+    Right,
+}
+
+/// The fixed direction every point along a line is nudged in to break ties,
+/// after any nudge to the left or right, so that none falls exactly between
+/// hexes
+const TIE_NUDGE: [i128; 3] = [1, 2, -3];
 
+/// An infinitesimal nudge, as a multiple of a nudge to the left and one in
+/// the `TIE_NUDGE` direction, which is infinitesimal even next to that
+type Nudge = (i128, i128);
+
+/// The hexes along the line from `start` to `end`, found exactly in integers.
+///
+/// The point `i` steps along the line is `start + delta * i / distance`,
+/// which is rounded to the nearest hex after being nudged aside as
+/// `TieBreak` says.
 pub struct HexLineDrawIter {
-    start: FloatCubeCoord,
-    end: FloatCubeCoord,
+    start: CubeCoord,
+    delta: [i128; 3],
     distance: i32,
-    t_unit: f64,
+    nudge: [Nudge; 3],
     next_i: i32,
 }
 
 impl HexLineDrawIter {
-    fn new(start: CubeCoord, end: CubeCoord) -> Self {
+    fn new(start: CubeCoord, end: CubeCoord, tie_break: TieBreak) -> Self {
         let distance = start.dist(end);
+        let delta = [
+            end.q as i128 - start.q as i128,
+            end.r as i128 - start.r as i128,
+            end.s as i128 - start.s as i128,
+        ];
+        // The cross product of the delta with (1, 1, 1), which points to its
+        // left
+        let left = [
+            delta[1] - delta[2],
+            delta[2] - delta[0],
+            delta[0] - delta[1],
+        ];
+        let side = match tie_break {
+            TieBreak::Symmetric => 0,
+            TieBreak::Left => 1,
+            TieBreak::Right => -1,
+        };
         Self {
-            start: start.into(),
-            end: FloatCubeCoord::from(end) + EPSILON_HEX,
+            start,
+            delta,
             distance,
-            t_unit: 1f64 / (distance as f64),
+            nudge: [0, 1, 2].map(|c| (left[c] * side, TIE_NUDGE[c])),
             next_i: 0,
         }
     }
+
+    /// The number of hexes in the line from `start` to `end`
+    pub fn size(start: CubeCoord, end: CubeCoord) -> i64 {
+        start.dist(end) as i64 + 1
+    }
+
+    /// The offset from `start` of the hex `i` steps along the line
+    fn offset(&self, i: i32) -> [i128; 3] {
+        if self.distance == 0 {
+            return [0; 3];
+        }
+        let distance = self.distance as i128;
+        let mut offset = [0; 3];
+        let mut error = [(0, (0, 0)); 3];
+        for c in 0..3 {
+            // The point is `n / distance`, plus the nudge
+            let n = self.delta[c] * i as i128;
+            let nudge = self.nudge[c];
+            let twice = 2 * n + distance;
+            offset[c] = twice.div_euclid(2 * distance);
+            // Exactly halfway, so the nudge decides
+            if twice.rem_euclid(2 * distance) == 0 && nudge < (0, 0) {
+                offset[c] -= 1;
+            }
+            // How far the point is from where it was rounded to
+            let under = offset[c] * distance - n;
+            error[c] = match under.cmp(&0) {
+                Ordering::Greater => (under, (-nudge.0, -nudge.1)),
+                Ordering::Less => (-under, nudge),
+                Ordering::Equal => (0, nudge.max((-nudge.0, -nudge.1))),
+            };
+        }
+        // Make the components add up to 0 again by fixing the one rounded
+        // furthest
+        let worst = (0..3).max_by_key(|&c| error[c]).unwrap_or_default();
+        offset[worst] = -offset[(worst + 1) % 3] - offset[(worst + 2) % 3];
+        offset
+    }
 }
 
 impl Iterator for HexLineDrawIter {
@@ -51,9 +149,12 @@ impl Iterator for HexLineDrawIter {
             return None;
         }
 
-        let next_coord = CubeCoord::from(
-            self.start
-                .cube_lerp(self.end, (self.next_i as f64) * self.t_unit),
+        let [q, r, s] = self.offset(self.next_i);
+        let start = self.start;
+        let next_coord = CubeCoord::new(
+            (start.q as i128 + q) as i32,
+            (start.r as i128 + r) as i32,
+            (start.s as i128 + s) as i32,
         );
 
         self.next_i += 1;
@@ -62,45 +163,189 @@ impl Iterator for HexLineDrawIter {
     }
 }
 
+/// The nudge added to every point along a ray, so that none falls exactly
+/// between hexes, and ties break the way `TieBreak::Symmetric` breaks them
+const RAY_NUDGE: FloatCubeCoord = FloatCubeCoord {
+    q: 1e-6,
+    r: 2e-6,
+    s: -3e-6,
+};
+
+/// The hexes along a ray from `origin` out to `max_range`, one further from
+/// it at each step
+pub struct HexRayIter {
+    origin: CubeCoord,
+    step: FloatCubeCoord,
+    max_range: i32,
+    next_i: i32,
+}
+
+impl HexRayIter {
+    fn new(origin: CubeCoord, angle_degrees: f64, max_range: i32) -> Self {
+        let (sin, cos) = angle_degrees.to_radians().sin_cos();
+        // How far the ray goes towards (1,0) at 0° and (1,-1) at 60° as it
+        // goes the width of a hex
+        let towards_60 = sin * 2.0 / 3f64.sqrt();
+        let towards_0 = cos - towards_60 / 2.0;
+        let direction = FloatCubeCoord {
+            q: towards_0 + towards_60,
+            r: -towards_60,
+            s: -towards_0,
+        };
+        let longest = direction
+            .q
+            .abs()
+            .max(direction.r.abs())
+            .max(direction.s.abs());
+        Self {
+            origin,
+            step: direction * longest.recip(),
+            max_range,
+            next_i: 0,
+        }
+    }
+}
+
+impl Iterator for HexRayIter {
+    type Item = CubeCoord;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next_i > self.max_range {
+            return None;
+        }
+
+        let offset = CubeCoord::from(self.step * self.next_i as f64 + RAY_NUDGE);
+        let next_coord = self.origin + offset;
+
+        self.next_i += 1;
+
+        Some(next_coord)
+    }
+}
+
+/// The hexes within per-axis bounds, column by column from the lowest `q`,
+/// and from the lowest `r` within each column
 pub struct HexRangeIter {
-    center: CubeCoord,
-    dist: i32,
-    q: i32,
-    r: i32,
+    bounds: CubeBounds,
+    q: i64,
+    r: i64,
+    q_max: i64,
 }
 
 impl HexRangeIter {
     fn new(center: CubeCoord, dist: i32) -> Self {
+        Self::within(CubeBounds::around(center, dist))
+    }
+
+    /// Every hex within `bounds`
+    pub fn within(bounds: CubeBounds) -> Self {
+        // Only the columns with some hex in them
+        let [q_min, r_min, s_min] = bounds.min.map(i64::from);
+        let [q_max, r_max, s_max] = bounds.max.map(i64::from);
+        let q = q_min.max(-r_max - s_max);
+        // Bounds can be empty while still spanning some columns, which would
+        // then run from a higher r to a lower one
+        let q_max = if bounds.is_empty() {
+            q - 1
+        } else {
+            q_max.min(-r_min - s_min)
+        };
         Self {
-            center,
-            dist,
-            q: -dist,
-            r: 0,
+            bounds,
+            q,
+            r: bounds.column(q).0,
+            q_max,
+        }
+    }
+
+    /// The number of hexes within `dist` of any center, `3n(n+1) + 1`, or
+    /// `i64::MAX` for a range too big to count in an `i64`
+    pub fn size(dist: i32) -> i64 {
+        saturating_size(Self::exact_size(dist))
+    }
+
+    /// The number of hexes within `dist` of any center, which for the biggest
+    /// `dist` is more than an `i64` holds
+    fn exact_size(dist: i32) -> i128 {
+        if dist < 0 {
+            return 0;
         }
+        let n = dist as i128;
+        3 * n * (n + 1) + 1
     }
 }
 
+/// A number of hexes as an `i64`, saturating at `i64::MAX`
+fn saturating_size(size: i128) -> i64 {
+    i64::try_from(size).unwrap_or(i64::MAX)
+}
+
 impl Iterator for HexRangeIter {
     type Item = CubeCoord;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let r_max = self.dist.min(-self.q + self.dist);
-        if self.q > self.dist {
+        if self.q > self.q_max {
             return None;
         }
 
-        let new_coord = self.center + CubeCoord::new(self.q, self.r, -self.q - self.r);
+        let (q, r) = (self.q, self.r);
+        let new_coord = CubeCoord::new(q as i32, r as i32, (-q - r) as i32);
 
         self.r += 1;
-        if self.r > r_max {
+        if self.r > self.bounds.column(self.q).1 {
             self.q += 1;
-            self.r = (-self.dist).max(-self.q - self.dist);
+            self.r = self.bounds.column(self.q).0;
         }
 
         Some(new_coord)
     }
 }
 
+/// The hexes from `min_dist` to `max_dist` away from a center, in the same
+/// order as `HexRangeIter`, jumping over the hole in the middle of each column
+pub struct HexAnnulusIter {
+    range: HexRangeIter,
+    hole: CubeBounds,
+}
+
+impl HexAnnulusIter {
+    fn new(center: CubeCoord, min_dist: i32, max_dist: i32) -> Self {
+        let hole = min_dist.max(0) - 1;
+        Self {
+            range: HexRangeIter::new(center, if hole < max_dist { max_dist } else { -1 }),
+            hole: CubeBounds::around(center, hole),
+        }
+    }
+
+    /// The number of hexes from `min_dist` to `max_dist` away from any center,
+    /// saturating at `i64::MAX`
+    pub fn size(min_dist: i32, max_dist: i32) -> i64 {
+        let hole = min_dist.max(0) - 1;
+        if hole >= max_dist {
+            return 0;
+        }
+        saturating_size(HexRangeIter::exact_size(max_dist) - HexRangeIter::exact_size(hole))
+    }
+}
+
+impl Iterator for HexAnnulusIter {
+    type Item = CubeCoord;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let HexRangeIter { q, r, .. } = self.range;
+        let hole = self.hole;
+        if hole.min[0] as i64 <= q && q <= hole.max[0] as i64 {
+            let (hole_min, hole_max) = hole.column(q);
+            // The hole is narrower than the range, so there's always more of
+            // the column after it
+            if r == hole_min {
+                self.range.r = hole_max + 1;
+            }
+        }
+        self.range.next()
+    }
+}
+
 pub struct HexRingPathIter {
     center: CubeCoord,
     radius: i32,
@@ -125,6 +370,15 @@ impl HexRingPathIter {
             emitted_self: false,
         }
     }
+
+    /// The number of hexes in a ring of `radius`, six per side and just the
+    /// center for a radius of 0
+    pub fn size(radius: i32) -> i64 {
+        if radius == 0 {
+            return 1;
+        }
+        6 * radius.max(1) as i64
+    }
 }
 
 impl Iterator for HexRingPathIter {
@@ -175,6 +429,12 @@ impl HexSpiralPathIter {
             current_iterator: HexRingPathIter::new(center, 0),
         }
     }
+
+    /// The number of hexes in a spiral of `radius`, the same as in the range,
+    /// saturating at `i64::MAX`
+    pub fn size(radius: i32) -> i64 {
+        HexRangeIter::size(radius.max(0))
+    }
 }
 
 impl Iterator for HexSpiralPathIter {
@@ -196,10 +456,11 @@ impl Iterator for HexSpiralPathIter {
 
 impl From<Hex> for CubeCoord {
     fn from(value: Hex) -> Self {
+        let s = -(value.q as i64) - value.r as i64;
         Self {
             q: value.q,
             r: value.r,
-            s: -value.q - value.r,
+            s: i32::try_from(s).unwrap_or_else(|_| out_of_range()),
         }
     }
 }
@@ -217,11 +478,7 @@ impl Add for CubeCoord {
     type Output = CubeCoord;
 
     fn add(self, rhs: Self) -> Self::Output {
-        Self {
-            q: self.q + rhs.q,
-            r: self.r + rhs.r,
-            s: self.s + rhs.s,
-        }
+        self.checked_add(rhs).unwrap_or_else(|| out_of_range())
     }
 }
 
@@ -235,11 +492,15 @@ impl Sub for CubeCoord {
     type Output = CubeCoord;
 
     fn sub(self, rhs: Self) -> Self::Output {
-        Self {
-            q: self.q - rhs.q,
-            r: self.r - rhs.r,
-            s: self.s - rhs.s,
-        }
+        self.checked_sub(rhs).unwrap_or_else(|| out_of_range())
+    }
+}
+
+impl Neg for CubeCoord {
+    type Output = CubeCoord;
+
+    fn neg(self) -> Self::Output {
+        self.checked_mul(-1).unwrap_or_else(|| out_of_range())
     }
 }
 
@@ -247,11 +508,7 @@ impl Mul<i32> for CubeCoord {
     type Output = Self;
 
     fn mul(self, rhs: i32) -> Self::Output {
-        Self {
-            q: self.q * rhs,
-            r: self.r * rhs,
-            s: self.s * rhs,
-        }
+        self.checked_mul(rhs).unwrap_or_else(|| out_of_range())
     }
 }
 impl Mul<CubeCoord> for i32 {
@@ -262,6 +519,64 @@ impl Mul<CubeCoord> for i32 {
     }
 }
 
+/// Scale down by `rhs`, rounding to the nearest hex
+impl Div<i32> for CubeCoord {
+    type Output = Self;
+
+    fn div(self, rhs: i32) -> Self::Output {
+        match rhs {
+            0 => division_by_zero(),
+            // The only quotient that can be out of range
+            -1 => -self,
+            _ => {
+                let rhs = rhs as f64;
+                CubeCoord::from(FloatCubeCoord {
+                    q: self.q as f64 / rhs,
+                    r: self.r as f64 / rhs,
+                    s: self.s as f64 / rhs,
+                })
+            }
+        }
+    }
+}
+
+/// Wrap onto the `rhs` by `rhs` rhombus of hexes with q and r from 0 to
+/// `|rhs| - 1`, as on a map whose edges join up. A negative `rhs` wraps the
+/// same way as its absolute value.
+impl Rem<i32> for CubeCoord {
+    type Output = Self;
+
+    fn rem(self, rhs: i32) -> Self::Output {
+        if rhs == 0 {
+            division_by_zero();
+        }
+        // Widened, as i32::MIN % -1 overflows even though the remainder is 0
+        let wrap = |c: i32| i64::from(c).rem_euclid(i64::from(rhs)) as i32;
+        let q = wrap(self.q);
+        let r = wrap(self.r);
+        // s can still be out of range when rhs is huge
+        CubeCoord::from(Hex { q, r })
+    }
+}
+
+/// Raise `numeric_value_out_of_range`, for arithmetic that leaves the range of
+/// an `int4` in any component
+fn out_of_range() -> ! {
+    ereport!(
+        ERROR,
+        PgSqlErrorCode::ERRCODE_NUMERIC_VALUE_OUT_OF_RANGE,
+        "hex out of range"
+    )
+}
+
+fn division_by_zero() -> ! {
+    ereport!(
+        ERROR,
+        PgSqlErrorCode::ERRCODE_DIVISION_BY_ZERO,
+        "division by zero"
+    )
+}
+
 impl Add for FloatCubeCoord {
     type Output = FloatCubeCoord;
 
@@ -286,6 +601,18 @@ impl Sub for FloatCubeCoord {
     }
 }
 
+impl Mul<f64> for FloatCubeCoord {
+    type Output = FloatCubeCoord;
+
+    fn mul(self, rhs: f64) -> Self::Output {
+        Self {
+            q: self.q * rhs,
+            r: self.r * rhs,
+            s: self.s * rhs,
+        }
+    }
+}
+
 const NEIGHBOR_DIRS: [CubeCoord; 6] = [
     CubeCoord { q: 1, r: 0, s: -1 },
     CubeCoord { q: 1, r: -1, s: 0 },
@@ -309,6 +636,38 @@ impl CubeCoord {
         Self { q, r, s }
     }
 
+    /// The q, r and s components, in that order
+    pub fn components(&self) -> [i32; 3] {
+        [self.q, self.r, self.s]
+    }
+
+    /// `self + rhs`, or `None` if any component overflows
+    pub fn checked_add(self, rhs: Self) -> Option<Self> {
+        Some(Self {
+            q: self.q.checked_add(rhs.q)?,
+            r: self.r.checked_add(rhs.r)?,
+            s: self.s.checked_add(rhs.s)?,
+        })
+    }
+
+    /// `self - rhs`, or `None` if any component overflows
+    pub fn checked_sub(self, rhs: Self) -> Option<Self> {
+        Some(Self {
+            q: self.q.checked_sub(rhs.q)?,
+            r: self.r.checked_sub(rhs.r)?,
+            s: self.s.checked_sub(rhs.s)?,
+        })
+    }
+
+    /// `self * rhs`, or `None` if any component overflows
+    pub fn checked_mul(self, rhs: i32) -> Option<Self> {
+        Some(Self {
+            q: self.q.checked_mul(rhs)?,
+            r: self.r.checked_mul(rhs)?,
+            s: self.s.checked_mul(rhs)?,
+        })
+    }
+
     pub fn neighbors(&self) -> Vec<CubeCoord> {
         NEIGHBOR_DIRS.iter().map(|&d| *self + d).collect()
     }
@@ -319,8 +678,9 @@ impl CubeCoord {
 
     /// Get the hex distance to the origin
     pub fn abs(&self) -> i32 {
-        [self.q.abs(), self.r.abs(), self.s.abs()]
+        [self.q, self.r, self.s]
             .into_iter()
+            .map(|c| c.checked_abs().unwrap_or_else(|| out_of_range()))
             .max()
             .unwrap_or_default()
     }
@@ -331,13 +691,24 @@ impl CubeCoord {
     }
 
     pub fn linedraw(&self, other: CubeCoord) -> HexLineDrawIter {
-        HexLineDrawIter::new(*self, other)
+        self.linedraw_with(other, TieBreak::Symmetric)
+    }
+
+    /// The line to the other hex, breaking ties between hexes as `tie_break`
+    /// says
+    pub fn linedraw_with(&self, other: CubeCoord, tie_break: TieBreak) -> HexLineDrawIter {
+        HexLineDrawIter::new(*self, other, tie_break)
     }
 
     pub fn range(&self, dist: i32) -> HexRangeIter {
         HexRangeIter::new(*self, dist)
     }
 
+    /// The hexes from `min_dist` to `max_dist` away, each once
+    pub fn annulus(&self, min_dist: i32, max_dist: i32) -> HexAnnulusIter {
+        HexAnnulusIter::new(*self, min_dist, max_dist)
+    }
+
     pub fn ring(&self, radius: i32) -> HexRingPathIter {
         HexRingPathIter::new(*self, radius)
     }
@@ -345,20 +716,192 @@ impl CubeCoord {
     pub fn spiral(&self, radius: i32) -> HexSpiralPathIter {
         HexSpiralPathIter::new(*self, radius)
     }
+
+    /// The ray at `angle_degrees` counterclockwise from the direction of
+    /// `(1,0)`, where `(1,-1)` is at 60° and the other neighbors follow it
+    /// round, out to `max_range`
+    pub fn ray(&self, angle_degrees: f64, max_range: i32) -> HexRayIter {
+        HexRayIter::new(*self, angle_degrees, max_range)
+    }
 }
 
-fn lerp(a: f64, b: f64, t: f64) -> f64 {
-    a + (b - a) * t
+/// An axis-aligned box in cube coordinates, bounding each of q, r and s.
+///
+/// The `repr(C)` layout is used directly as the key of the GiST index.
+#[derive(PartialEq, Debug, Copy, Clone, Eq)]
+#[repr(C)]
+pub struct CubeBounds {
+    /// Lower bounds of q, r and s
+    min: [i32; 3],
+    /// Upper bounds of q, r and s
+    max: [i32; 3],
 }
 
-impl FloatCubeCoord {
-    fn cube_lerp(&self, other: Self, t: f64) -> Self {
+impl CubeBounds {
+    /// The bounds containing only a single hex
+    pub fn from_point(coord: CubeCoord) -> Self {
+        Self {
+            min: coord.components(),
+            max: coord.components(),
+        }
+    }
+
+    /// The bounds between `min` and `max` q, r and s, inclusive
+    pub fn new(min: [i32; 3], max: [i32; 3]) -> Self {
+        Self { min, max }
+    }
+
+    /// Lower bounds of q, r and s
+    pub fn min(&self) -> [i32; 3] {
+        self.min
+    }
+
+    /// Upper bounds of q, r and s
+    pub fn max(&self) -> [i32; 3] {
+        self.max
+    }
+
+    /// The bounds containing every representable hex
+    pub fn unbounded() -> Self {
+        Self {
+            min: [i32::MIN; 3],
+            max: [i32::MAX; 3],
+        }
+    }
+
+    /// The bounds containing no hexes, which are contained by any others
+    pub fn empty() -> Self {
+        Self {
+            min: [i32::MAX; 3],
+            max: [i32::MIN; 3],
+        }
+    }
+
+    /// The bounds of every hex within `radius` of `center`, which contain
+    /// just those hexes
+    pub fn around(center: CubeCoord, radius: i32) -> Self {
+        if radius < 0 {
+            return Self::empty();
+        }
+        let bound = |c: i32, offset: i32| c.checked_add(offset).unwrap_or_else(|| out_of_range());
+        Self {
+            min: center.components().map(|c| bound(c, -radius)),
+            max: center.components().map(|c| bound(c, radius)),
+        }
+    }
+
+    /// The hexes within both these bounds and the others
+    pub fn intersection(self, other: CubeBounds) -> Self {
+        Self {
+            min: [0, 1, 2].map(|a| self.min[a].max(other.min[a])),
+            max: [0, 1, 2].map(|a| self.max[a].min(other.max[a])),
+        }
+    }
+
+    /// Whether there are no hexes within the bounds, because there's no `q`,
+    /// `r` and `s` within them that add up to 0
+    pub fn is_empty(&self) -> bool {
+        let [min, max] = [self.min, self.max].map(|b| b.map(i64::from));
+        (0..3).any(|a| min[a] > max[a])
+            || min.iter().sum::<i64>() > 0
+            || max.iter().sum::<i64>() < 0
+    }
+
+    /// The lowest and highest `r` within the bounds in column `q`, whether or
+    /// not `q` is
+    fn column(&self, q: i64) -> (i64, i64) {
+        let [_, r_min, s_min] = self.min.map(i64::from);
+        let [_, r_max, s_max] = self.max.map(i64::from);
+        (r_min.max(-q - s_max), r_max.min(-q - s_min))
+    }
+
+    /// Split into the halves at or below, and at or above, `value` on `axis`
+    /// (0, 1 and 2 being q, r and s)
+    pub fn split_at(&self, axis: usize, value: i32) -> [CubeBounds; 2] {
+        let mut lower = *self;
+        let mut upper = *self;
+        lower.max[axis] = lower.max[axis].min(value);
+        upper.min[axis] = upper.min[axis].max(value);
+        [lower, upper]
+    }
+
+    /// The smallest bounds containing both of these
+    pub fn union(self, other: CubeBounds) -> Self {
         Self {
-            q: lerp(self.q, other.q, t),
-            r: lerp(self.r, other.r, t),
-            s: lerp(self.s, other.s, t),
+            min: [0, 1, 2].map(|a| self.min[a].min(other.min[a])),
+            max: [0, 1, 2].map(|a| self.max[a].max(other.max[a])),
         }
     }
+
+    pub fn contains(&self, coord: CubeCoord) -> bool {
+        let c = coord.components();
+        (0..3).all(|a| self.min[a] <= c[a] && c[a] <= self.max[a])
+    }
+
+    /// The hex, if these bounds contain exactly one
+    pub fn as_point(&self) -> Option<CubeCoord> {
+        if self.min != self.max {
+            return None;
+        }
+        let [q, r, s] = self.min;
+        Some(CubeCoord::new(q, r, s))
+    }
+
+    /// A lower bound on the hex distance from `coord` to any hex in the
+    /// bounds, which is exact when the bounds are a single hex
+    pub fn min_dist(&self, coord: CubeCoord) -> i64 {
+        let c = coord.components();
+        (0..3)
+            .map(|a| {
+                let (c, min, max) = (c[a] as i64, self.min[a] as i64, self.max[a] as i64);
+                (min - c).max(c - max).max(0)
+            })
+            .max()
+            .unwrap_or_default()
+    }
+
+    /// The total extent along the three axes, as a measure of how much of the
+    /// grid the bounds cover
+    pub fn extent(&self) -> i64 {
+        (0..3)
+            .map(|a| self.max[a] as i64 - self.min[a] as i64)
+            .sum()
+    }
+
+    /// Split a set of bounds into two halves of (nearly) equal size, dividing
+    /// along the axis their midpoints are most spread out on
+    pub fn split(bounds: &[CubeBounds]) -> (Vec<usize>, Vec<usize>) {
+        // Twice the midpoint, to stay in integers
+        let midpoint = |b: &CubeBounds, a: usize| b.min[a] as i64 + b.max[a] as i64;
+
+        let axis = (0..3)
+            .max_by_key(|&a| {
+                let midpoints = bounds.iter().map(|b| midpoint(b, a));
+                let (lo, hi) =
+                    midpoints.fold((i64::MAX, i64::MIN), |(lo, hi), m| (lo.min(m), hi.max(m)));
+                hi.saturating_sub(lo)
+            })
+            .unwrap_or_default();
+
+        let mut order = (0..bounds.len()).collect::<Vec<_>>();
+        order.sort_by_key(|&i| midpoint(&bounds[i], axis));
+        let right = order.split_off(order.len() / 2);
+
+        (order, right)
+    }
+}
+
+/// Text form of the bounds, `(q_min,r_min,s_min),(q_max,r_max,s_max)`
+impl fmt::Display for CubeBounds {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [q_min, r_min, s_min] = self.min;
+        let [q_max, r_max, s_max] = self.max;
+        write!(
+            f,
+            "({},{},{}),({},{},{})",
+            q_min, r_min, s_min, q_max, r_max, s_max
+        )
+    }
 }
 
 impl From<FloatCubeCoord> for CubeCoord {
@@ -418,7 +961,7 @@ mod tests {
         #[case] right: CubeCoord,
         #[case] expected: CubeCoord,
     ) {
-        let mut result = left.clone();
+        let mut result = left;
         result += right;
         assert_eq!(result, expected)
     }
@@ -444,6 +987,61 @@ mod tests {
         assert_eq!(right * left, expected);
     }
 
+    #[rstest]
+    #[case(CubeCoord{q: 1, r: 2, s: -3}, CubeCoord{q: -1, r: -2, s: 3} )]
+    #[case(CubeCoord{q: 0, r: 0, s: 0}, CubeCoord{q: 0, r: 0, s: 0} )]
+    fn test_negate(#[case] coord: CubeCoord, #[case] expected: CubeCoord) {
+        assert_eq!(-coord, expected);
+    }
+
+    #[rstest]
+    #[case(CubeCoord{q: 4, r: -6, s: 2}, 2, CubeCoord{q: 2, r: -3, s: 1} )]
+    #[case(CubeCoord{q: 4, r: -6, s: 2}, -2, CubeCoord{q: -2, r: 3, s: -1} )]
+    #[case(CubeCoord{q: 5, r: -3, s: -2}, 3, CubeCoord{q: 2, r: -1, s: -1} )]
+    #[case(CubeCoord{q: 7, r: 0, s: -7}, 10, CubeCoord{q: 1, r: 0, s: -1} )]
+    #[case(CubeCoord{q: 1, r: 2, s: -3}, 100, CubeCoord{q: 0, r: 0, s: 0} )]
+    #[case(CubeCoord{q: 1, r: 2, s: -3}, -1, CubeCoord{q: -1, r: -2, s: 3} )]
+    fn test_divide(#[case] left: CubeCoord, #[case] right: i32, #[case] expected: CubeCoord) {
+        assert_eq!(left / right, expected);
+    }
+
+    #[rstest]
+    #[case(CubeCoord{q: 1, r: 2, s: -3}, 5, CubeCoord{q: 1, r: 2, s: -3} )]
+    #[case(CubeCoord{q: 6, r: -1, s: -5}, 5, CubeCoord{q: 1, r: 4, s: -5} )]
+    #[case(CubeCoord{q: -5, r: 10, s: -5}, 5, CubeCoord{q: 0, r: 0, s: 0} )]
+    #[case(CubeCoord{q: 6, r: -1, s: -5}, -5, CubeCoord{q: 1, r: 4, s: -5} )]
+    #[case(CubeCoord{q: 1, r: i32::MIN, s: i32::MAX}, -1, CubeCoord{q: 0, r: 0, s: 0} )]
+    fn test_remainder(#[case] left: CubeCoord, #[case] right: i32, #[case] expected: CubeCoord) {
+        assert_eq!(left % right, expected);
+    }
+
+    #[rstest]
+    fn test_checked_overflow() {
+        let edge = CubeCoord::from(Hex {
+            q: i32::MAX,
+            r: -i32::MAX,
+        });
+        assert_eq!(edge.checked_add(NEIGHBOR_DIRS[0]), None);
+        assert_eq!(edge.checked_sub(NEIGHBOR_DIRS[3]), None);
+        assert_eq!(edge.checked_mul(2), None);
+        assert_eq!(
+            edge.checked_add(NEIGHBOR_DIRS[3]),
+            Some(edge + NEIGHBOR_DIRS[3])
+        );
+    }
+
+    #[rstest]
+    #[should_panic]
+    fn test_add_overflow() {
+        let _ = CubeCoord::new(i32::MAX, 0, -i32::MAX) + NEIGHBOR_DIRS[0];
+    }
+
+    #[rstest]
+    #[should_panic]
+    fn test_divide_by_zero() {
+        let _ = CubeCoord::new(1, 2, -3) / 0;
+    }
+
     #[rstest]
     fn test_neighbors() {
         assert_eq!(
@@ -493,6 +1091,7 @@ mod tests {
 
     #[rstest]
     #[case(CubeCoord::new(0, 0, 0), CubeCoord::new(0, 0, 0), vec![CubeCoord::new(0, 0, 0)])]
+    #[case(CubeCoord::new(5, 5, -10), CubeCoord::new(5, 5, -10), vec![CubeCoord::new(5, 5, -10)])]
     #[case(CubeCoord::new(-3, 0, 3), CubeCoord::new(3, -3, 0), vec![
         CubeCoord::new(-3, 0, 3),
         CubeCoord::new(-2, 0, 2),
@@ -517,6 +1116,93 @@ mod tests {
         assert_eq!(line, expected);
     }
 
+    #[rstest]
+    // Halfway along, the line passes along the edge between (1,0) and (0,1)
+    #[case(TieBreak::Symmetric, CubeCoord::new(0, 1, -1))]
+    #[case(TieBreak::Left, CubeCoord::new(1, 0, -1))]
+    #[case(TieBreak::Right, CubeCoord::new(0, 1, -1))]
+    fn test_linedraw_tie_break(#[case] tie_break: TieBreak, #[case] expected: CubeCoord) {
+        let (from, to) = (CubeCoord::new(0, 0, 0), CubeCoord::new(1, 1, -2));
+        let line = from.linedraw_with(to, tie_break).collect::<Vec<_>>();
+        assert_eq!(line, vec![from, expected, to]);
+    }
+
+    #[rstest]
+    #[case(CubeCoord::new(0, 0, 0), CubeCoord::new(4, 2, -6))]
+    #[case(CubeCoord::new(-3, 5, -2), CubeCoord::new(3, -4, 1))]
+    #[case(CubeCoord::new(2, -1, -1), CubeCoord::new(-6, 3, 3))]
+    #[case(CubeCoord::new(0, 0, 0), CubeCoord::new(7, 0, -7))]
+    fn test_linedraw_reversed(#[case] from: CubeCoord, #[case] to: CubeCoord) {
+        let line = |from: CubeCoord, to: CubeCoord, tie_break| {
+            from.linedraw_with(to, tie_break).collect::<Vec<_>>()
+        };
+        let reversed = |mut line: Vec<CubeCoord>| {
+            line.reverse();
+            line
+        };
+
+        let symmetric = line(from, to, TieBreak::Symmetric);
+        assert_eq!(symmetric, reversed(line(to, from, TieBreak::Symmetric)));
+        let left = line(from, to, TieBreak::Left);
+        assert_eq!(left, reversed(line(to, from, TieBreak::Right)));
+
+        for line in [symmetric, left] {
+            assert_eq!(line.first(), Some(&from));
+            assert_eq!(line.last(), Some(&to));
+            assert!(line.windows(2).all(|pair| pair[0].dist(pair[1]) == 1));
+        }
+    }
+
+    #[rstest]
+    fn test_linedraw_far_from_origin() {
+        let far = CubeCoord::new(1_000_000_000, -2_000_000_000, 1_000_000_000);
+        let (from, to) = (CubeCoord::new(0, 0, 0), CubeCoord::new(4, 2, -6));
+        let line = from.linedraw(to).map(|coord| coord + far);
+        assert!(line.eq((from + far).linedraw(to + far)));
+    }
+
+    #[rstest]
+    // Towards each neighbor and the diagonals between them
+    #[case(0.0, CubeCoord::new(5, 0, -5))]
+    #[case(60.0, CubeCoord::new(5, -5, 0))]
+    #[case(-60.0, CubeCoord::new(0, 5, -5))]
+    #[case(300.0, CubeCoord::new(0, 5, -5))]
+    #[case(180.0, CubeCoord::new(-5, 0, 5))]
+    #[case(30.0, CubeCoord::new(6, -3, -3))]
+    #[case(90.0, CubeCoord::new(3, -6, 3))]
+    #[case(-90.0, CubeCoord::new(-3, 6, -3))]
+    #[case(210.0, CubeCoord::new(-6, 3, 3))]
+    fn test_ray_matches_linedraw(#[case] angle_degrees: f64, #[case] to: CubeCoord) {
+        let origin = CubeCoord::new(2, -7, 5);
+        let to = origin + to;
+        let ray = origin.ray(angle_degrees, origin.dist(to));
+        assert!(ray.eq(origin.linedraw(to)));
+    }
+
+    #[rstest]
+    fn test_ray_steps() {
+        let origin = CubeCoord::new(0, 0, 0);
+        for angle_degrees in (0..3600).map(|tenths| tenths as f64 / 10.0) {
+            let ray = origin.ray(angle_degrees, 20).collect::<Vec<_>>();
+            assert_eq!(ray.len(), 21);
+            for (i, coord) in ray.iter().enumerate() {
+                assert_eq!(coord.dist(origin), i as i32, "{}°", angle_degrees);
+            }
+        }
+        assert_eq!(origin.ray(45.0, -1).count(), 0);
+    }
+
+    #[rstest]
+    #[case(CubeCoord::new(0, 0, 0), CubeCoord::new(1, -1, 0))]
+    #[case(CubeCoord::new(1, -3, 2), CubeCoord::new(2, 2, -4))]
+    #[case(CubeCoord::new(-40, 10, 30), CubeCoord::new(2, 2, -4))]
+    fn test_linedraw_size(#[case] from: CubeCoord, #[case] to: CubeCoord) {
+        assert_eq!(
+            HexLineDrawIter::size(from, to),
+            from.linedraw(to).count() as i64
+        );
+    }
+
     #[rstest]
     #[case(CubeCoord::new(0, 0, 0))]
     #[case(CubeCoord::new(100, -5, -95))]
@@ -563,17 +1249,59 @@ mod tests {
     fn test_range_2(#[case] center: CubeCoord, #[case] dist: i32) {
         let mut expected = HashSet::from([center]);
         for _ in 0..dist {
-            expected = expected
-                .into_iter()
-                .map(|t| t.neighbors())
-                .flatten()
-                .collect();
+            expected = expected.into_iter().flat_map(|t| t.neighbors()).collect();
         }
 
         let range = center.range(dist).collect::<HashSet<_>>();
         assert_eq!(range, expected);
     }
 
+    #[rstest]
+    fn test_range_size(#[values(-2, 0, 1, 2, 7)] dist: i32) {
+        let center = CubeCoord::new(3, -1, -2);
+        assert_eq!(HexRangeIter::size(dist), center.range(dist).count() as i64);
+    }
+
+    #[rstest]
+    #[case(1_000_000_000, 3_000_000_003_000_000_001)]
+    #[case(2_000_000_000, i64::MAX)]
+    #[case(i32::MAX, i64::MAX)]
+    fn test_range_size_huge(#[case] dist: i32, #[case] expected: i64) {
+        assert_eq!(HexRangeIter::size(dist), expected);
+    }
+
+    #[rstest]
+    #[case(3, 6)]
+    #[case(0, 4)]
+    #[case(-2, 2)]
+    #[case(1, 1)]
+    #[case(5, 5)]
+    #[case(4, 3)]
+    #[case(0, -1)]
+    fn test_annulus(#[case] min_dist: i32, #[case] max_dist: i32) {
+        let center = CubeCoord::new(7, -3, -4);
+        let annulus = center.annulus(min_dist, max_dist).collect::<Vec<_>>();
+        let expected = center
+            .range(max_dist)
+            .filter(|coord| coord.dist(center) >= min_dist)
+            .collect::<Vec<_>>();
+        assert_eq!(annulus, expected);
+        assert_eq!(
+            HexAnnulusIter::size(min_dist, max_dist),
+            annulus.len() as i64
+        );
+    }
+
+    #[rstest]
+    fn test_annulus_size_huge() {
+        // Exact even though the range around it is too big to count
+        assert_eq!(
+            HexAnnulusIter::size(2_000_000_000, 2_000_000_001),
+            24_000_000_006
+        );
+        assert_eq!(HexAnnulusIter::size(0, i32::MAX), i64::MAX);
+    }
+
     #[rstest]
     #[case(CubeCoord::new(0, 0, 0))]
     #[case(CubeCoord::new(100, -5, -95))]
@@ -627,6 +1355,15 @@ mod tests {
         assert_eq!(ring_2, expected_2);
     }
 
+    #[rstest]
+    fn test_ring_size(#[values(-2, 0, 1, 2, 7)] radius: i32) {
+        let center = CubeCoord::new(3, -1, -2);
+        assert_eq!(
+            HexRingPathIter::size(radius),
+            center.ring(radius).count() as i64
+        );
+    }
+
     #[rstest]
     #[case(CubeCoord::new(0, 0, 0))]
     #[case(CubeCoord::new(100, -5, -95))]
@@ -681,4 +1418,113 @@ mod tests {
         let spiral_2 = center.spiral(2).collect::<Vec<_>>();
         assert_eq!(spiral_2, expected_2);
     }
+
+    #[rstest]
+    fn test_spiral_size(#[values(-2, 0, 1, 2, 7)] radius: i32) {
+        let center = CubeCoord::new(3, -1, -2);
+        assert_eq!(
+            HexSpiralPathIter::size(radius),
+            center.spiral(radius).count() as i64
+        );
+    }
+
+    #[rstest]
+    fn test_bounds_union() {
+        let bounds = CubeBounds::from_point(CubeCoord::new(1, -3, 2))
+            .union(CubeBounds::from_point(CubeCoord::new(-2, 0, 2)));
+        assert_eq!(
+            bounds,
+            CubeBounds {
+                min: [-2, -3, 2],
+                max: [1, 0, 2]
+            }
+        );
+        assert!(bounds.contains(CubeCoord::new(0, -2, 2)));
+        assert!(!bounds.contains(CubeCoord::new(0, -1, 1)));
+        assert_eq!(bounds.extent(), 6);
+        assert_eq!(bounds.as_point(), None);
+    }
+
+    #[rstest]
+    #[case(CubeCoord::new(0, 0, 0))]
+    #[case(CubeCoord::new(100, -5, -95))]
+    fn test_bounds_point(#[case] coord: CubeCoord) {
+        let bounds = CubeBounds::from_point(coord);
+        assert_eq!(bounds.as_point(), Some(coord));
+        assert_eq!(bounds.extent(), 0);
+        assert!(bounds.contains(coord));
+        for other in coord.range(3) {
+            assert_eq!(bounds.min_dist(other), coord.dist(other) as i64);
+        }
+    }
+
+    #[rstest]
+    #[case(CubeCoord::new(0, 0, 0), 2)]
+    #[case(CubeCoord::new(100, -5, -95), 4)]
+    /// The distance to a bounding box is never more than the distance to the
+    /// closest hex inside it
+    fn test_bounds_min_dist_lower_bound(#[case] center: CubeCoord, #[case] dist: i32) {
+        let members = center.ring(dist).step_by(3).collect::<Vec<_>>();
+        let bounds = members
+            .iter()
+            .map(|&c| CubeBounds::from_point(c))
+            .reduce(CubeBounds::union)
+            .unwrap();
+
+        for query in CubeCoord::new(0, 0, 0).range(8).map(|c| c + center) {
+            let closest = members.iter().map(|&m| m.dist(query)).min().unwrap();
+            assert!(bounds.min_dist(query) <= closest as i64);
+        }
+    }
+
+    #[rstest]
+    fn test_bounds_min_dist_extreme() {
+        let bounds = CubeBounds::from_point(CubeCoord::new(i32::MAX, i32::MIN, 1));
+        assert_eq!(
+            bounds.min_dist(CubeCoord::new(i32::MIN, i32::MAX, 1)),
+            u32::MAX as i64
+        );
+    }
+
+    #[rstest]
+    fn test_bounds_split_at() {
+        let [lower, upper] = CubeBounds::unbounded().split_at(1, 5);
+        assert!(lower.contains(CubeCoord::new(-10, 5, 5)));
+        assert!(!lower.contains(CubeCoord::new(-10, 6, 4)));
+        assert!(upper.contains(CubeCoord::new(-10, 5, 5)));
+        assert!(!upper.contains(CubeCoord::new(-10, 4, 6)));
+
+        assert_eq!(lower.min_dist(CubeCoord::new(-9, 9, 0)), 4);
+        assert_eq!(upper.min_dist(CubeCoord::new(-9, 9, 0)), 0);
+
+        let [lower_lower, lower_upper] = lower.split_at(0, -2);
+        assert_eq!(lower_lower.min_dist(CubeCoord::new(2, 6, -8)), 4);
+        assert_eq!(lower_upper.min_dist(CubeCoord::new(2, 6, -8)), 1);
+    }
+
+    #[rstest]
+    fn test_bounds_split() {
+        let bounds = [
+            CubeCoord::new(5, 0, -5),
+            CubeCoord::new(-5, 1, 4),
+            CubeCoord::new(4, 0, -4),
+            CubeCoord::new(-4, -1, 5),
+            CubeCoord::new(6, -1, -5),
+        ]
+        .map(CubeBounds::from_point);
+
+        let (mut left, mut right) = CubeBounds::split(&bounds);
+        left.sort();
+        right.sort();
+        assert_eq!(left, vec![1, 3]);
+        assert_eq!(right, vec![0, 2, 4]);
+    }
+
+    #[rstest]
+    fn test_bounds_split_identical() {
+        let bounds = [CubeBounds::from_point(CubeCoord::new(1, 1, -2)); 4];
+        let (left, right) = CubeBounds::split(&bounds);
+        assert_eq!(left.len(), 2);
+        assert_eq!(right.len(), 2);
+    }
 }
@@ -0,0 +1,123 @@
+use super::shapes::turn;
+use super::{CubeCoord, DIAGONAL_DIRS, NEIGHBOR_DIRS};
+
+/// How many directions there are. They're numbered 30° apart
+/// counterclockwise from the direction of `(1,0)`, so the even ones are the
+/// directions to neighbors and the odd ones the directions to diagonals.
+pub const DIRECTIONS: usize = 12;
+
+/// The offset to the neighbor or diagonal in `direction`, counting round
+/// again past the last direction
+pub fn direction_offset(direction: usize) -> CubeCoord {
+    let dirs = if direction % 2 == 1 {
+        &DIAGONAL_DIRS
+    } else {
+        &NEIGHBOR_DIRS
+    };
+    dirs[direction / 2 % 6]
+}
+
+/// The direction from the center of `from` to the center of `to`, and
+/// whether `to` is exactly in that direction rather than just nearer to it
+/// than to any other. There's no direction from a hex to itself.
+pub fn direction_between(from: CubeCoord, to: CubeCoord) -> Option<(usize, bool)> {
+    if from == to {
+        return None;
+    }
+    let offset = to - from;
+    let direction = (0..DIRECTIONS)
+        .find(|&direction| nearest(direction, offset))
+        .expect("no hex is exactly halfway between two directions");
+    Some((direction, turn(direction_offset(direction), offset) == 0))
+}
+
+/// Whether `offset` is within 15° of `direction`, and so nearer to it than to
+/// any other direction
+fn nearest(direction: usize, offset: CubeCoord) -> bool {
+    // Doubling x and dividing y by √3 leaves whole coordinates, so the dot
+    // product and the cross product over √3 are whole too
+    let cartesian = |coord: CubeCoord| {
+        let [q, r, _] = coord.components().map(i128::from);
+        (2 * q + r, -r)
+    };
+    let ((ax, ay), (bx, by)) = (cartesian(direction_offset(direction)), cartesian(offset));
+    let dot = ax * bx + 3 * ay * by;
+    let cross = ax * by - ay * bx;
+    // tan 15° is 2 - √3, so that's √3 |cross| < (2 - √3) dot, and squaring
+    // √3 (|cross| + dot) < 2 dot gets rid of the last root
+    dot > 0 && 3 * (cross.abs() + dot).pow(2) < 4 * dot.pow(2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hex_alg::shapes::angle_degrees;
+    use rstest::*;
+
+    #[rstest]
+    fn test_direction_offset() {
+        for direction in 0..DIRECTIONS {
+            let offset = direction_offset(direction);
+            let expected = if direction % 2 == 1 { 2 } else { 1 };
+            assert_eq!(offset.abs(), expected);
+            assert_eq!(direction_offset(direction + DIRECTIONS), offset);
+        }
+        assert_eq!(direction_offset(2), CubeCoord::new(1, -1, 0));
+        assert_eq!(direction_offset(3), CubeCoord::new(1, -2, 1));
+    }
+
+    #[rstest]
+    fn test_direction_between_exact() {
+        let from = CubeCoord::new(-3, 7, -4);
+        for direction in 0..DIRECTIONS {
+            for distance in [1, 2, 5] {
+                let to = from + direction_offset(direction) * distance;
+                assert_eq!(direction_between(from, to), Some((direction, true)));
+            }
+        }
+    }
+
+    #[rstest]
+    // Just off east, either side
+    #[case(CubeCoord::new(5, -1, -4), 0)]
+    #[case(CubeCoord::new(4, 1, -5), 0)]
+    // Nearer the diagonal between east and northeast
+    #[case(CubeCoord::new(3, -1, -2), 1)]
+    // Just off west
+    #[case(CubeCoord::new(-5, 1, 4), 6)]
+    // Nearer the diagonal between southeast and east
+    #[case(CubeCoord::new(2, 1, -3), 11)]
+    // Far out, either side of halfway between east and the diagonal after it
+    #[case(CubeCoord::new(1_866_025_403, -500_000_000, -1_366_025_403), 1)]
+    #[case(CubeCoord::new(1_866_025_404, -500_000_000, -1_366_025_404), 0)]
+    fn test_direction_between_nearest(#[case] to: CubeCoord, #[case] expected: usize) {
+        let from = CubeCoord::new(0, 0, 0);
+        assert_eq!(direction_between(from, to), Some((expected, false)));
+    }
+
+    #[rstest]
+    fn test_direction_between_matches_angle() {
+        let from = CubeCoord::new(0, 0, 0);
+        for to in from.range(20).filter(|&to| to != from) {
+            let expected = (angle_degrees(to - from) / 30.0).round().rem_euclid(12.0) as usize;
+            assert_eq!(direction_between(from, to).unwrap().0, expected, "{:?}", to);
+        }
+    }
+
+    #[rstest]
+    fn test_direction_between_same() {
+        let coord = CubeCoord::new(2, -2, 0);
+        assert_eq!(direction_between(coord, coord), None);
+    }
+
+    #[rstest]
+    fn test_direction_between_reversed() {
+        let from = CubeCoord::new(1, 2, -3);
+        for to in from.range(4).filter(|&to| to != from) {
+            let (there, exact) = direction_between(from, to).unwrap();
+            let (back, exact_back) = direction_between(to, from).unwrap();
+            assert_eq!(back, (there + DIRECTIONS / 2) % DIRECTIONS);
+            assert_eq!(exact_back, exact);
+        }
+    }
+}
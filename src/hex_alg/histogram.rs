@@ -0,0 +1,316 @@
+use super::CubeCoord;
+
+/// Most cells along each of q and r
+const MAX_CELLS_PER_AXIS: usize = 16;
+
+/// Number of values stored before the cell fractions in `to_numbers`
+const HEADER_LEN: usize = 6;
+
+/// How a sample of hexes is spread over the grid, as the fraction of the
+/// sample in each cell of a grid over axial q and r.
+///
+/// Cells cover a continuous plane where each hex is a unit square centered on
+/// its q and r, and hexes are assumed to be spread evenly within a cell.
+#[derive(PartialEq, Debug, Clone)]
+pub struct HexHistogram {
+    /// Lowest q and r edges of the grid
+    origin: [f64; 2],
+    /// Width of each cell along q and r
+    cell_size: [f64; 2],
+    /// Number of cells along q and r
+    cells: [usize; 2],
+    /// Fraction of the sample in each cell, row by row
+    fractions: Vec<f64>,
+}
+
+impl HexHistogram {
+    /// The histogram of the hexes in a sample of `sample_size` rows, the rest
+    /// of which were null. `None` if there are no hexes.
+    pub fn new(coords: &[CubeCoord], sample_size: usize) -> Option<Self> {
+        let first = coords.first()?.components();
+        let (mut min, mut max) = ([first[0], first[1]], [first[0], first[1]]);
+        for coord in coords {
+            let c = coord.components();
+            for axis in 0..2 {
+                min[axis] = min[axis].min(c[axis]);
+                max[axis] = max[axis].max(c[axis]);
+            }
+        }
+
+        let extent = [0, 1].map(|axis| max[axis] as f64 - min[axis] as f64 + 1.0);
+        let cells = extent.map(|e| (e as usize).min(MAX_CELLS_PER_AXIS));
+        let mut histogram = Self {
+            origin: [0, 1].map(|axis| min[axis] as f64 - 0.5),
+            cell_size: [0, 1].map(|axis| extent[axis] / cells[axis] as f64),
+            cells,
+            fractions: vec![0.0; cells[0] * cells[1]],
+        };
+
+        let weight = 1.0 / sample_size.max(coords.len()) as f64;
+        for coord in coords {
+            let c = coord.components();
+            let cell = [0, 1].map(|axis| {
+                let offset = (c[axis] as f64 - histogram.origin[axis]) / histogram.cell_size[axis];
+                (offset as usize).min(histogram.cells[axis] - 1)
+            });
+            histogram.fractions[cell[1] * cells[0] + cell[0]] += weight;
+        }
+        Some(histogram)
+    }
+
+    /// The histogram as a flat array, for storing in `pg_statistic`
+    pub fn to_numbers(&self) -> Vec<f32> {
+        let header = [
+            self.origin[0],
+            self.origin[1],
+            self.cell_size[0],
+            self.cell_size[1],
+            self.cells[0] as f64,
+            self.cells[1] as f64,
+        ];
+        header
+            .iter()
+            .chain(&self.fractions)
+            .map(|&n| n as f32)
+            .collect()
+    }
+
+    /// Read a histogram written by `to_numbers`
+    pub fn from_numbers(numbers: &[f32]) -> Option<Self> {
+        let header = numbers.get(..HEADER_LEN)?;
+        let cells = [header[4] as usize, header[5] as usize];
+        let fractions = numbers.get(HEADER_LEN..)?;
+        if cells[0] == 0 || cells[1] == 0 || fractions.len() != cells[0] * cells[1] {
+            return None;
+        }
+        Some(Self {
+            origin: [header[0] as f64, header[1] as f64],
+            cell_size: [header[2] as f64, header[3] as f64],
+            cells,
+            fractions: fractions.iter().map(|&f| f as f64).collect(),
+        })
+    }
+
+    /// The estimated fraction of rows within `radius` of `center`
+    pub fn fraction_within(&self, center: CubeCoord, radius: i32) -> f64 {
+        if radius < 0 {
+            return 0.0;
+        }
+        let c = center.components();
+        self.fraction_within_point([c[0] as f64, c[1] as f64], apothem(radius))
+    }
+
+    /// The estimated fraction of pairs of rows, one from each histogram, that
+    /// are within `radius` of each other
+    pub fn fraction_pairs_within(&self, other: &HexHistogram, radius: i32) -> f64 {
+        if radius < 0 {
+            return 0.0;
+        }
+        self.cells()
+            .filter(|&(_, fraction)| fraction > 0.0)
+            .map(|(bounds, fraction)| {
+                let center = [(bounds[0] + bounds[1]) / 2.0, (bounds[2] + bounds[3]) / 2.0];
+                fraction * other.fraction_within_point(center, apothem(radius))
+            })
+            .sum()
+    }
+
+    /// The fraction of rows in the hexagon of points within `apothem` of
+    /// `center` along each of q, r and s
+    fn fraction_within_point(&self, center: [f64; 2], apothem: f64) -> f64 {
+        let cell_area = self.cell_size[0] * self.cell_size[1];
+        self.cells()
+            .filter(|&(_, fraction)| fraction > 0.0)
+            .map(|(bounds, fraction)| {
+                fraction * hexagon_overlap(bounds, center, apothem) / cell_area
+            })
+            .sum::<f64>()
+            .clamp(0.0, 1.0)
+    }
+
+    /// The `[q_min, q_max, r_min, r_max]` edges and fraction of each cell
+    fn cells(&self) -> impl Iterator<Item = ([f64; 4], f64)> + '_ {
+        self.fractions.iter().enumerate().map(|(i, &fraction)| {
+            let (q, r) = ((i % self.cells[0]) as f64, (i / self.cells[0]) as f64);
+            let q_min = self.origin[0] + q * self.cell_size[0];
+            let r_min = self.origin[1] + r * self.cell_size[1];
+            let bounds = [
+                q_min,
+                q_min + self.cell_size[0],
+                r_min,
+                r_min + self.cell_size[1],
+            ];
+            (bounds, fraction)
+        })
+    }
+}
+
+/// The apothem of the hexagon with the same area as the number of hexes within
+/// `radius` of a hex, `3n(n+1) + 1`
+fn apothem(radius: i32) -> f64 {
+    let n = radius as f64;
+    (n * n + n + 1.0 / 3.0).sqrt()
+}
+
+/// The area of the part of the `[q_min, q_max, r_min, r_max]` rectangle inside
+/// the hexagon of points within `apothem` of `center` along each cube axis
+fn hexagon_overlap(rect: [f64; 4], center: [f64; 2], apothem: f64) -> f64 {
+    let [q_min, q_max, r_min, r_max] = rect;
+    let mut polygon = vec![
+        [q_min, r_min],
+        [q_max, r_min],
+        [q_max, r_max],
+        [q_min, r_max],
+    ];
+
+    // The hexagon is where -apothem <= q, r, and q + r (i.e. -s) <= apothem,
+    // relative to the center
+    let normals = [
+        [1.0, 0.0],
+        [-1.0, 0.0],
+        [0.0, 1.0],
+        [0.0, -1.0],
+        [1.0, 1.0],
+        [-1.0, -1.0],
+    ];
+    for normal in normals {
+        let limit = apothem + normal[0] * center[0] + normal[1] * center[1];
+        polygon = clip(&polygon, normal, limit);
+        if polygon.is_empty() {
+            return 0.0;
+        }
+    }
+
+    // Shoelace formula
+    let twice_area: f64 = (0..polygon.len())
+        .map(|i| {
+            let [x1, y1] = polygon[i];
+            let [x2, y2] = polygon[(i + 1) % polygon.len()];
+            x1 * y2 - x2 * y1
+        })
+        .sum();
+    twice_area.abs() / 2.0
+}
+
+/// The part of a convex polygon where `normal . point <= limit`
+fn clip(polygon: &[[f64; 2]], normal: [f64; 2], limit: f64) -> Vec<[f64; 2]> {
+    let side = |p: [f64; 2]| normal[0] * p[0] + normal[1] * p[1] - limit;
+    let mut clipped = Vec::with_capacity(polygon.len() + 1);
+    for i in 0..polygon.len() {
+        let (a, b) = (polygon[i], polygon[(i + 1) % polygon.len()]);
+        let (side_a, side_b) = (side(a), side(b));
+        if side_a <= 0.0 {
+            clipped.push(a);
+        }
+        if (side_a < 0.0 && side_b > 0.0) || (side_a > 0.0 && side_b < 0.0) {
+            let t = side_a / (side_a - side_b);
+            clipped.push([a[0] + t * (b[0] - a[0]), a[1] + t * (b[1] - a[1])]);
+        }
+    }
+    clipped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::*;
+
+    fn assert_close(actual: f64, expected: f64, tolerance: f64) {
+        assert!(
+            (actual - expected).abs() <= tolerance,
+            "{} is not within {} of {}",
+            actual,
+            tolerance,
+            expected
+        );
+    }
+
+    #[rstest]
+    fn test_histogram_empty() {
+        assert_eq!(HexHistogram::new(&[], 10), None);
+    }
+
+    #[rstest]
+    fn test_histogram_fractions_sum_to_non_null() {
+        let coords = CubeCoord::new(0, 0, 0).range(20).collect::<Vec<_>>();
+        let histogram = HexHistogram::new(&coords, coords.len() * 2).unwrap();
+        assert_close(histogram.fractions.iter().sum(), 0.5, 1e-9);
+    }
+
+    #[rstest]
+    fn test_histogram_numbers_roundtrip() {
+        let coords = CubeCoord::new(-100, 30, 70).range(25).collect::<Vec<_>>();
+        let histogram = HexHistogram::new(&coords, coords.len()).unwrap();
+        let numbers = histogram.to_numbers();
+        assert_eq!(numbers.len(), HEADER_LEN + 16 * 16);
+
+        let read = HexHistogram::from_numbers(&numbers).unwrap();
+        assert_eq!(read.cells, histogram.cells);
+        for (read, original) in read.fractions.iter().zip(&histogram.fractions) {
+            assert_close(*read, *original, 1e-6);
+        }
+    }
+
+    #[rstest]
+    #[case(&[])]
+    #[case(&[0.0, 0.0, 1.0, 1.0, 2.0, 2.0, 0.5])]
+    #[case(&[0.0, 0.0, 1.0, 1.0, 0.0, 2.0])]
+    fn test_histogram_from_bad_numbers(#[case] numbers: &[f32]) {
+        assert_eq!(HexHistogram::from_numbers(numbers), None);
+    }
+
+    #[rstest]
+    fn test_histogram_single_hex() {
+        let coord = CubeCoord::new(3, -5, 2);
+        let histogram = HexHistogram::new(&[coord], 1).unwrap();
+        assert!(histogram.fraction_within(coord, 0) > 0.8);
+        assert_close(
+            histogram.fraction_within(CubeCoord::new(6, -5, -1), 1),
+            0.0,
+            1e-9,
+        );
+    }
+
+    #[rstest]
+    #[case(CubeCoord::new(0, 0, 0), 40, 1.0)]
+    #[case(CubeCoord::new(0, 0, 0), 10, 331.0 / 1261.0)]
+    #[case(CubeCoord::new(15, -5, -10), 4, 61.0 / 1261.0)]
+    #[case(CubeCoord::new(30, 0, -30), 5, 0.0)]
+    fn test_histogram_fraction_within(
+        #[case] center: CubeCoord,
+        #[case] radius: i32,
+        #[case] expected: f64,
+    ) {
+        // A hexagon of radius 20, with 1261 hexes
+        let coords = CubeCoord::new(0, 0, 0).range(20).collect::<Vec<_>>();
+        let histogram = HexHistogram::new(&coords, coords.len()).unwrap();
+        assert_close(histogram.fraction_within(center, radius), expected, 0.02);
+    }
+
+    #[rstest]
+    fn test_histogram_fraction_pairs_within() {
+        let near = CubeCoord::new(0, 0, 0).range(3).collect::<Vec<_>>();
+        let far = CubeCoord::new(100, 0, -100).range(3).collect::<Vec<_>>();
+        let near = HexHistogram::new(&near, near.len()).unwrap();
+        let far = HexHistogram::new(&far, far.len()).unwrap();
+
+        assert_close(near.fraction_pairs_within(&far, 10), 0.0, 1e-9);
+        assert_close(near.fraction_pairs_within(&far, 110), 1.0, 1e-9);
+        // 36% of pairs of hexes within 3 of a center are within 2 of each other
+        assert_close(near.fraction_pairs_within(&near, 2), 0.36, 0.05);
+    }
+
+    #[rstest]
+    #[case([0.0, 1.0, 0.0, 1.0], [0.5, 0.5], 10.0, 1.0)]
+    #[case([0.0, 1.0, 0.0, 1.0], [20.0, 0.5], 10.0, 0.0)]
+    #[case([-10.0, 10.0, -10.0, 10.0], [0.0, 0.0], 1.0, 3.0)]
+    #[case([0.0, 10.0, -10.0, 10.0], [0.0, 0.0], 1.0, 1.5)]
+    fn test_hexagon_overlap(
+        #[case] rect: [f64; 4],
+        #[case] center: [f64; 2],
+        #[case] apothem: f64,
+        #[case] expected: f64,
+    ) {
+        assert_close(hexagon_overlap(rect, center, apothem), expected, 1e-9);
+    }
+}
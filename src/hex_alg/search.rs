@@ -0,0 +1,695 @@
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::hash::Hash;
+
+use pgrx::check_for_interrupts;
+
+use super::{CubeCoord, NEIGHBOR_DIRS};
+
+/// What it costs to move over the grid. Entering a hex costs 1, unless it's
+/// blocked or has a cost of its own.
+#[derive(Debug, Default, Clone)]
+pub struct Terrain {
+    blocked: HashSet<CubeCoord>,
+    costs: HashMap<CubeCoord, f64>,
+}
+
+impl Terrain {
+    /// Make `coord` impassable
+    pub fn block(&mut self, coord: CubeCoord) {
+        self.blocked.insert(coord);
+    }
+
+    /// Set the cost of entering `coord`, where an infinite cost blocks it
+    pub fn set_cost(&mut self, coord: CubeCoord, cost: f64) {
+        if cost == f64::INFINITY {
+            self.block(coord);
+        } else {
+            self.costs.insert(coord, cost);
+        }
+    }
+
+    /// The cost of entering `coord`, or `None` if it's blocked
+    pub fn cost(&self, coord: CubeCoord) -> Option<f64> {
+        if self.blocked.contains(&coord) {
+            return None;
+        }
+        Some(self.costs.get(&coord).copied().unwrap_or(1.0))
+    }
+
+    /// The cheapest a single step can be
+    fn min_cost(&self) -> f64 {
+        self.costs.values().copied().fold(1.0, f64::min)
+    }
+}
+
+/// What it costs a unit that faces one of its neighbors to move: forward into
+/// the hex it faces and in reverse into the hex behind it, each as a multiple
+/// of the cost of entering that hex, and turning in place by a sixth of a
+/// turn. An infinite cost rules that move out.
+///
+/// `turn_radius` is how many hexes the unit must move, forward or in
+/// reverse, after each sixth of a turn before it can turn again, so 0 lets it
+/// turn as often as it likes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Maneuvering {
+    pub forward: f64,
+    pub reverse: f64,
+    pub turn: f64,
+    pub turn_radius: u32,
+}
+
+impl Default for Maneuvering {
+    fn default() -> Self {
+        Self {
+            forward: 1.0,
+            reverse: 1.0,
+            turn: 1.0,
+            turn_radius: 0,
+        }
+    }
+}
+
+/// A hex, or a hex and facing, waiting to be expanded, ordered so that a
+/// `BinaryHeap` pops the lowest `priority` first, and the one furthest along
+/// a path among equals
+#[derive(PartialEq, Debug)]
+struct Frontier<S> {
+    priority: f64,
+    cost: f64,
+    state: S,
+}
+
+impl<S: PartialEq> Eq for Frontier<S> {}
+
+impl<S: PartialEq> Ord for Frontier<S> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .priority
+            .total_cmp(&self.priority)
+            .then(self.cost.total_cmp(&other.cost))
+    }
+}
+
+impl<S: PartialEq> PartialOrd for Frontier<S> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// The cheapest known cost of reaching each hex, or hex and facing, and the
+/// one it's reached from
+type Visited<S = CubeCoord> = HashMap<S, (f64, Option<S>)>;
+
+/// The cheapest path from `start` to `goal`, as each hex along it with the
+/// total cost of reaching it.
+///
+/// `None` if there's no path, or if finding one would mean expanding more
+/// than `max_expansions` hexes. As the grid is unbounded, that's also how a
+/// search for a goal that's walled off ends.
+pub fn astar(
+    start: CubeCoord,
+    goal: CubeCoord,
+    terrain: &Terrain,
+    max_expansions: usize,
+) -> Option<Vec<(CubeCoord, f64)>> {
+    terrain.cost(goal)?;
+    // Every step costs at least this much, so the heuristic never overestimates
+    let min_cost = terrain.min_cost();
+    let heuristic = |coord: CubeCoord| coord.dist(goal) as f64 * min_cost;
+
+    let mut visited = Visited::from([(start, (0.0, None))]);
+    let mut frontier = BinaryHeap::from([Frontier {
+        priority: heuristic(start),
+        cost: 0.0,
+        state: start,
+    }]);
+    let mut expansions = 0;
+
+    while let Some(Frontier {
+        cost, state: coord, ..
+    }) = frontier.pop()
+    {
+        if coord == goal {
+            return Some(trace_back(&visited, goal));
+        }
+        // Already expanded from a cheaper path
+        if cost > visited[&coord].0 {
+            continue;
+        }
+        if expansions == max_expansions {
+            return None;
+        }
+        expansions += 1;
+        check_for_interrupts!();
+
+        for next in coord.neighbors() {
+            let Some(next_cost) = step_cost(terrain, &visited, cost, next) else {
+                continue;
+            };
+            visited.insert(next, (next_cost, Some(coord)));
+            frontier.push(Frontier {
+                priority: next_cost + heuristic(next),
+                cost: next_cost,
+                state: next,
+            });
+        }
+    }
+    None
+}
+
+/// The cheapest path for a unit at `start` facing the neighbor in `facing`,
+/// an index into the directions to neighbors, to reach `goal` facing any way,
+/// as each hex along it with the facing there and the total cost of reaching
+/// it. Each sixth of a turn in place is a step of its own, and the unit can
+/// turn straight away at `start`.
+///
+/// `None` if there's no path, or if finding one would mean expanding more
+/// than `max_expansions` hexes and facings.
+pub fn astar_facing(
+    start: CubeCoord,
+    facing: usize,
+    goal: CubeCoord,
+    terrain: &Terrain,
+    maneuvering: &Maneuvering,
+    max_expansions: usize,
+) -> Option<Vec<(CubeCoord, usize, f64)>> {
+    terrain.cost(goal)?;
+    // Every step into another hex costs at least this much, and turning only
+    // adds to it
+    let min_step = [maneuvering.forward, maneuvering.reverse]
+        .into_iter()
+        .filter(|multiple| multiple.is_finite())
+        .reduce(f64::min)
+        .map_or(0.0, |multiple| multiple * terrain.min_cost());
+    let heuristic = |coord: CubeCoord| coord.dist(goal) as f64 * min_step;
+
+    // Each state is a hex, a facing, and how many hexes the unit has moved
+    // since it last turned, up to the turn radius
+    let start = (start, facing % 6, maneuvering.turn_radius);
+    let mut visited = Visited::from([(start, (0.0, None))]);
+    let mut frontier = BinaryHeap::from([Frontier {
+        priority: heuristic(start.0),
+        cost: 0.0,
+        state: start,
+    }]);
+    let mut expansions = 0;
+
+    while let Some(Frontier { cost, state, .. }) = frontier.pop() {
+        if state.0 == goal {
+            let path = trace_back(&visited, state);
+            return Some(
+                path.into_iter()
+                    .map(|((coord, facing, _), cost)| (coord, facing, cost))
+                    .collect(),
+            );
+        }
+        // Already expanded from a cheaper path
+        if cost > visited[&state].0 {
+            continue;
+        }
+        if expansions == max_expansions {
+            return None;
+        }
+        expansions += 1;
+        check_for_interrupts!();
+
+        for (next, step) in maneuvers(state, terrain, maneuvering) {
+            let Some(next_cost) = cheaper(&visited, next, cost + step) else {
+                continue;
+            };
+            visited.insert(next, (next_cost, Some(state)));
+            frontier.push(Frontier {
+                priority: next_cost + heuristic(next.0),
+                cost: next_cost,
+                state: next,
+            });
+        }
+    }
+    None
+}
+
+/// Each hex and facing a unit at `coord` facing `facing` can get to in one
+/// step, having moved `straight` hexes since it last turned, with what that
+/// step costs
+fn maneuvers(
+    (coord, facing, straight): (CubeCoord, usize, u32),
+    terrain: &Terrain,
+    maneuvering: &Maneuvering,
+) -> Vec<((CubeCoord, usize, u32), f64)> {
+    let mut steps = Vec::with_capacity(4);
+    let moved = (straight + 1).min(maneuvering.turn_radius);
+    for (multiple, ahead) in [
+        (maneuvering.forward, NEIGHBOR_DIRS[facing]),
+        (maneuvering.reverse, -NEIGHBOR_DIRS[facing]),
+    ] {
+        if !multiple.is_finite() {
+            continue;
+        }
+        let next = coord + ahead;
+        if let Some(cost) = terrain.cost(next) {
+            steps.push(((next, facing, moved), cost * multiple));
+        }
+    }
+    if maneuvering.turn.is_finite() && straight >= maneuvering.turn_radius {
+        for turned in [(facing + 1) % 6, (facing + 5) % 6] {
+            steps.push(((coord, turned, 0), maneuvering.turn));
+        }
+    }
+    steps
+}
+
+/// Every hex that can be reached from `origin` for at most `budget`, with the
+/// cost of the cheapest path to it and the hex before it on that path, in
+/// order of cost.
+///
+/// `None` if that would mean expanding more than `max_expansions` hexes.
+pub fn reachable(
+    origin: CubeCoord,
+    budget: f64,
+    terrain: &Terrain,
+    max_expansions: usize,
+) -> Option<Vec<(CubeCoord, f64, Option<CubeCoord>)>> {
+    let mut visited = Visited::from([(origin, (0.0, None))]);
+    let mut frontier = BinaryHeap::from([Frontier {
+        priority: 0.0,
+        cost: 0.0,
+        state: origin,
+    }]);
+    let mut reached = Vec::new();
+
+    while let Some(Frontier {
+        cost, state: coord, ..
+    }) = frontier.pop()
+    {
+        let (known, previous) = visited[&coord];
+        // Already reached by a cheaper path
+        if cost > known {
+            continue;
+        }
+        if reached.len() == max_expansions {
+            return None;
+        }
+        reached.push((coord, cost, previous));
+        check_for_interrupts!();
+
+        for next in coord.neighbors() {
+            let Some(next_cost) = step_cost(terrain, &visited, cost, next) else {
+                continue;
+            };
+            if next_cost > budget {
+                continue;
+            }
+            visited.insert(next, (next_cost, Some(coord)));
+            frontier.push(Frontier {
+                priority: next_cost,
+                cost: next_cost,
+                state: next,
+            });
+        }
+    }
+    Some(reached)
+}
+
+/// The cost of stepping into `next` from a neighbor that cost `cost` to
+/// reach, if `next` can be entered and no path to it that cheap is known
+fn step_cost(terrain: &Terrain, visited: &Visited, cost: f64, next: CubeCoord) -> Option<f64> {
+    let next_cost = cost + terrain.cost(next)?;
+    cheaper(visited, next, next_cost)
+}
+
+/// `cost`, if no path to `next` that cheap is known
+fn cheaper<S: Eq + Hash>(visited: &Visited<S>, next: S, cost: f64) -> Option<f64> {
+    match visited.get(&next) {
+        Some(&(known, _)) if known <= cost => None,
+        _ => Some(cost),
+    }
+}
+
+/// Follow the hexes, or hexes and facings, each was reached from back from
+/// `end`, returning the path from the start in order
+fn trace_back<S: Copy + Eq + Hash>(visited: &Visited<S>, end: S) -> Vec<(S, f64)> {
+    let mut path = Vec::new();
+    let mut next = Some(end);
+    while let Some(state) = next {
+        let (cost, previous) = visited[&state];
+        path.push((state, cost));
+        next = previous;
+    }
+    path.reverse();
+    path
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::*;
+
+    fn coords(path: &[(CubeCoord, f64)]) -> Vec<[i32; 3]> {
+        path.iter().map(|(coord, _)| coord.components()).collect()
+    }
+
+    #[rstest]
+    #[case(CubeCoord::new(0, 0, 0), CubeCoord::new(3, -1, -2))]
+    #[case(CubeCoord::new(2, 2, -4), CubeCoord::new(-5, 1, 4))]
+    #[case(CubeCoord::new(1, 1, -2), CubeCoord::new(1, 1, -2))]
+    fn test_astar_open(#[case] start: CubeCoord, #[case] goal: CubeCoord) {
+        let path = astar(start, goal, &Terrain::default(), 1000).unwrap();
+        assert_eq!(path.len() as i32, start.dist(goal) + 1);
+        assert_eq!(path.first().unwrap().0, start);
+        assert_eq!(path.last().unwrap().0, goal);
+        for (i, pair) in path.windows(2).enumerate() {
+            assert_eq!(pair[0].0.dist(pair[1].0), 1);
+            assert_eq!(pair[1].1, (i + 1) as f64);
+        }
+    }
+
+    #[rstest]
+    fn test_astar_around_wall() {
+        let mut terrain = Terrain::default();
+        terrain.block(CubeCoord::new(1, 0, -1));
+        terrain.block(CubeCoord::new(1, -1, 0));
+
+        let path = astar(
+            CubeCoord::new(0, 0, 0),
+            CubeCoord::new(2, 0, -2),
+            &terrain,
+            1000,
+        );
+        assert_eq!(
+            coords(&path.unwrap()),
+            vec![[0, 0, 0], [0, 1, -1], [1, 1, -2], [2, 0, -2]]
+        );
+    }
+
+    #[rstest]
+    fn test_astar_costs() {
+        let mut terrain = Terrain::default();
+        terrain.set_cost(CubeCoord::new(1, 0, -1), 5.0);
+        terrain.set_cost(CubeCoord::new(1, -1, 0), 0.25);
+        terrain.set_cost(CubeCoord::new(2, -1, -1), 0.25);
+
+        let path = astar(
+            CubeCoord::new(0, 0, 0),
+            CubeCoord::new(2, 0, -2),
+            &terrain,
+            1000,
+        );
+        let path = path.unwrap();
+        assert_eq!(
+            coords(&path),
+            vec![[0, 0, 0], [1, -1, 0], [2, -1, -1], [2, 0, -2]]
+        );
+        assert_eq!(path.last().unwrap().1, 1.5);
+    }
+
+    #[rstest]
+    fn test_astar_infinite_cost_blocks() {
+        let mut terrain = Terrain::default();
+        terrain.set_cost(CubeCoord::new(1, 0, -1), f64::INFINITY);
+        assert_eq!(terrain.cost(CubeCoord::new(1, 0, -1)), None);
+
+        let path = astar(
+            CubeCoord::new(0, 0, 0),
+            CubeCoord::new(1, 0, -1),
+            &terrain,
+            1000,
+        );
+        assert_eq!(path, None);
+    }
+
+    #[rstest]
+    fn test_astar_walled_off() {
+        let goal = CubeCoord::new(5, 0, -5);
+        let mut terrain = Terrain::default();
+        for coord in goal.ring(1) {
+            terrain.block(coord);
+        }
+        assert_eq!(astar(CubeCoord::new(0, 0, 0), goal, &terrain, 1000), None);
+    }
+
+    #[rstest]
+    #[case(0, 1)]
+    #[case(1, 7)]
+    #[case(3, 37)]
+    fn test_reachable_open(#[case] budget: i32, #[case] expected: usize) {
+        let origin = CubeCoord::new(2, -1, -1);
+        let reached = reachable(origin, budget as f64, &Terrain::default(), 1000).unwrap();
+        assert_eq!(reached.len(), expected);
+        for (coord, cost, previous) in reached {
+            assert_eq!(cost, coord.dist(origin) as f64);
+            match previous {
+                Some(previous) => assert_eq!(previous.dist(coord), 1),
+                None => assert_eq!(coord, origin),
+            }
+        }
+    }
+
+    #[rstest]
+    fn test_reachable_in_order_of_cost() {
+        let reached = reachable(CubeCoord::new(0, 0, 0), 4.0, &Terrain::default(), 1000).unwrap();
+        assert!(reached.windows(2).all(|pair| pair[0].1 <= pair[1].1));
+    }
+
+    #[rstest]
+    fn test_reachable_terrain() {
+        let origin = CubeCoord::new(0, 0, 0);
+        let mut terrain = Terrain::default();
+        // Walled in on all sides but one, which leads into a swamp
+        for coord in origin.ring(1).skip(1) {
+            terrain.block(coord);
+        }
+        let gap = origin.ring(1).next().unwrap();
+        for coord in gap.neighbors() {
+            terrain.set_cost(coord, 2.0);
+        }
+
+        let reached = reachable(origin, 3.0, &terrain, 1000).unwrap();
+        let costs = reached
+            .iter()
+            .map(|&(coord, cost, _)| (coord.components(), cost))
+            .collect::<HashMap<_, _>>();
+        assert_eq!(costs.get(&origin.components()), Some(&0.0));
+        assert_eq!(costs.get(&gap.components()), Some(&1.0));
+        // The gap's neighbors outside the wall cost 2 to enter
+        assert_eq!(costs.len(), 2 + 3);
+        for coord in gap.neighbors() {
+            if terrain.cost(coord).is_some() && coord.dist(origin) == 2 {
+                assert_eq!(costs.get(&coord.components()), Some(&3.0));
+            }
+        }
+    }
+
+    fn facings(path: &[(CubeCoord, usize, f64)]) -> Vec<([i32; 3], usize)> {
+        path.iter()
+            .map(|&(coord, facing, _)| (coord.components(), facing))
+            .collect()
+    }
+
+    #[rstest]
+    fn test_astar_facing_straight() {
+        let path = astar_facing(
+            CubeCoord::new(0, 0, 0),
+            0,
+            CubeCoord::new(3, 0, -3),
+            &Terrain::default(),
+            &Maneuvering::default(),
+            1000,
+        )
+        .unwrap();
+        assert_eq!(
+            facings(&path),
+            vec![
+                ([0, 0, 0], 0),
+                ([1, 0, -1], 0),
+                ([2, 0, -2], 0),
+                ([3, 0, -3], 0)
+            ]
+        );
+        assert_eq!(path.last().unwrap().2, 3.0);
+    }
+
+    #[rstest]
+    fn test_astar_facing_turn_first() {
+        let path = astar_facing(
+            CubeCoord::new(0, 0, 0),
+            0,
+            CubeCoord::new(1, -1, 0),
+            &Terrain::default(),
+            &Maneuvering::default(),
+            1000,
+        )
+        .unwrap();
+        assert_eq!(
+            facings(&path),
+            vec![([0, 0, 0], 0), ([0, 0, 0], 1), ([1, -1, 0], 1)]
+        );
+        assert_eq!(path.last().unwrap().2, 2.0);
+    }
+
+    #[rstest]
+    // Backing up beats turning round
+    #[case(1.5, 2.0, 3.0, 3)]
+    // Turning round beats backing up
+    #[case(4.0, 0.5, 3.5, 6)]
+    // No going backwards at all
+    #[case(f64::INFINITY, 1.0, 5.0, 6)]
+    fn test_astar_facing_behind(
+        #[case] reverse: f64,
+        #[case] turn: f64,
+        #[case] expected_cost: f64,
+        #[case] expected_steps: usize,
+    ) {
+        let maneuvering = Maneuvering {
+            reverse,
+            turn,
+            ..Maneuvering::default()
+        };
+        let path = astar_facing(
+            CubeCoord::new(0, 0, 0),
+            0,
+            CubeCoord::new(-2, 0, 2),
+            &Terrain::default(),
+            &maneuvering,
+            1000,
+        )
+        .unwrap();
+        assert_eq!(path.last().unwrap().2, expected_cost);
+        assert_eq!(path.len(), expected_steps);
+    }
+
+    #[rstest]
+    #[case(CubeCoord::new(0, 0, 0), CubeCoord::new(3, -1, -2))]
+    #[case(CubeCoord::new(2, 2, -4), CubeCoord::new(-5, 1, 4))]
+    fn test_astar_facing_free_turns(#[case] start: CubeCoord, #[case] goal: CubeCoord) {
+        // Turning for nothing, a unit can go wherever it could without facing
+        let mut terrain = Terrain::default();
+        terrain.block(CubeCoord::new(1, 0, -1));
+        terrain.set_cost(CubeCoord::new(0, 1, -1), 3.0);
+        let maneuvering = Maneuvering {
+            turn: 0.0,
+            ..Maneuvering::default()
+        };
+        let path = astar_facing(start, 2, goal, &terrain, &maneuvering, 10000).unwrap();
+        let expected = astar(start, goal, &terrain, 10000).unwrap();
+        assert_eq!(path.last().unwrap().2, expected.last().unwrap().1);
+        for pair in path.windows(2) {
+            let ((from, facing, _), (to, _, _)) = (pair[0], pair[1]);
+            assert!(from == to || (to - from).abs() == 1);
+            if from != to {
+                let ahead = NEIGHBOR_DIRS[facing];
+                assert!(to - from == ahead || from - to == ahead);
+            }
+        }
+    }
+
+    #[rstest]
+    fn test_astar_facing_terrain() {
+        // Going round the swamp would take three turns and an extra step, so
+        // wading straight through is cheaper
+        let mut terrain = Terrain::default();
+        terrain.set_cost(CubeCoord::new(1, 0, -1), 4.0);
+        let maneuvering = Maneuvering {
+            reverse: f64::INFINITY,
+            ..Maneuvering::default()
+        };
+        let path = astar_facing(
+            CubeCoord::new(0, 0, 0),
+            0,
+            CubeCoord::new(2, 0, -2),
+            &terrain,
+            &maneuvering,
+            1000,
+        )
+        .unwrap();
+        assert_eq!(path.last().unwrap().2, 5.0);
+
+        terrain.block(CubeCoord::new(2, 0, -2));
+        let path = astar_facing(
+            CubeCoord::new(0, 0, 0),
+            0,
+            CubeCoord::new(2, 0, -2),
+            &terrain,
+            &maneuvering,
+            1000,
+        );
+        assert_eq!(path, None);
+    }
+
+    #[rstest]
+    fn test_astar_facing_stuck() {
+        // A unit that can only turn never gets anywhere
+        let maneuvering = Maneuvering {
+            forward: f64::INFINITY,
+            reverse: f64::INFINITY,
+            ..Maneuvering::default()
+        };
+        let start = CubeCoord::new(0, 0, 0);
+        let goal = CubeCoord::new(1, 0, -1);
+        let terrain = Terrain::default();
+        assert_eq!(
+            astar_facing(start, 3, goal, &terrain, &maneuvering, 1000),
+            None
+        );
+        assert_eq!(
+            astar_facing(start, 3, start, &terrain, &maneuvering, 1000),
+            Some(vec![(start, 3, 0.0)])
+        );
+    }
+
+    #[rstest]
+    // Turning twice on the spot, then straight there
+    #[case(0, 5.0, 6)]
+    // A hex between turns, going past the goal to turn back to it
+    #[case(1, 7.0, 8)]
+    // Two hexes between turns, looping wide round to it
+    #[case(2, 12.0, 13)]
+    fn test_astar_facing_turn_radius(
+        #[case] turn_radius: u32,
+        #[case] expected_cost: f64,
+        #[case] expected_steps: usize,
+    ) {
+        let maneuvering = Maneuvering {
+            reverse: f64::INFINITY,
+            turn_radius,
+            ..Maneuvering::default()
+        };
+        let path = astar_facing(
+            CubeCoord::new(0, 0, 0),
+            0,
+            CubeCoord::new(0, -3, 3),
+            &Terrain::default(),
+            &maneuvering,
+            10000,
+        )
+        .unwrap();
+        assert_eq!(path.last().unwrap().2, expected_cost);
+        assert_eq!(path.len(), expected_steps);
+        // Never turning again before going `turn_radius` hexes
+        let mut straight = turn_radius;
+        for pair in path.windows(2) {
+            let ((from, facing, _), (to, turned, _)) = (pair[0], pair[1]);
+            if facing == turned {
+                assert_ne!(from, to);
+                straight += 1;
+            } else {
+                assert!(straight >= turn_radius);
+                straight = 0;
+            }
+        }
+    }
+
+    #[rstest]
+    fn test_astar_max_expansions() {
+        let (start, goal) = (CubeCoord::new(0, 0, 0), CubeCoord::new(10, 0, -10));
+        assert_eq!(astar(start, goal, &Terrain::default(), 5), None);
+        assert!(astar(start, goal, &Terrain::default(), 10).is_some());
+    }
+    #[rstest]
+    fn test_reachable_max_expansions() {
+        let origin = CubeCoord::new(0, 0, 0);
+        // Three rings out is 37 hexes
+        assert_eq!(reachable(origin, 3.0, &Terrain::default(), 36), None);
+        assert!(reachable(origin, 3.0, &Terrain::default(), 37).is_some());
+    }
+}
@@ -0,0 +1,267 @@
+use std::collections::BTreeMap;
+
+use super::{CubeBounds, CubeCoord, HexRangeIter, DIAGONAL_DIRS};
+
+/// How far past the edge of a cone, in degrees, a hex can be and still count
+/// as on it, so that hexes exactly on the edge are in the cone despite
+/// rounding
+const CONE_EDGE_DEGREES: f64 = 1e-9;
+
+/// Every hex within `radius` of `origin` whose center is within `half_angle`
+/// degrees either side of `direction_degrees`, measured as for
+/// `CubeCoord::ray`, in the order of `CubeCoord::range`. Hexes exactly on the
+/// edges of the cone are in it, and `origin` is always in it.
+pub fn cone(
+    origin: CubeCoord,
+    direction_degrees: f64,
+    half_angle: f64,
+    radius: i32,
+) -> Vec<CubeCoord> {
+    if half_angle < 0.0 {
+        return Vec::new();
+    }
+    origin
+        .range(radius)
+        .filter(|&coord| {
+            coord == origin || {
+                let off = (angle_degrees(coord - origin) - direction_degrees).rem_euclid(360.0);
+                off.min(360.0 - off) <= half_angle + CONE_EDGE_DEGREES
+            }
+        })
+        .collect()
+}
+
+/// Every hex within `radius` of `origin` in the 60° wedge facing the
+/// neighbor in `direction`, an index into the directions to neighbors, in
+/// the order of `CubeCoord::range`. The hexes on the diagonals along either
+/// edge of the wedge are in it, as they are in the wedges either side of it.
+pub fn wedge(origin: CubeCoord, direction: usize, radius: i32) -> Vec<CubeCoord> {
+    origin
+        .range(radius)
+        .filter(|&coord| in_wedge(origin, direction, coord))
+        .collect()
+}
+
+/// Whether `coord` is in the wedge from `origin` facing the neighbor in
+/// `direction`, as for `wedge` but without limit on the distance
+pub fn in_wedge(origin: CubeCoord, direction: usize, coord: CubeCoord) -> bool {
+    let right_edge = DIAGONAL_DIRS[(direction + 5) % 6];
+    let left_edge = DIAGONAL_DIRS[direction % 6];
+    let offset = coord - origin;
+    turn(right_edge, offset) >= 0 && turn(offset, left_edge) >= 0
+}
+
+/// Every hex within range of any of the `disks`, each a center and a radius,
+/// once each and column by column as `CubeCoord::range` orders them.
+///
+/// Rather than going through every hex of every disk, each disk adds the
+/// stretch of each column it covers, and the stretches that overlap are
+/// merged before any hex in them is returned.
+pub fn range_union(disks: &[(CubeCoord, i32)]) -> Vec<CubeCoord> {
+    let mut columns = BTreeMap::<i64, Vec<(i64, i64)>>::new();
+    for &(center, radius) in disks {
+        let bounds = CubeBounds::around(center, radius);
+        for q in bounds.min[0] as i64..=bounds.max[0] as i64 {
+            columns.entry(q).or_default().push(bounds.column(q));
+        }
+    }
+
+    let mut coords = Vec::new();
+    for (q, mut stretches) in columns {
+        stretches.sort_unstable();
+        let mut next_r = i64::MIN;
+        for (low, high) in stretches {
+            for r in next_r.max(low)..=high {
+                coords.push(CubeCoord::new(q as i32, r as i32, (-q - r) as i32));
+            }
+            next_r = next_r.max(high + 1);
+        }
+    }
+    coords
+}
+
+/// Every hex within range of all of the `disks`, each a center and a radius,
+/// as `CubeCoord::range` orders them. Each range is bounded on each axis, so
+/// the hexes in all of them are those within the tightest bounds on each.
+/// There are none if there are no disks.
+pub fn range_intersection(disks: &[(CubeCoord, i32)]) -> HexRangeIter {
+    HexRangeIter::within(intersection_bounds(disks))
+}
+
+/// Whether any hex is within range of all of the `disks`, without going
+/// through the hexes
+pub fn ranges_intersect(disks: &[(CubeCoord, i32)]) -> bool {
+    !intersection_bounds(disks).is_empty()
+}
+
+fn intersection_bounds(disks: &[(CubeCoord, i32)]) -> CubeBounds {
+    disks
+        .iter()
+        .map(|&(center, radius)| CubeBounds::around(center, radius))
+        .reduce(CubeBounds::intersection)
+        .unwrap_or_else(CubeBounds::empty)
+}
+
+/// The angle of the center of `offset` from the center of the origin, in
+/// degrees counterclockwise from `(1,0)`
+pub(super) fn angle_degrees(offset: CubeCoord) -> f64 {
+    let x = offset.q as f64 + offset.r as f64 / 2.0;
+    let y = -(offset.r as f64) * 3f64.sqrt() / 2.0;
+    y.atan2(x).to_degrees()
+}
+
+/// Three times the cross product of `a` and `b`, which is positive when `b`
+/// is counterclockwise of `a`, negative when it's clockwise, and 0 when
+/// they're in line
+pub(super) fn turn(a: CubeCoord, b: CubeCoord) -> i64 {
+    let [aq, ar, a_s] = a.components().map(i64::from);
+    let [bq, br, bs] = b.components().map(i64::from);
+    -(ar * bs - a_s * br + a_s * bq - aq * bs + aq * br - ar * bq)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hex_alg::NEIGHBOR_DIRS;
+    use rstest::*;
+    use std::collections::HashSet;
+
+    fn set(coords: Vec<CubeCoord>) -> HashSet<CubeCoord> {
+        coords.into_iter().collect()
+    }
+
+    #[rstest]
+    #[case(0)]
+    #[case(1)]
+    #[case(4)]
+    fn test_wedge(#[case] direction: usize) {
+        let origin = CubeCoord::new(3, -2, -1);
+        let wedge = wedge(origin, direction, 3);
+        // The origin, the neighbor, then the diagonals either side and the
+        // hexes between them
+        assert_eq!(wedge.len(), 1 + 1 + 3 + 3);
+        assert!(wedge.contains(&(origin + NEIGHBOR_DIRS[direction] * 3)));
+        assert!(wedge.contains(&(origin + DIAGONAL_DIRS[direction])));
+        assert!(wedge.contains(&(origin + DIAGONAL_DIRS[(direction + 5) % 6])));
+        assert!(!wedge.contains(&(origin + NEIGHBOR_DIRS[(direction + 1) % 6])));
+    }
+
+    #[rstest]
+    fn test_in_wedge_far() {
+        let origin = CubeCoord::new(-1, 2, -1);
+        let far = origin + DIAGONAL_DIRS[1] * 1000 + NEIGHBOR_DIRS[2];
+        assert!(in_wedge(origin, 2, far));
+        assert!(!in_wedge(origin, 1, far));
+        assert!(in_wedge(origin, 1, origin + DIAGONAL_DIRS[1] * 1000));
+    }
+
+    #[rstest]
+    fn test_wedges_cover_range() {
+        let origin = CubeCoord::new(0, 0, 0);
+        let covered = (0..6)
+            .flat_map(|direction| wedge(origin, direction, 4))
+            .collect::<HashSet<_>>();
+        assert_eq!(covered, set(origin.range(4).collect()));
+    }
+
+    #[rstest]
+    #[case(0)]
+    #[case(2)]
+    #[case(5)]
+    fn test_cone_matches_wedge(#[case] direction: usize) {
+        let origin = CubeCoord::new(-2, 5, -3);
+        let cone = cone(origin, direction as f64 * 60.0, 30.0, 5);
+        assert_eq!(cone, wedge(origin, direction, 5));
+    }
+
+    #[rstest]
+    // Just the hexes straight ahead
+    #[case(0.0, 0.0, 4)]
+    // Either side of a diagonal, out to the neighbors
+    #[case(30.0, 30.0, 1 + 2 + 3 + 4)]
+    #[case(-330.0, 30.0, 1 + 2 + 3 + 4)]
+    // Everything
+    #[case(45.0, 180.0, 37)]
+    #[case(0.0, -1.0, 0)]
+    fn test_cone(#[case] direction_degrees: f64, #[case] half_angle: f64, #[case] expected: usize) {
+        let origin = CubeCoord::new(0, 0, 0);
+        let cone = cone(origin, direction_degrees, half_angle, 3);
+        assert_eq!(cone.len(), expected);
+    }
+
+    #[rstest]
+    #[case(&[])]
+    #[case(&[((0, 0), 3)])]
+    #[case(&[((0, 0), 2), ((3, 0), 2), ((1, 1), 0), ((0, 0), 1)])]
+    #[case(&[((-5, 2), 4), ((5, -2), 4), ((20, 20), -1), ((0, 9), 2)])]
+    fn test_range_union(#[case] disks: &[((i32, i32), i32)]) {
+        let disks = disks
+            .iter()
+            .map(|&((q, r), radius)| (CubeCoord::new(q, r, -q - r), radius))
+            .collect::<Vec<_>>();
+        let union = range_union(&disks);
+
+        let mut expected = disks
+            .iter()
+            .flat_map(|&(center, radius)| center.range(radius))
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect::<Vec<_>>();
+        expected.sort_by_key(|coord| (coord.q, coord.r));
+        assert_eq!(union, expected);
+    }
+
+    #[rstest]
+    #[case(&[], 0)]
+    #[case(&[((0, 0), 3)], 37)]
+    // Just touching
+    #[case(&[((0, 0), 2), ((4, 0), 2)], 1)]
+    #[case(&[((0, 0), 2), ((5, 0), 2)], 0)]
+    #[case(&[((0, 0), 3), ((2, 2), 5), ((-1, 4), 4), ((0, 0), 1)], 5)]
+    #[case(&[((-5, 2), 4), ((5, -2), 4), ((1, 1), 6)], 0)]
+    #[case(&[((3, 3), 0), ((1, 2), 3)], 1)]
+    #[case(&[((0, 0), 3), ((1, -1), -1)], 0)]
+    // Overlapping along q but not along r and s
+    #[case(&[((0, 0), 5), ((-3, 7), 1)], 0)]
+    fn test_range_intersection(#[case] disks: &[((i32, i32), i32)], #[case] expected: usize) {
+        let disks = disks
+            .iter()
+            .map(|&((q, r), radius)| (CubeCoord::new(q, r, -q - r), radius))
+            .collect::<Vec<_>>();
+        let intersection = range_intersection(&disks).collect::<Vec<_>>();
+
+        let within_all = |coord: &CubeCoord| {
+            disks
+                .iter()
+                .all(|&(center, radius)| coord.dist(center) <= radius)
+        };
+        let mut expected_coords = disks
+            .iter()
+            .flat_map(|&(center, radius)| center.range(radius))
+            .filter(within_all)
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect::<Vec<_>>();
+        expected_coords.sort_by_key(|coord| (coord.q, coord.r));
+        assert_eq!(intersection, expected_coords);
+        assert_eq!(intersection.len(), expected);
+        assert_eq!(ranges_intersect(&disks), expected > 0);
+    }
+
+    #[rstest]
+    fn test_range_union_one() {
+        let center = CubeCoord::new(4, -1, -3);
+        assert!(range_union(&[(center, 4)]).into_iter().eq(center.range(4)));
+    }
+
+    #[rstest]
+    fn test_cone_order() {
+        let origin = CubeCoord::new(1, 1, -2);
+        let cone = cone(origin, 100.0, 50.0, 3);
+        let range = origin
+            .range(3)
+            .filter(|coord| cone.contains(coord))
+            .collect::<Vec<_>>();
+        assert_eq!(cone, range);
+    }
+}
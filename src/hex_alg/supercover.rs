@@ -0,0 +1,172 @@
+use std::cmp::Ordering;
+use std::collections::HashSet;
+
+use super::CubeCoord;
+
+/// A fraction of the way along a line, kept exact
+#[derive(Debug, Copy, Clone)]
+pub(super) struct Fraction {
+    num: i64,
+    den: i64,
+}
+
+impl Fraction {
+    fn new(num: i64, den: i64) -> Self {
+        if den < 0 {
+            Self::new(-num, -den)
+        } else {
+            Self { num, den }
+        }
+    }
+}
+
+impl PartialEq for Fraction {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Fraction {}
+
+impl Ord for Fraction {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.num as i128 * other.den as i128).cmp(&(other.num as i128 * self.den as i128))
+    }
+}
+
+impl PartialOrd for Fraction {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Every hex that the line between the centers of `from` and `to` passes
+/// through or touches the edge or corner of, in the order the line enters
+/// them, and row by row among hexes it enters at the same corner.
+pub fn supercover(from: CubeCoord, to: CubeCoord) -> Vec<CubeCoord> {
+    // Every hex the line touches is next to one that it passes through the
+    // center of a step along it
+    let candidates = from
+        .linedraw(to)
+        .flat_map(|coord| {
+            let mut near = coord.neighbors();
+            near.push(coord);
+            near
+        })
+        .collect::<HashSet<_>>();
+
+    let mut touched = candidates
+        .into_iter()
+        .filter_map(|coord| Some((touching(from, to, coord)?, coord)))
+        .collect::<Vec<_>>();
+    touched.sort_by_key(|&((enter, leave), coord)| (enter, leave, coord.r, coord.q));
+    touched.into_iter().map(|(_, coord)| coord).collect()
+}
+
+/// How far along the line from `from` to `to` it enters and leaves the hex
+/// `coord`, edges included, or `None` if it never touches it
+pub(super) fn touching(
+    from: CubeCoord,
+    to: CubeCoord,
+    coord: CubeCoord,
+) -> Option<(Fraction, Fraction)> {
+    let offset = components(from, coord);
+    let delta = components(to, from);
+    let mut enter = Fraction::new(0, 1);
+    let mut leave = Fraction::new(1, 1);
+
+    // A point is in a hex when each difference between two of its components
+    // relative to the center is between -1 and 1, the edges halfway to
+    // the neighbors on either side
+    for (a, b) in [(0, 1), (1, 2), (2, 0)] {
+        let start = offset[a] - offset[b];
+        let slope = delta[a] - delta[b];
+        if slope == 0 {
+            if start.abs() > 1 {
+                return None;
+            }
+            continue;
+        }
+        let low = Fraction::new(-1 - start, slope);
+        let high = Fraction::new(1 - start, slope);
+        enter = enter.max(low.min(high));
+        leave = leave.min(low.max(high));
+    }
+    (enter <= leave).then_some((enter, leave))
+}
+
+/// The components of `a - b`, which can't overflow
+fn components(a: CubeCoord, b: CubeCoord) -> [i64; 3] {
+    [
+        a.q as i64 - b.q as i64,
+        a.r as i64 - b.r as i64,
+        a.s as i64 - b.s as i64,
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hex_alg::TieBreak;
+    use rstest::*;
+
+    #[rstest]
+    #[case(CubeCoord::new(0, 0, 0), CubeCoord::new(0, 0, 0))]
+    #[case(CubeCoord::new(0, 0, 0), CubeCoord::new(4, 0, -4))]
+    #[case(CubeCoord::new(3, -1, -2), CubeCoord::new(3, -6, 3))]
+    fn test_supercover_straight(#[case] from: CubeCoord, #[case] to: CubeCoord) {
+        // Along the directions to neighbors, the line only crosses edges in
+        // the middle
+        assert_eq!(supercover(from, to), from.linedraw(to).collect::<Vec<_>>());
+    }
+
+    #[rstest]
+    fn test_supercover_along_edge() {
+        let line = supercover(CubeCoord::new(0, 0, 0), CubeCoord::new(2, -1, -1));
+        assert_eq!(
+            line,
+            vec![
+                CubeCoord::new(0, 0, 0),
+                CubeCoord::new(1, -1, 0),
+                CubeCoord::new(1, 0, -1),
+                CubeCoord::new(2, -1, -1),
+            ]
+        );
+    }
+
+    #[rstest]
+    #[case(CubeCoord::new(0, 0, 0), CubeCoord::new(5, -1, -4))]
+    #[case(CubeCoord::new(-3, 5, -2), CubeCoord::new(3, -4, 1))]
+    #[case(CubeCoord::new(0, 0, 0), CubeCoord::new(4, 2, -6))]
+    #[case(CubeCoord::new(2, -1, -1), CubeCoord::new(-6, 3, 3))]
+    fn test_supercover_covers_lines(#[case] from: CubeCoord, #[case] to: CubeCoord) {
+        let line = supercover(from, to);
+        assert_eq!(line.first(), Some(&from));
+        assert_eq!(line.last(), Some(&to));
+
+        let covered = line.iter().copied().collect::<HashSet<_>>();
+        assert_eq!(covered.len(), line.len());
+        for tie_break in [TieBreak::Symmetric, TieBreak::Left, TieBreak::Right] {
+            assert!(from
+                .linedraw_with(to, tie_break)
+                .all(|coord| covered.contains(&coord)));
+        }
+
+        let back = supercover(to, from).into_iter().collect::<HashSet<_>>();
+        assert_eq!(back, covered);
+    }
+
+    #[rstest]
+    fn test_supercover_through_corner() {
+        // A third of the way along, the line passes through the corner where
+        // (1,0), (2,-1) and (2,0) meet, and touches all three
+        let line = supercover(CubeCoord::new(0, 0, 0), CubeCoord::new(5, -1, -4));
+        for coord in [
+            CubeCoord::new(1, 0, -1),
+            CubeCoord::new(2, -1, -1),
+            CubeCoord::new(2, 0, -2),
+        ] {
+            assert!(line.contains(&coord), "{:?}", coord);
+        }
+    }
+}
@@ -0,0 +1,520 @@
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::ops::RangeInclusive;
+
+use super::{CubeCoord, NEIGHBOR_DIRS};
+
+/// A slope within a sextant, as a fraction of a column per row
+#[derive(Debug, Copy, Clone)]
+struct Slope {
+    num: i64,
+    den: i64,
+}
+
+impl Slope {
+    fn new(num: i64, den: i64) -> Self {
+        Self { num, den }
+    }
+
+    /// The column in row `depth` whose center this slope passes through, or
+    /// the one before if it passes between two
+    fn col(self, depth: i64) -> i64 {
+        (depth as i128 * self.num as i128).div_euclid(self.den as i128) as i64
+    }
+}
+
+impl PartialEq for Slope {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Slope {}
+
+impl Ord for Slope {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.num as i128 * other.den as i128).cmp(&(other.num as i128 * self.den as i128))
+    }
+}
+
+impl PartialOrd for Slope {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// The corners of a hex, as thirds of a row and of a column from its center
+const CORNERS: [(i64, i64); 6] = [(2, 1), (1, 2), (-1, 1), (-2, -1), (-1, -2), (1, -1)];
+
+/// The slopes of the lines through the outermost corners of the hex at `col`
+/// in row `depth`. The lines strictly between them pass through its inside.
+fn shadow(depth: i64, col: i64) -> (Slope, Slope) {
+    let corners = CORNERS.map(|(rows, cols)| Slope::new(3 * col + cols, 3 * depth + rows));
+    let first = corners.into_iter().min().expect("a hex has corners");
+    let last = corners.into_iter().max().expect("a hex has corners");
+    (first, last)
+}
+
+/// A row of a sextant still to be scanned, and the slopes between which the
+/// lines from the origin are clear of blockers in the rows before it
+#[derive(Debug, Copy, Clone)]
+struct Row {
+    depth: i64,
+    start: Slope,
+    end: Slope,
+}
+
+impl Row {
+    fn next(self) -> Self {
+        Self {
+            depth: self.depth + 1,
+            ..self
+        }
+    }
+
+    /// The columns of the hexes in this row that the clear lines pass through
+    /// or touch
+    fn cols(&self) -> RangeInclusive<i64> {
+        let depth = self.depth;
+        let mut first = self.start.col(depth).clamp(0, depth);
+        while first > 0 && shadow(depth, first - 1).1 >= self.start {
+            first -= 1;
+        }
+        while shadow(depth, first).1 < self.start {
+            first += 1;
+        }
+        let mut last = self.end.col(depth).clamp(0, depth);
+        while last < depth && shadow(depth, last + 1).0 <= self.end {
+            last += 1;
+        }
+        while shadow(depth, last).0 > self.end {
+            last -= 1;
+        }
+        first..=last
+    }
+
+    /// Whether the line from the origin to the center of the hex at `col` is
+    /// clear, so that it's seen from the origin exactly when it can see the
+    /// origin
+    fn is_symmetric(&self, col: i64) -> bool {
+        let center = Slope::new(col, self.depth);
+        self.start <= center && center <= self.end
+    }
+}
+
+/// Every hex within `radius` of `origin` that can be seen from it past the
+/// `blockers`, by symmetric shadowcasting. Unless `walls_visible`, blockers
+/// are never visible themselves.
+///
+/// The grid is scanned in six sextants, each between two of the directions to
+/// neighbors. A hex is at a depth, its distance from the origin, and a column
+/// along its ring within the sextant, from 0 to the depth. A hex can be seen
+/// when the line from the center of the origin to its center doesn't pass
+/// through the inside of a blocker, or between two blockers along the edge
+/// they share, so if one hex can see another, that one can see it too. Lines
+/// that only touch a corner of a blocker, or run along its edge with a hex
+/// that isn't one, are clear. Each blocker shades the lines through its
+/// corners, which are worked out exactly, and a blocker is visible when any
+/// clear line reaches it.
+pub fn field_of_view(
+    origin: CubeCoord,
+    radius: i32,
+    blockers: &HashSet<CubeCoord>,
+    walls_visible: bool,
+) -> Vec<CubeCoord> {
+    if radius < 0 {
+        return Vec::new();
+    }
+    let mut visible = HashSet::from([origin]);
+
+    for sextant in 0..6 {
+        let hex_at = |depth: i64, col: i64| {
+            origin
+                + NEIGHBOR_DIRS[sextant] * depth as i32
+                + NEIGHBOR_DIRS[(sextant + 2) % 6] * col as i32
+        };
+
+        let mut rows = vec![Row {
+            depth: 1,
+            start: Slope::new(0, 1),
+            end: Slope::new(1, 1),
+        }];
+        while let Some(row) = rows.pop() {
+            if row.depth > radius as i64 {
+                continue;
+            }
+            // The clear lines carried on to the next row start here, after
+            // the blockers so far. The shadows of blockers next to each other
+            // in a row overlap, or meet along the edge between them, so a run
+            // of them shades everything from the start of the first's shadow
+            // to the end of the last's.
+            let mut start = row.start;
+            let mut previous_is_wall = None;
+            for col in row.cols() {
+                let hex = hex_at(row.depth, col);
+                let is_wall = blockers.contains(&hex);
+                if (is_wall && walls_visible) || (!is_wall && row.is_symmetric(col)) {
+                    visible.insert(hex);
+                }
+                if is_wall {
+                    let (shadow_start, shadow_end) = shadow(row.depth, col);
+                    // The start of a wall ends the clear lines through the row
+                    // so far
+                    if previous_is_wall == Some(false) && start <= shadow_start {
+                        rows.push(Row {
+                            start,
+                            end: shadow_start,
+                            ..row.next()
+                        });
+                    }
+                    start = start.max(shadow_end);
+                }
+                previous_is_wall = Some(is_wall);
+            }
+            if previous_is_wall == Some(false) && start <= row.end {
+                rows.push(Row {
+                    start,
+                    ..row.next()
+                });
+            }
+        }
+    }
+
+    nearest_first(origin, visible.into_iter().collect())
+}
+
+/// Whether a viewer `from_height` above the ground at `from` can see a point
+/// `to_height` above the ground at `to`, where the ground of each hex is at its
+/// `elevation`, or 0 if it has none. The hexes along the line between them
+/// block the view if their ground is above the line of sight where it passes
+/// over their centers.
+pub fn line_of_sight_elevated(
+    from: CubeCoord,
+    from_height: f64,
+    to: CubeCoord,
+    to_height: f64,
+    elevation: &HashMap<CubeCoord, f64>,
+) -> bool {
+    let ground = |coord: CubeCoord| elevation.get(&coord).copied().unwrap_or(0.0);
+    let distance = from.dist(to);
+    let start = ground(from) + from_height;
+    let end = ground(to) + to_height;
+    hexes_between(from, to).all(|(i, coord)| {
+        let t = i as f64 / distance as f64;
+        ground(coord) <= start + (end - start) * t
+    })
+}
+
+/// The total opacity of the hexes between `from` and `to` along the line
+/// between them, where each hex's is its `opacity`, or 0 if it has none
+pub fn obscurement(from: CubeCoord, to: CubeCoord, opacity: &HashMap<CubeCoord, f64>) -> f64 {
+    hexes_between(from, to)
+        .map(|(_, coord)| opacity.get(&coord).copied().unwrap_or(0.0))
+        .sum()
+}
+
+/// The `obscurement` of the line from `origin` to every hex within `radius`
+/// of it, nearest first.
+///
+/// This sums each line separately, in O(`radius`³) time. A total can't be
+/// built from the total of the hex before it on the line, since the line to
+/// that hex can pass through different hexes on the way.
+pub fn obscurement_in_range(
+    origin: CubeCoord,
+    radius: i32,
+    opacity: &HashMap<CubeCoord, f64>,
+) -> Vec<(CubeCoord, f64)> {
+    nearest_first(origin, origin.range(radius).collect())
+        .into_iter()
+        .map(|coord| (coord, obscurement(origin, coord, opacity)))
+        .collect()
+}
+
+/// The hexes strictly between `from` and `to` along the line between them,
+/// with how many steps each is from `from`
+fn hexes_between(from: CubeCoord, to: CubeCoord) -> impl Iterator<Item = (usize, CubeCoord)> {
+    let distance = from.dist(to);
+    from.linedraw(to)
+        .enumerate()
+        .skip(1)
+        .take((distance - 1).max(0) as usize)
+}
+
+/// Every hex within `radius` of `origin` where a point `target_height` above
+/// the ground can be seen by a viewer `viewer_height` above the ground at
+/// `origin`, as for `line_of_sight_elevated`.
+///
+/// The line to each hex is drawn and checked on its own, in O(`radius`³)
+/// time. The line to a far hex doesn't always run through the hexes of the
+/// lines to nearer ones, so carrying the highest slope outwards along them
+/// would give different answers from `line_of_sight_elevated`.
+pub fn field_of_view_elevated(
+    origin: CubeCoord,
+    radius: i32,
+    viewer_height: f64,
+    target_height: f64,
+    elevation: &HashMap<CubeCoord, f64>,
+) -> Vec<CubeCoord> {
+    let visible = origin
+        .range(radius)
+        .filter(|&coord| {
+            line_of_sight_elevated(origin, viewer_height, coord, target_height, elevation)
+        })
+        .collect();
+    nearest_first(origin, visible)
+}
+
+/// Sort hexes by their distance from `origin`, then row by row
+fn nearest_first(origin: CubeCoord, mut coords: Vec<CubeCoord>) -> Vec<CubeCoord> {
+    coords.sort_by_key(|&coord| (coord.dist(origin), coord.r, coord.q));
+    coords
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hex_alg::supercover::touching;
+    use rstest::*;
+
+    fn blockers(coords: &[(i32, i32)]) -> HashSet<CubeCoord> {
+        coords
+            .iter()
+            .map(|&(q, r)| CubeCoord::new(q, r, -q - r))
+            .collect()
+    }
+
+    /// Blockers scattered over a range of 8 around the origin, at random but
+    /// the same each time
+    fn scattered_blockers() -> HashSet<CubeCoord> {
+        let mut state = 12345u32;
+        CubeCoord::new(0, 0, 0)
+            .range(8)
+            .filter(|coord| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                *coord != CubeCoord::new(0, 0, 0) && state >> 30 == 0
+            })
+            .collect()
+    }
+
+    #[rstest]
+    #[case(0, 1)]
+    #[case(1, 7)]
+    #[case(4, 61)]
+    fn test_fov_open(#[case] radius: i32, #[case] expected: usize) {
+        let origin = CubeCoord::new(3, -2, -1);
+        let visible = field_of_view(origin, radius, &HashSet::new(), true);
+        assert_eq!(visible.len(), expected);
+        assert!(visible.iter().all(|coord| coord.dist(origin) <= radius));
+        assert_eq!(visible[0], origin);
+    }
+
+    #[rstest]
+    fn test_fov_negative_radius() {
+        let visible = field_of_view(CubeCoord::new(0, 0, 0), -1, &HashSet::new(), true);
+        assert!(visible.is_empty());
+    }
+
+    #[rstest]
+    #[case(true)]
+    #[case(false)]
+    fn test_fov_behind_wall(#[case] walls_visible: bool) {
+        let origin = CubeCoord::new(0, 0, 0);
+        let wall = blockers(&[(2, -1), (2, 0), (2, -2)]);
+        let visible = field_of_view(origin, 4, &wall, walls_visible)
+            .into_iter()
+            .collect::<HashSet<_>>();
+
+        // Directly behind the wall
+        assert!(!visible.contains(&CubeCoord::new(3, -1, -2)));
+        assert!(!visible.contains(&CubeCoord::new(4, -2, -2)));
+        // Off to the side of it
+        assert!(visible.contains(&CubeCoord::new(2, 1, -3)));
+        assert!(visible.contains(&CubeCoord::new(-4, 0, 4)));
+        // The line to (3,1) clips the corner of (2,0)
+        assert!(!visible.contains(&CubeCoord::new(3, 1, -4)));
+
+        for coord in &wall {
+            assert_eq!(visible.contains(coord), walls_visible);
+        }
+    }
+
+    #[rstest]
+    fn test_fov_adjacent_blocker() {
+        let origin = CubeCoord::new(0, 0, 0);
+        let visible = field_of_view(origin, 3, &blockers(&[(1, 0)]), true);
+        let visible = visible.into_iter().collect::<HashSet<_>>();
+        assert!(visible.contains(&CubeCoord::new(1, 0, -1)));
+        assert!(!visible.contains(&CubeCoord::new(2, 0, -2)));
+        assert!(!visible.contains(&CubeCoord::new(3, 0, -3)));
+        // Lines that only run along the blocker's edges are clear
+        assert!(visible.contains(&CubeCoord::new(2, -1, -1)));
+        assert!(visible.contains(&CubeCoord::new(1, 1, -2)));
+    }
+
+    fn hex_values(coords: &[(i32, i32, f64)]) -> HashMap<CubeCoord, f64> {
+        coords
+            .iter()
+            .map(|&(q, r, height)| (CubeCoord::new(q, r, -q - r), height))
+            .collect()
+    }
+
+    #[rstest]
+    // Flat ground
+    #[case(0.0, 0.0, &[], true)]
+    // A wall in the way
+    #[case(1.0, 0.0, &[(2, 0, 2.0)], false)]
+    // A tower sees over it
+    #[case(5.0, 0.0, &[(2, 0, 2.0)], true)]
+    // Just over the top of it, where the line passes 1 + (3 - 1) / 2 high
+    #[case(1.0, 3.0, &[(2, 0, 2.0)], true)]
+    #[case(1.0, 2.9, &[(2, 0, 2.0)], false)]
+    // A viewer in a valley can't see out
+    #[case(1.0, 1.0, &[(0, 0, -3.0), (1, 0, -1.0)], false)]
+    // The ground at the ends lifts both points
+    #[case(0.5, 0.5, &[(0, 0, 3.0), (2, 0, 2.5), (4, 0, 3.0)], true)]
+    fn test_line_of_sight_elevated(
+        #[case] from_height: f64,
+        #[case] to_height: f64,
+        #[case] ground: &[(i32, i32, f64)],
+        #[case] expected: bool,
+    ) {
+        let (from, to) = (CubeCoord::new(0, 0, 0), CubeCoord::new(4, 0, -4));
+        let ground = hex_values(ground);
+        assert_eq!(
+            line_of_sight_elevated(from, from_height, to, to_height, &ground),
+            expected
+        );
+    }
+
+    #[rstest]
+    fn test_line_of_sight_elevated_neighbors() {
+        let coord = CubeCoord::new(0, 0, 0);
+        let ground = hex_values(&[(1, 0, 100.0)]);
+        assert!(line_of_sight_elevated(coord, 0.0, coord, 0.0, &ground));
+        assert!(line_of_sight_elevated(
+            coord,
+            0.0,
+            CubeCoord::new(1, 0, -1),
+            0.0,
+            &ground
+        ));
+    }
+
+    #[rstest]
+    // Smoke at both ends doesn't count
+    #[case(&[(0, 0, 0.5), (4, 0, 0.5)], 0.0)]
+    #[case(&[(1, 0, 0.25), (2, 0, 0.5), (3, 0, 0.5)], 1.25)]
+    // Off the line
+    #[case(&[(1, 0, 0.25), (2, 1, 0.5), (3, -1, 0.5)], 0.25)]
+    fn test_obscurement(#[case] opacity: &[(i32, i32, f64)], #[case] expected: f64) {
+        let (from, to) = (CubeCoord::new(0, 0, 0), CubeCoord::new(4, 0, -4));
+        let opacity = hex_values(opacity);
+        assert_eq!(obscurement(from, to, &opacity), expected);
+        assert_eq!(obscurement(to, from, &opacity), expected);
+    }
+
+    #[rstest]
+    fn test_obscurement_in_range() {
+        let origin = CubeCoord::new(0, 0, 0);
+        let forest = hex_values(&[(0, 0, 1.0), (1, 0, 0.5), (2, -1, 0.25)]);
+        let obscured = obscurement_in_range(origin, 2, &forest);
+
+        assert_eq!(obscured.len(), 19);
+        assert_eq!(obscured[0], (origin, 0.0));
+        let total = |coord: CubeCoord| {
+            obscured
+                .iter()
+                .find(|&&(c, _)| c == coord)
+                .map(|&(_, total)| total)
+        };
+        assert_eq!(total(CubeCoord::new(1, 0, -1)), Some(0.0));
+        assert_eq!(total(CubeCoord::new(2, 0, -2)), Some(0.5));
+        assert_eq!(total(CubeCoord::new(0, 2, -2)), Some(0.0));
+    }
+
+    #[rstest]
+    fn test_fov_elevated() {
+        let origin = CubeCoord::new(0, 0, 0);
+        let ring = origin.ring(2).map(|coord| {
+            let [q, r, _] = coord.components();
+            (q, r, 2.0)
+        });
+        let walls = hex_values(&ring.collect::<Vec<_>>());
+
+        // Walled in, the viewer only sees the wall and what's inside it
+        let visible = field_of_view_elevated(origin, 4, 1.0, 0.0, &walls);
+        assert_eq!(visible, nearest_first(origin, origin.range(2).collect()));
+
+        // From high enough, it sees over the wall, if not right behind it
+        let visible = field_of_view_elevated(origin, 4, 4.0, 0.0, &walls);
+        assert_eq!(visible.len(), 61 - 18);
+        assert!(visible.iter().all(|coord| coord.dist(origin) != 3));
+    }
+
+    #[rstest]
+    fn test_fov_symmetric() {
+        let blockers = scattered_blockers();
+        let floors = CubeCoord::new(0, 0, 0)
+            .range(5)
+            .filter(|coord| !blockers.contains(coord))
+            .collect::<Vec<_>>();
+        let views = floors
+            .iter()
+            .map(|&coord| {
+                let visible = field_of_view(coord, 10, &blockers, false);
+                (coord, visible.into_iter().collect::<HashSet<_>>())
+            })
+            .collect::<Vec<_>>();
+
+        for (a, seen_from_a) in &views {
+            for (b, seen_from_b) in &views {
+                assert_eq!(
+                    seen_from_a.contains(b),
+                    seen_from_b.contains(a),
+                    "{:?} and {:?}",
+                    a,
+                    b
+                );
+            }
+        }
+    }
+
+    /// Whether the line between the centers of `from` and `to` is clear of
+    /// `blockers`, checking each one exactly against the line. A blocker it
+    /// touches for more than a point blocks it, unless the line only runs
+    /// along its edge with a hex that isn't a blocker.
+    fn line_is_clear(from: CubeCoord, to: CubeCoord, blockers: &HashSet<CubeCoord>) -> bool {
+        blockers
+            .iter()
+            .filter(|&&blocker| blocker != from && blocker != to)
+            .all(|&blocker| match touching(from, to, blocker) {
+                Some((enter, leave)) if enter < leave => blocker
+                    .neighbors()
+                    .into_iter()
+                    .filter(|neighbor| !blockers.contains(neighbor))
+                    .any(|neighbor| touching(from, to, neighbor) == Some((enter, leave))),
+                _ => true,
+            })
+    }
+
+    #[rstest]
+    #[case(CubeCoord::new(0, 0, 0), scattered_blockers(), 9)]
+    #[case(CubeCoord::new(2, -3, 1), scattered_blockers(), 7)]
+    #[case(CubeCoord::new(0, 0, 0), blockers(&[(-3, 0)]), 6)]
+    #[case(CubeCoord::new(0, 0, 0), blockers(&[(-3, 1)]), 6)]
+    // A wall along a diagonal direction, which lines along it run between
+    #[case(CubeCoord::new(0, 0, 0), blockers(&[(1, -1), (1, 0), (3, -2), (3, -1)]), 6)]
+    fn test_fov_exact(
+        #[case] origin: CubeCoord,
+        #[case] blockers: HashSet<CubeCoord>,
+        #[case] radius: i32,
+    ) {
+        let visible = field_of_view(origin, radius, &blockers, false)
+            .into_iter()
+            .collect::<HashSet<_>>();
+        for coord in origin.range(radius) {
+            let expected = !blockers.contains(&coord) && line_is_clear(origin, coord, &blockers);
+            assert_eq!(visible.contains(&coord), expected, "{:?}", coord);
+        }
+    }
+}
@@ -0,0 +1,348 @@
+use std::fmt;
+use std::str::FromStr;
+
+use crate::hexrange::HexRange;
+use crate::Hex;
+
+/// Reasons a string can fail to parse as a `Hex`
+#[derive(PartialEq, Debug, Clone)]
+pub enum ParseHexError {
+    /// The input was empty or only whitespace
+    Empty,
+    /// The input wasn't wrapped in `()`, `[]` or `{}`
+    BadDelimiters,
+    /// The input had a number of components other than 2 (axial) or 3 (cube)
+    ComponentCount(usize),
+    /// A component wasn't a valid 32-bit integer
+    InvalidInteger(String),
+    /// A cube coordinate whose components don't sum to zero
+    NotOnPlane { q: i32, r: i32, s: i32 },
+    /// A JSON key other than `q`, `r` or `s`
+    UnknownKey(String),
+    /// A JSON key given more than once
+    DuplicateKey(String),
+    /// A JSON object without a `q` or `r` key
+    MissingKey(&'static str),
+}
+
+impl fmt::Display for ParseHexError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseHexError::Empty => write!(f, "Input is empty."),
+            ParseHexError::BadDelimiters => write!(
+                f,
+                "Expected \"(q,r)\", \"[q,r]\", \"(q,r,s)\" or {{\"q\":q,\"r\":r}}."
+            ),
+            ParseHexError::ComponentCount(n) => {
+                write!(f, "Expected 2 or 3 components but found {}.", n)
+            }
+            ParseHexError::InvalidInteger(value) => {
+                write!(f, "\"{}\" is not a valid integer.", value)
+            }
+            ParseHexError::NotOnPlane { q, r, s } => write!(
+                f,
+                "Cube coordinates must satisfy q + r + s = 0, but {} + {} + {} = {}.",
+                q,
+                r,
+                s,
+                *q as i64 + *r as i64 + *s as i64
+            ),
+            ParseHexError::UnknownKey(key) => write!(f, "Unknown key \"{}\".", key),
+            ParseHexError::DuplicateKey(key) => write!(f, "Key \"{}\" given more than once.", key),
+            ParseHexError::MissingKey(key) => write!(f, "Missing key \"{}\".", key),
+        }
+    }
+}
+
+impl std::error::Error for ParseHexError {}
+
+/// Reasons a string can fail to parse as a `HexRange`
+#[derive(PartialEq, Debug, Clone)]
+pub enum ParseHexRangeError {
+    /// The input wasn't of the form `<center,radius>`
+    BadDelimiters,
+    /// The center wasn't a valid hex
+    Center(ParseHexError),
+    /// The radius wasn't a valid 32-bit integer
+    InvalidRadius(String),
+    /// The radius was negative
+    NegativeRadius(i32),
+}
+
+impl fmt::Display for ParseHexRangeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseHexRangeError::BadDelimiters => {
+                write!(f, "Expected \"<center,radius>\", e.g. \"<(0,0),3>\".")
+            }
+            ParseHexRangeError::Center(e) => write!(f, "Invalid center: {}", e),
+            ParseHexRangeError::InvalidRadius(value) => {
+                write!(f, "\"{}\" is not a valid radius.", value)
+            }
+            ParseHexRangeError::NegativeRadius(radius) => {
+                write!(f, "Radius must not be negative, but is {}.", radius)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ParseHexRangeError {}
+
+impl Hex {
+    /// Pack into the 8-byte on-disk and in-memory representation: `q` in the
+    /// high 32 bits and `r` in the low 32 bits
+    pub fn to_datum_bits(self) -> u64 {
+        ((self.q as u32 as u64) << 32) | (self.r as u32 as u64)
+    }
+
+    /// Unpack from the representation produced by `to_datum_bits`
+    pub fn from_datum_bits(bits: u64) -> Self {
+        Hex {
+            q: (bits >> 32) as u32 as i32,
+            r: bits as u32 as i32,
+        }
+    }
+
+    /// Binary wire format used by `hex_send` and `hex_recv`: `q` then `r`, each
+    /// as a big-endian 4-byte signed integer
+    pub fn to_send_bytes(self) -> [u8; 8] {
+        let mut bytes = [0; 8];
+        bytes[..4].copy_from_slice(&self.q.to_be_bytes());
+        bytes[4..].copy_from_slice(&self.r.to_be_bytes());
+        bytes
+    }
+
+    /// Read the binary wire format produced by `to_send_bytes`
+    pub fn from_send_bytes(bytes: [u8; 8]) -> Self {
+        let (q, r) = bytes.split_at(4);
+        Hex {
+            q: i32::from_be_bytes(q.try_into().unwrap()),
+            r: i32::from_be_bytes(r.try_into().unwrap()),
+        }
+    }
+}
+
+/// Canonical text form of a hex, `(q,r)`
+impl fmt::Display for Hex {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "({},{})", self.q, self.r)
+    }
+}
+
+/// Parse any of the accepted text forms of a hex:
+///
+/// - `(q,r)` - the canonical output form
+/// - `[q,r]`
+/// - `(q,r,s)` or `[q,r,s]` - cube coordinates, where `q + r + s` must be 0
+/// - `{"q":q,"r":r}` - the JSON form used by earlier versions of the extension
+impl FromStr for Hex {
+    type Err = ParseHexError;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let input = input.trim();
+        if input.is_empty() {
+            return Err(ParseHexError::Empty);
+        }
+
+        if let Some(body) = strip_delimiters(input, '{', '}') {
+            return parse_json(body);
+        }
+
+        let body = strip_delimiters(input, '(', ')')
+            .or_else(|| strip_delimiters(input, '[', ']'))
+            .ok_or(ParseHexError::BadDelimiters)?;
+
+        let components = body
+            .split(',')
+            .map(parse_component)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        match components[..] {
+            [q, r] => Ok(Hex { q, r }),
+            [q, r, s] => {
+                if q as i64 + r as i64 + s as i64 != 0 {
+                    return Err(ParseHexError::NotOnPlane { q, r, s });
+                }
+                Ok(Hex { q, r })
+            }
+            _ => Err(ParseHexError::ComponentCount(components.len())),
+        }
+    }
+}
+
+/// Canonical text form of a hex range, `<(q,r),radius>`
+impl fmt::Display for HexRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<{},{}>", self.center, self.radius)
+    }
+}
+
+/// Parse `<center,radius>`, where the center is in any of the text forms
+/// accepted for a `Hex`
+impl FromStr for HexRange {
+    type Err = ParseHexRangeError;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let body =
+            strip_delimiters(input.trim(), '<', '>').ok_or(ParseHexRangeError::BadDelimiters)?;
+        let (center, radius) = body
+            .rsplit_once(',')
+            .ok_or(ParseHexRangeError::BadDelimiters)?;
+
+        let center = center.parse().map_err(ParseHexRangeError::Center)?;
+        let radius = radius.trim();
+        let radius = i32::from_str(radius)
+            .map_err(|_| ParseHexRangeError::InvalidRadius(radius.to_string()))?;
+        if radius < 0 {
+            return Err(ParseHexRangeError::NegativeRadius(radius));
+        }
+
+        Ok(HexRange { center, radius })
+    }
+}
+
+fn strip_delimiters(input: &str, open: char, close: char) -> Option<&str> {
+    input.strip_prefix(open)?.strip_suffix(close)
+}
+
+fn parse_component(value: &str) -> Result<i32, ParseHexError> {
+    let value = value.trim();
+    i32::from_str(value).map_err(|_| ParseHexError::InvalidInteger(value.to_string()))
+}
+
+/// Parse the body of a flat JSON object with integer `q`, `r` and optionally
+/// `s` keys
+fn parse_json(body: &str) -> Result<Hex, ParseHexError> {
+    let (mut q, mut r, mut s) = (None, None, None);
+
+    for member in body.split(',') {
+        let (key, value) = member.split_once(':').ok_or(ParseHexError::BadDelimiters)?;
+        let key = strip_delimiters(key.trim(), '"', '"').ok_or(ParseHexError::BadDelimiters)?;
+
+        let slot = match key {
+            "q" => &mut q,
+            "r" => &mut r,
+            "s" => &mut s,
+            _ => return Err(ParseHexError::UnknownKey(key.to_string())),
+        };
+        if slot.is_some() {
+            return Err(ParseHexError::DuplicateKey(key.to_string()));
+        }
+        *slot = Some(parse_component(value)?);
+    }
+
+    let q = q.ok_or(ParseHexError::MissingKey("q"))?;
+    let r = r.ok_or(ParseHexError::MissingKey("r"))?;
+    if let Some(s) = s {
+        if q as i64 + r as i64 + s as i64 != 0 {
+            return Err(ParseHexError::NotOnPlane { q, r, s });
+        }
+    }
+
+    Ok(Hex { q, r })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::*;
+
+    #[rstest]
+    #[case("(1,2)", Hex { q: 1, r: 2 })]
+    #[case("[1,2]", Hex { q: 1, r: 2 })]
+    #[case("[0, -2]", Hex { q: 0, r: -2 })]
+    #[case("  ( -3 ,\t4 )  ", Hex { q: -3, r: 4 })]
+    #[case("(1,2,-3)", Hex { q: 1, r: 2 })]
+    #[case("[1, 2, -3]", Hex { q: 1, r: 2 })]
+    #[case("{\"q\":0,\"r\":-2}", Hex { q: 0, r: -2 })]
+    #[case("{ \"r\": 5, \"q\": -1 }", Hex { q: -1, r: 5 })]
+    #[case("{\"q\":1,\"r\":2,\"s\":-3}", Hex { q: 1, r: 2 })]
+    #[case("(2147483647,-2147483648)", Hex { q: i32::MAX, r: i32::MIN })]
+    fn test_parse(#[case] input: &str, #[case] expected: Hex) {
+        assert_eq!(input.parse::<Hex>(), Ok(expected));
+    }
+
+    #[rstest]
+    #[case("", ParseHexError::Empty)]
+    #[case("   ", ParseHexError::Empty)]
+    #[case("1,2", ParseHexError::BadDelimiters)]
+    #[case("(1,2]", ParseHexError::BadDelimiters)]
+    #[case("(1)", ParseHexError::ComponentCount(1))]
+    #[case("(1,2,-3,0)", ParseHexError::ComponentCount(4))]
+    #[case("(1,x)", ParseHexError::InvalidInteger("x".to_string()))]
+    #[case("(1,)", ParseHexError::InvalidInteger("".to_string()))]
+    #[case("(1,2.5)", ParseHexError::InvalidInteger("2.5".to_string()))]
+    #[case("(2147483648,0)", ParseHexError::InvalidInteger("2147483648".to_string()))]
+    #[case("(1,2,3)", ParseHexError::NotOnPlane { q: 1, r: 2, s: 3 })]
+    #[case("{\"q\":1}", ParseHexError::MissingKey("r"))]
+    #[case("{\"q\":1,\"q\":2}", ParseHexError::DuplicateKey("q".to_string()))]
+    #[case("{\"x\":1,\"r\":2}", ParseHexError::UnknownKey("x".to_string()))]
+    #[case("{q:1,r:2}", ParseHexError::BadDelimiters)]
+    #[case("{\"q\":1,\"r\":2,\"s\":0}", ParseHexError::NotOnPlane { q: 1, r: 2, s: 0 })]
+    fn test_parse_error(#[case] input: &str, #[case] expected: ParseHexError) {
+        assert_eq!(input.parse::<Hex>(), Err(expected));
+    }
+
+    #[rstest]
+    #[case(Hex { q: 0, r: 0 })]
+    #[case(Hex { q: 1, r: -2 })]
+    #[case(Hex { q: i32::MIN, r: i32::MAX })]
+    fn test_display_roundtrip(#[case] hex: Hex) {
+        assert_eq!(hex.to_string().parse::<Hex>(), Ok(hex));
+    }
+
+    #[rstest]
+    #[case(Hex { q: 0, r: 0 }, 0)]
+    #[case(Hex { q: 1, r: 2 }, 0x0000_0001_0000_0002)]
+    #[case(Hex { q: -1, r: 0 }, 0xffff_ffff_0000_0000)]
+    #[case(Hex { q: 0, r: -1 }, 0x0000_0000_ffff_ffff)]
+    #[case(Hex { q: i32::MIN, r: i32::MAX }, 0x8000_0000_7fff_ffff)]
+    fn test_datum_bits(#[case] hex: Hex, #[case] bits: u64) {
+        assert_eq!(hex.to_datum_bits(), bits);
+        assert_eq!(Hex::from_datum_bits(bits), hex);
+    }
+
+    #[rstest]
+    #[case(Hex { q: 0, r: 0 }, [0, 0, 0, 0, 0, 0, 0, 0])]
+    #[case(Hex { q: 1, r: -2 }, [0, 0, 0, 1, 0xff, 0xff, 0xff, 0xfe])]
+    #[case(Hex { q: i32::MIN, r: i32::MAX }, [0x80, 0, 0, 0, 0x7f, 0xff, 0xff, 0xff])]
+    fn test_send_bytes(#[case] hex: Hex, #[case] bytes: [u8; 8]) {
+        assert_eq!(hex.to_send_bytes(), bytes);
+        assert_eq!(Hex::from_send_bytes(bytes), hex);
+    }
+
+    #[rstest]
+    #[case("<(1,2),3>", Hex { q: 1, r: 2 }, 3)]
+    #[case(" < [1, 2] , 0 > ", Hex { q: 1, r: 2 }, 0)]
+    #[case("<(1,2,-3),3>", Hex { q: 1, r: 2 }, 3)]
+    #[case("<{\"q\":1,\"r\":2},3>", Hex { q: 1, r: 2 }, 3)]
+    fn test_parse_range(#[case] input: &str, #[case] center: Hex, #[case] radius: i32) {
+        assert_eq!(input.parse::<HexRange>(), Ok(HexRange { center, radius }));
+    }
+
+    #[rstest]
+    #[case("(1,2),3", ParseHexRangeError::BadDelimiters)]
+    #[case("<(1,2)>", ParseHexRangeError::Center(ParseHexError::BadDelimiters))]
+    #[case("<3>", ParseHexRangeError::BadDelimiters)]
+    #[case("<(1,2,3),3>", ParseHexRangeError::Center(ParseHexError::NotOnPlane { q: 1, r: 2, s: 3 }))]
+    #[case("<(1,2),x>", ParseHexRangeError::InvalidRadius("x".to_string()))]
+    #[case("<(1,2),-1>", ParseHexRangeError::NegativeRadius(-1))]
+    fn test_parse_range_error(#[case] input: &str, #[case] expected: ParseHexRangeError) {
+        assert_eq!(input.parse::<HexRange>(), Err(expected));
+    }
+
+    #[rstest]
+    fn test_display_range() {
+        let range = HexRange {
+            center: Hex { q: 0, r: -2 },
+            radius: 4,
+        };
+        assert_eq!(range.to_string(), "<(0,-2),4>");
+        assert_eq!(range.to_string().parse::<HexRange>(), Ok(range));
+    }
+
+    #[rstest]
+    fn test_display() {
+        assert_eq!(Hex { q: 0, r: -2 }.to_string(), "(0,-2)");
+    }
+}
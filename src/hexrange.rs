@@ -0,0 +1,231 @@
+use core::ffi::CStr;
+use std::mem::size_of;
+
+use pgrx::callconv::{Arg, ArgAbi, BoxRet, FcInfo};
+use pgrx::ffi::CString;
+use pgrx::pgrx_sql_entity_graph::metadata::{
+    ArgumentError, Returns, ReturnsError, SqlMapping, SqlTranslatable,
+};
+use pgrx::prelude::*;
+use pgrx::wrappers::rust_regtypein;
+
+use crate::hex_alg::CubeCoord;
+use crate::Hex;
+
+/// Every hex within `radius` of `center`.
+///
+/// In Postgres this is a fixed-length 12-byte type that's passed by reference,
+/// laid out as this `repr(C)` struct.
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+#[repr(C)]
+pub struct HexRange {
+    pub(crate) center: Hex,
+    pub(crate) radius: i32,
+}
+
+impl HexRange {
+    pub fn contains(&self, coord: Hex) -> bool {
+        CubeCoord::from(self.center).dist(CubeCoord::from(coord)) <= self.radius
+    }
+
+    /// Copy into a newly `palloc`ed datum
+    fn to_datum(self) -> pg_sys::Datum {
+        unsafe {
+            let ptr = pg_sys::palloc(size_of::<HexRange>()) as *mut HexRange;
+            ptr.write(self);
+            pg_sys::Datum::from(ptr)
+        }
+    }
+}
+
+impl FromDatum for HexRange {
+    unsafe fn from_polymorphic_datum(
+        datum: pg_sys::Datum,
+        is_null: bool,
+        _typoid: pg_sys::Oid,
+    ) -> Option<Self> {
+        if is_null {
+            None
+        } else {
+            Some(unsafe { datum.cast_mut_ptr::<HexRange>().read_unaligned() })
+        }
+    }
+}
+
+impl IntoDatum for HexRange {
+    fn into_datum(self) -> Option<pg_sys::Datum> {
+        Some(self.to_datum())
+    }
+
+    fn type_oid() -> pg_sys::Oid {
+        rust_regtypein::<Self>()
+    }
+}
+
+unsafe impl<'fcx> ArgAbi<'fcx> for HexRange {
+    unsafe fn unbox_arg_unchecked(arg: Arg<'_, 'fcx>) -> Self {
+        let index = arg.index();
+        unsafe {
+            arg.unbox_arg_using_from_datum()
+                .unwrap_or_else(|| panic!("argument {index} must not be null"))
+        }
+    }
+}
+
+unsafe impl BoxRet for HexRange {
+    unsafe fn box_into<'fcx>(self, fcinfo: &mut FcInfo<'fcx>) -> pgrx::datum::Datum<'fcx> {
+        unsafe { fcinfo.return_raw_datum(self.to_datum()) }
+    }
+}
+
+unsafe impl SqlTranslatable for HexRange {
+    fn argument_sql() -> Result<SqlMapping, ArgumentError> {
+        Ok(SqlMapping::As(String::from("hexrange")))
+    }
+
+    fn return_sql() -> Result<Returns, ReturnsError> {
+        Ok(Returns::One(SqlMapping::As(String::from("hexrange"))))
+    }
+}
+
+#[pg_extern(immutable, strict, parallel_safe, sql = false)]
+fn hexrange_in(input: &CStr) -> HexRange {
+    let text = input.to_string_lossy();
+    match text.parse() {
+        Ok(range) => range,
+        Err(e) => ereport!(
+            ERROR,
+            PgSqlErrorCode::ERRCODE_INVALID_TEXT_REPRESENTATION,
+            format!("invalid input syntax for type hexrange: \"{}\"", text),
+            e.to_string()
+        ),
+    }
+}
+
+#[pg_extern(immutable, strict, parallel_safe, sql = false)]
+fn hexrange_out(value: HexRange) -> CString {
+    CString::new(value.to_string()).expect("hexrange text form contains no nul bytes")
+}
+
+extension_sql!(
+    r#"
+CREATE TYPE hexrange;
+
+CREATE FUNCTION hexrange_in(cstring) RETURNS hexrange
+    IMMUTABLE STRICT PARALLEL SAFE
+    LANGUAGE c AS 'MODULE_PATHNAME', 'hexrange_in_wrapper';
+
+CREATE FUNCTION hexrange_out(hexrange) RETURNS cstring
+    IMMUTABLE STRICT PARALLEL SAFE
+    LANGUAGE c AS 'MODULE_PATHNAME', 'hexrange_out_wrapper';
+
+CREATE TYPE hexrange (
+    INPUT = hexrange_in,
+    OUTPUT = hexrange_out,
+    INTERNALLENGTH = 12,
+    ALIGNMENT = int4,
+    STORAGE = plain
+);
+"#,
+    name = "hexrange_type",
+    creates = [Type(HexRange)],
+);
+
+/// The range of hexes within `radius` of `center`
+#[pg_extern(immutable, strict, parallel_safe)]
+fn hexrange(center: Hex, radius: i32) -> HexRange {
+    if radius < 0 {
+        ereport!(
+            ERROR,
+            PgSqlErrorCode::ERRCODE_INVALID_PARAMETER_VALUE,
+            format!("hexrange radius must not be negative, but is {}", radius)
+        );
+    }
+    HexRange { center, radius }
+}
+
+#[pg_extern(immutable, strict, parallel_safe)]
+fn hexrange_center(range: HexRange) -> Hex {
+    range.center
+}
+
+#[pg_extern(immutable, strict, parallel_safe)]
+fn hexrange_radius(range: HexRange) -> i32 {
+    range.radius
+}
+
+// Operators. Their restriction and join selectivity functions use the
+// statistics of hex columns, and are set in `stats`.
+
+#[pg_operator(immutable, parallel_safe)]
+#[opname(<@)]
+#[commutator(@>)]
+fn hex_within(coord: Hex, range: HexRange) -> bool {
+    range.contains(coord)
+}
+
+#[pg_operator(immutable, parallel_safe)]
+#[opname(@>)]
+#[commutator(<@)]
+fn hexrange_contains(range: HexRange, coord: Hex) -> bool {
+    range.contains(coord)
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use crate::hexrange::HexRange;
+    use crate::Hex;
+    use pgrx::prelude::*;
+
+    #[pg_test]
+    fn test_create_hexrange() {
+        let value = Spi::get_one::<HexRange>("select '<[1,2],3>'::hexrange")
+            .unwrap()
+            .unwrap();
+        let expected = HexRange {
+            center: Hex { q: 1, r: 2 },
+            radius: 3,
+        };
+        assert_eq!(value, expected);
+
+        let value = Spi::get_one::<HexRange>("select hexrange('(1,2)', 3)")
+            .unwrap()
+            .unwrap();
+        assert_eq!(value, expected);
+
+        let value = Spi::get_one::<String>("select hexrange('(1,2)', 3)::text")
+            .unwrap()
+            .unwrap();
+        assert_eq!(value, "<(1,2),3>");
+    }
+
+    #[pg_test(error = "invalid input syntax for type hexrange: \"<(1,2),-3>\"")]
+    fn test_hexrange_input_negative_radius() {
+        Spi::get_one::<HexRange>("select '<(1,2),-3>'::hexrange").unwrap();
+    }
+
+    #[pg_test(error = "hexrange radius must not be negative, but is -3")]
+    fn test_hexrange_negative_radius() {
+        Spi::get_one::<HexRange>("select hexrange('(1,2)', -3)").unwrap();
+    }
+
+    #[pg_test]
+    fn test_hex_within() {
+        let value = Spi::get_one::<i64>(
+            "select count(*) from hexes_in_range('(0,0)', 5) coord
+            where coord <@ hexrange('(1,1)', 2)",
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(value, 19);
+
+        let value = Spi::get_one::<bool>(
+            "select hexrange('(1,1)', 2) @> '(3,0)'::hex
+                and not hexrange('(1,1)', 2) @> '(4,0)'::hex",
+        )
+        .unwrap()
+        .unwrap();
+        assert!(value);
+    }
+}
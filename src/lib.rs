@@ -1,47 +1,334 @@
-use hex_alg::CubeCoord;
+use core::ffi::CStr;
+use std::cmp::Ordering;
+
+use hex_alg::{CubeCoord, TieBreak};
+use pgrx::callconv::{Arg, ArgAbi, BoxRet, FcInfo};
+use pgrx::datum::UnboxDatum;
+use pgrx::ffi::CString;
+use pgrx::pgrx_sql_entity_graph::metadata::{
+    ArgumentError, Returns, ReturnsError, SqlMapping, SqlTranslatable,
+};
 use pgrx::prelude::*;
-use serde::{Deserialize, Serialize};
+use pgrx::spi::SpiError;
+use pgrx::wrappers::rust_regtypein;
+use pgrx::Internal;
 
 ::pgrx::pg_module_magic!();
 
+mod brin;
+mod direction;
+mod facing;
+mod gist;
 mod hex_alg;
+mod hex_io;
+mod hexrange;
+mod pathfinding;
+mod shapes;
+mod spgist;
+mod stats;
+mod support;
+mod visibility;
 
-#[derive(PartialEq, Debug, Copy, Clone, PostgresType, Serialize, Deserialize)]
-//#[pgvarlena_inoutfuncs]
-/// A hex position in cubic coordinates
+/// A hex position in cubic coordinates, stored as its `q` and `r` components
+/// with `s = -q - r` implied.
+///
+/// In Postgres this is a fixed-length 8-byte type that's passed by value. See
+/// `Hex::to_datum_bits` for the layout.
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+#[repr(C)]
 struct Hex {
     q: i32,
     r: i32,
 }
 
-// TODO: Custom repr
-// impl PgVarlenaInOutFuncs for Hex {
-//     fn input(input: &core::ffi::CStr) -> PgVarlena<Self> {
-//         let mut iter = input.to_str().unwrap().split(',');
-//         let (q, r) = (iter.next(), iter.next());
-//
-//         let mut result = PgVarlena::<Self>::new();
-//         result.q =
-//             i32::from_str(q.unwrap().trim()).expect(&format!("q {:?} is not a valid i32", q));
-//         result.r =
-//             i32::from_str(r.unwrap().trim()).expect(&format!("r {:?} is not a valid i32", r));
-//
-//         result
-//     }
-//
-//     fn output(&self, buffer: &mut pgrx::StringInfo) {
-//         buffer.push_str(&format!("{},{}", self.q, self.r));
-//     }
-// }
+/// Hexes are totally ordered by `r` and then `q`, i.e. row by row
+impl Ord for Hex {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.r, self.q).cmp(&(other.r, other.q))
+    }
+}
+
+impl PartialOrd for Hex {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl FromDatum for Hex {
+    unsafe fn from_polymorphic_datum(
+        datum: pg_sys::Datum,
+        is_null: bool,
+        _typoid: pg_sys::Oid,
+    ) -> Option<Self> {
+        if is_null {
+            None
+        } else {
+            Some(Hex::from_datum_bits(datum.value() as u64))
+        }
+    }
+}
+
+impl IntoDatum for Hex {
+    fn into_datum(self) -> Option<pg_sys::Datum> {
+        Some(pg_sys::Datum::from(self.to_datum_bits()))
+    }
+
+    fn type_oid() -> pg_sys::Oid {
+        rust_regtypein::<Self>()
+    }
+}
+
+unsafe impl<'fcx> ArgAbi<'fcx> for Hex {
+    unsafe fn unbox_arg_unchecked(arg: Arg<'_, 'fcx>) -> Self {
+        let index = arg.index();
+        unsafe {
+            arg.unbox_arg_using_from_datum()
+                .unwrap_or_else(|| panic!("argument {index} must not be null"))
+        }
+    }
+}
+
+/// Lets hexes be read from arrays, such as the `hex[]` arguments of
+/// `pathfinding::hex_astar`
+unsafe impl UnboxDatum for Hex {
+    type As<'src> = Hex;
+
+    unsafe fn unbox<'src>(datum: pgrx::datum::Datum<'src>) -> Self::As<'src>
+    where
+        Self: 'src,
+    {
+        Hex::from_datum_bits(datum.sans_lifetime().value() as u64)
+    }
+}
+
+unsafe impl BoxRet for Hex {
+    unsafe fn box_into<'fcx>(self, fcinfo: &mut FcInfo<'fcx>) -> pgrx::datum::Datum<'fcx> {
+        unsafe { fcinfo.return_raw_datum(pg_sys::Datum::from(self.to_datum_bits())) }
+    }
+}
+
+unsafe impl SqlTranslatable for Hex {
+    fn argument_sql() -> Result<SqlMapping, ArgumentError> {
+        Ok(SqlMapping::As(String::from("hex")))
+    }
+
+    fn return_sql() -> Result<Returns, ReturnsError> {
+        Ok(Returns::One(SqlMapping::As(String::from("hex"))))
+    }
+}
+
+// The I/O functions have to be declared against a shell type before the type
+// itself can be, so their SQL is written by hand in the `hex_type` block, as is
+// that of `stats::hex_typanalyze`.
+
+#[pg_extern(immutable, strict, parallel_safe, sql = false)]
+fn hex_in(input: &CStr) -> Hex {
+    let text = input.to_string_lossy();
+    match text.parse() {
+        Ok(hex) => hex,
+        Err(e) => ereport!(
+            ERROR,
+            PgSqlErrorCode::ERRCODE_INVALID_TEXT_REPRESENTATION,
+            format!("invalid input syntax for type hex: \"{}\"", text),
+            e.to_string()
+        ),
+    }
+}
+
+#[pg_extern(immutable, strict, parallel_safe, sql = false)]
+fn hex_out(value: Hex) -> CString {
+    CString::new(value.to_string()).expect("hex text form contains no nul bytes")
+}
+
+/// Binary input, reading the format described in `Hex::to_send_bytes`
+#[pg_extern(immutable, strict, parallel_safe, sql = false)]
+fn hex_recv(mut internal: Internal) -> Hex {
+    let buf = unsafe { internal.get_mut::<pg_sys::StringInfoData>() }
+        .expect("hex_recv called without a message buffer");
+    let bytes = unsafe { pg_sys::pq_getmsgbytes(buf, 8) };
+    // SAFETY: pq_getmsgbytes raises an error unless 8 bytes are available
+    let bytes = unsafe { std::ptr::read_unaligned(bytes as *const [u8; 8]) };
+    Hex::from_send_bytes(bytes)
+}
+
+/// Binary output, in the format described in `Hex::to_send_bytes`
+#[pg_extern(immutable, strict, parallel_safe, sql = false)]
+fn hex_send(value: Hex) -> Vec<u8> {
+    value.to_send_bytes().to_vec()
+}
+
+extension_sql!(
+    r#"
+CREATE TYPE hex;
+
+CREATE FUNCTION hex_in(cstring) RETURNS hex
+    IMMUTABLE STRICT PARALLEL SAFE
+    LANGUAGE c AS 'MODULE_PATHNAME', 'hex_in_wrapper';
+
+CREATE FUNCTION hex_out(hex) RETURNS cstring
+    IMMUTABLE STRICT PARALLEL SAFE
+    LANGUAGE c AS 'MODULE_PATHNAME', 'hex_out_wrapper';
+
+CREATE FUNCTION hex_recv(internal) RETURNS hex
+    IMMUTABLE STRICT PARALLEL SAFE
+    LANGUAGE c AS 'MODULE_PATHNAME', 'hex_recv_wrapper';
+
+CREATE FUNCTION hex_send(hex) RETURNS bytea
+    IMMUTABLE STRICT PARALLEL SAFE
+    LANGUAGE c AS 'MODULE_PATHNAME', 'hex_send_wrapper';
+
+CREATE FUNCTION hex_typanalyze(internal) RETURNS boolean
+    IMMUTABLE STRICT PARALLEL SAFE
+    LANGUAGE c AS 'MODULE_PATHNAME', 'hex_typanalyze_wrapper';
+
+CREATE TYPE hex (
+    INPUT = hex_in,
+    OUTPUT = hex_out,
+    RECEIVE = hex_recv,
+    SEND = hex_send,
+    ANALYZE = hex_typanalyze,
+    INTERNALLENGTH = 8,
+    PASSEDBYVALUE,
+    ALIGNMENT = double,
+    STORAGE = plain
+);
+"#,
+    name = "hex_type",
+    creates = [Type(Hex)],
+);
 
 // Operators
 
-#[pg_operator]
+#[pg_operator(immutable, parallel_safe)]
 #[opname(=)]
+#[commutator(=)]
+#[negator(<>)]
+#[restrict(eqsel)]
+#[join(eqjoinsel)]
+#[hashes]
+#[merges]
 fn hex_eq(left: Hex, right: Hex) -> bool {
     left == right
 }
 
+#[pg_operator(immutable, parallel_safe)]
+#[opname(<>)]
+#[commutator(<>)]
+#[negator(=)]
+#[restrict(neqsel)]
+#[join(neqjoinsel)]
+fn hex_ne(left: Hex, right: Hex) -> bool {
+    left != right
+}
+
+#[pg_operator(immutable, parallel_safe)]
+#[opname(<)]
+#[commutator(>)]
+#[negator(>=)]
+#[restrict(scalarltsel)]
+#[join(scalarltjoinsel)]
+fn hex_lt(left: Hex, right: Hex) -> bool {
+    left < right
+}
+
+#[pg_operator(immutable, parallel_safe)]
+#[opname(<=)]
+#[commutator(>=)]
+#[negator(>)]
+#[restrict(scalarlesel)]
+#[join(scalarlejoinsel)]
+fn hex_le(left: Hex, right: Hex) -> bool {
+    left <= right
+}
+
+#[pg_operator(immutable, parallel_safe)]
+#[opname(>)]
+#[commutator(<)]
+#[negator(<=)]
+#[restrict(scalargtsel)]
+#[join(scalargtjoinsel)]
+fn hex_gt(left: Hex, right: Hex) -> bool {
+    left > right
+}
+
+#[pg_operator(immutable, parallel_safe)]
+#[opname(>=)]
+#[commutator(<=)]
+#[negator(<)]
+#[restrict(scalargesel)]
+#[join(scalargejoinsel)]
+fn hex_ge(left: Hex, right: Hex) -> bool {
+    left >= right
+}
+
+/// Btree comparison support function
+#[pg_extern(immutable, strict, parallel_safe)]
+fn hex_cmp(left: Hex, right: Hex) -> i32 {
+    match left.cmp(&right) {
+        Ordering::Less => -1,
+        Ordering::Equal => 0,
+        Ordering::Greater => 1,
+    }
+}
+
+/// Hash support function, hashing the 8-byte representation as an `int8`
+#[pg_extern(immutable, strict, parallel_safe)]
+fn hex_hash(value: Hex) -> i32 {
+    unsafe {
+        direct_function_call::<i32>(
+            pg_sys::hashint8,
+            &[(value.to_datum_bits() as i64).into_datum()],
+        )
+    }
+    .unwrap()
+}
+
+/// Seeded hash support function, used for hash partitioning
+#[pg_extern(immutable, strict, parallel_safe)]
+fn hex_hash_extended(value: Hex, seed: i64) -> i64 {
+    unsafe {
+        direct_function_call::<i64>(
+            pg_sys::hashint8extended,
+            &[
+                (value.to_datum_bits() as i64).into_datum(),
+                seed.into_datum(),
+            ],
+        )
+    }
+    .unwrap()
+}
+
+extension_sql!(
+    r#"
+CREATE OPERATOR CLASS hex_btree_ops
+    DEFAULT FOR TYPE hex USING btree AS
+        OPERATOR 1 <,
+        OPERATOR 2 <=,
+        OPERATOR 3 =,
+        OPERATOR 4 >=,
+        OPERATOR 5 >,
+        FUNCTION 1 hex_cmp(hex, hex);
+
+CREATE OPERATOR CLASS hex_hash_ops
+    DEFAULT FOR TYPE hex USING hash AS
+        OPERATOR 1 =,
+        FUNCTION 1 hex_hash(hex),
+        FUNCTION 2 hex_hash_extended(hex, bigint);
+"#,
+    name = "hex_btree_hash_opclasses",
+    requires = [
+        hex_eq,
+        hex_ne,
+        hex_lt,
+        hex_le,
+        hex_gt,
+        hex_ge,
+        hex_cmp,
+        hex_hash,
+        hex_hash_extended
+    ],
+);
+
 #[pg_operator]
 #[opname(+)]
 fn hex_add(left: Hex, right: Hex) -> Hex {
@@ -54,6 +341,103 @@ fn hex_sub(left: Hex, right: Hex) -> Hex {
     (CubeCoord::from(left) - CubeCoord::from(right)).into()
 }
 
+#[pg_operator(immutable, parallel_safe)]
+#[opname(*)]
+#[commutator(*)]
+fn hex_mul(left: Hex, right: i32) -> Hex {
+    (CubeCoord::from(left) * right).into()
+}
+
+#[pg_operator(immutable, parallel_safe)]
+#[opname(*)]
+#[commutator(*)]
+fn int4_hex_mul(left: i32, right: Hex) -> Hex {
+    (left * CubeCoord::from(right)).into()
+}
+
+/// Scale a hex down, rounding to the nearest hex
+#[pg_operator(immutable, parallel_safe)]
+#[opname(/)]
+fn hex_div(left: Hex, right: i32) -> Hex {
+    (CubeCoord::from(left) / right).into()
+}
+
+/// Wrap a hex onto the `right` by `right` map with q and r from 0 to
+/// `right - 1`. A negative `right` wraps the same way as its absolute value.
+#[pg_operator(immutable, parallel_safe)]
+#[opname(%)]
+fn hex_mod(left: Hex, right: i32) -> Hex {
+    (CubeCoord::from(left) % right).into()
+}
+
+// pgrx only generates binary operators, so unary minus is declared by hand
+#[pg_extern(immutable, strict, parallel_safe)]
+fn hex_neg(value: Hex) -> Hex {
+    (-CubeCoord::from(value)).into()
+}
+
+extension_sql!(
+    r#"
+CREATE OPERATOR - (
+    RIGHTARG = hex,
+    FUNCTION = hex_neg
+);
+"#,
+    name = "hex_neg_operator",
+    requires = [hex_neg],
+);
+
+/// Raise an error unless the arrays named `names` have the same `lengths`,
+/// as arrays of hexes and of values for each hex must
+fn same_length(lengths: [usize; 2], names: [&str; 2]) {
+    if lengths[0] != lengths[1] {
+        ereport!(
+            ERROR,
+            PgSqlErrorCode::ERRCODE_ARRAY_SUBSCRIPT_ERROR,
+            format!(
+                "{} and {} must be the same length, but have {} and {} elements",
+                names[0], names[1], lengths[0], lengths[1]
+            )
+        );
+    }
+}
+
+/// Pair each hex in `hexes` with the value at the same position in `values`,
+/// skipping null hexes. The arrays are named in the error if their lengths
+/// differ.
+fn hex_values(
+    hexes: &Array<Hex>,
+    values: &Array<f64>,
+    names: [&str; 2],
+) -> Vec<(Hex, Option<f64>)> {
+    same_length([hexes.len(), values.len()], names);
+    hexes
+        .iter()
+        .zip(values.iter())
+        .filter_map(|(hex, value)| Some((hex?, value)))
+        .collect()
+}
+
+/// The hexes and values in the first two columns of the rows returned by
+/// `query`, which reads `what` for the error if it fails
+fn query_hex_values(query: &str, what: &str) -> Vec<(Hex, Option<f64>)> {
+    // Renaming the columns lets the values be of any numeric type
+    let query = format!(
+        "select hex, value::float8 from ({}) hex_values (hex, value)",
+        query
+    );
+    Spi::connect(|client| {
+        let mut values = Vec::new();
+        for row in client.select(&query, None, None)? {
+            if let Some(hex) = row.get::<Hex>(1)? {
+                values.push((hex, row.get::<f64>(2)?));
+            }
+        }
+        Ok::<_, SpiError>(values)
+    })
+    .unwrap_or_else(|e| error!("could not read {}: {}", what, e))
+}
+
 // Functions
 #[pg_extern]
 fn neighbors(coord: Hex) -> SetOfIterator<'static, Hex> {
@@ -75,20 +459,82 @@ fn diagonals(coord: Hex) -> SetOfIterator<'static, Hex> {
     )
 }
 
-#[pg_extern]
+#[pg_operator(immutable, parallel_safe)]
+#[opname(<->)]
+#[commutator(<->)]
 fn hex_distance(coord: Hex, other: Hex) -> i32 {
     CubeCoord::from(coord).dist(CubeCoord::from(other))
 }
 
+/// The way to break ties named by `tie_break`
+fn tie_break_arg(tie_break: &str) -> TieBreak {
+    match tie_break {
+        "symmetric" => TieBreak::Symmetric,
+        "left" => TieBreak::Left,
+        "right" => TieBreak::Right,
+        _ => ereport!(
+            ERROR,
+            PgSqlErrorCode::ERRCODE_INVALID_PARAMETER_VALUE,
+            format!(
+                "tie_break must be symmetric, left or right, but is {}",
+                tie_break
+            )
+        ),
+    }
+}
+
+/// The hexes along the straight line from `coord` to `other`. Where the line
+/// passes exactly along the edge between two hexes, `tie_break` picks the one
+/// the same way in both directions, or the one to the `left` or `right`.
 #[pg_extern]
-fn linedraw(coord: Hex, other: Hex) -> SetOfIterator<'static, Hex> {
+fn linedraw(
+    coord: Hex,
+    other: Hex,
+    tie_break: default!(&str, "'symmetric'"),
+) -> SetOfIterator<'static, Hex> {
     SetOfIterator::new(
         CubeCoord::from(coord)
-            .linedraw(CubeCoord::from(other))
+            .linedraw_with(CubeCoord::from(other), tie_break_arg(tie_break))
+            .map(|cube| cube.into()),
+    )
+}
+
+/// Every hex that the line from the center of `coord` to the center of
+/// `other` passes through or touches the edge of, in order along it
+#[pg_extern(immutable, strict, parallel_safe)]
+fn linedraw_supercover(coord: Hex, other: Hex) -> SetOfIterator<'static, Hex> {
+    SetOfIterator::new(
+        hex_alg::supercover(coord.into(), other.into())
+            .into_iter()
             .map(|cube| cube.into()),
     )
 }
 
+/// Each step of the lines from `coord` to `other` that take the hex to the
+/// left and to the right where they pass along the edge between two, so that
+/// `other` can be seen past blockers when either line misses them all
+#[pg_extern(immutable, strict, parallel_safe)]
+fn linedraw_both_sides(
+    coord: Hex,
+    other: Hex,
+) -> TableIterator<
+    'static,
+    (
+        name!(step, i32),
+        name!(left_hex, Hex),
+        name!(right_hex, Hex),
+    ),
+> {
+    let (coord, other) = (CubeCoord::from(coord), CubeCoord::from(other));
+    let left = coord.linedraw_with(other, TieBreak::Left);
+    let right = coord.linedraw_with(other, TieBreak::Right);
+    TableIterator::new(
+        left.zip(right)
+            .enumerate()
+            .map(|(step, (left, right))| (step as i32, left.into(), right.into())),
+    )
+}
+
 #[pg_extern]
 fn hexes_in_range(coord: Hex, dist: i32) -> SetOfIterator<'static, Hex> {
     SetOfIterator::new(CubeCoord::from(coord).range(dist).map(|cube| cube.into()))
@@ -113,12 +559,231 @@ fn spiral_path(coord: Hex, radius: i32) -> SetOfIterator<'static, Hex> {
 mod tests {
     use crate::*;
 
+    /// The plan for a query, as text
+    pub fn explain(query: &str) -> String {
+        explain_with("costs off", query)
+    }
+
+    /// The plan for a query, as text, with other `explain` options
+    pub fn explain_with(options: &str, query: &str) -> String {
+        Spi::run(
+            "create or replace function pg_temp.explain_text(options text, query text)
+            returns text
+            language plpgsql as $$
+            declare
+                line text;
+                plan text := '';
+            begin
+                for line in execute 'explain (' || options || ') ' || query loop
+                    plan := plan || line || E'\\n';
+                end loop;
+                return plan;
+            end
+            $$",
+        )
+        .unwrap();
+        Spi::get_one::<String>(&format!(
+            "select pg_temp.explain_text('{}', '{}')",
+            options,
+            query.replace('\'', "''")
+        ))
+        .unwrap()
+        .unwrap()
+    }
+
+    /// The planner's estimate of the number of rows a query returns
+    pub fn estimated_rows(query: &str) -> i64 {
+        let plan = explain_with("costs on", query);
+        let rows = plan.split("rows=").nth(1).expect("plan has a row estimate");
+        rows.split(|c: char| !c.is_ascii_digit())
+            .next()
+            .unwrap()
+            .parse()
+            .unwrap()
+    }
+
     #[pg_test]
     fn test_create_hex() {
         let value = Spi::get_one::<Hex>("select '[1,2]'::hex").unwrap().unwrap();
         assert_eq!(value, Hex { q: 1, r: 2 });
     }
 
+    #[pg_test]
+    fn test_hex_input_forms() {
+        for input in [
+            "(1,2)",
+            "[1,2]",
+            "( 1 , 2 )",
+            "(1,2,-3)",
+            "{\"q\":1,\"r\":2}",
+        ] {
+            let value = Spi::get_one::<Hex>(&format!("select '{}'::hex", input))
+                .unwrap()
+                .unwrap();
+            assert_eq!(value, Hex { q: 1, r: 2 });
+        }
+    }
+
+    #[pg_test]
+    fn test_hex_output() {
+        let value = Spi::get_one::<String>("select '[0, -2]'::hex::text")
+            .unwrap()
+            .unwrap();
+        assert_eq!(value, "(0,-2)");
+    }
+
+    #[pg_test(error = "invalid input syntax for type hex: \"(1,2,3)\"")]
+    fn test_hex_input_off_plane() {
+        Spi::get_one::<Hex>("select '(1,2,3)'::hex").unwrap();
+    }
+
+    #[pg_test(error = "invalid input syntax for type hex: \"1,2\"")]
+    fn test_hex_input_malformed() {
+        Spi::get_one::<Hex>("select '1,2'::hex").unwrap();
+    }
+
+    #[pg_test]
+    fn test_hex_is_fixed_length_by_value() {
+        let (len, by_val) =
+            Spi::get_two::<i16, bool>("select typlen, typbyval from pg_type where typname = 'hex'")
+                .unwrap();
+        assert_eq!(len, Some(8));
+        assert_eq!(by_val, Some(true));
+
+        let size = Spi::get_one::<i32>("select pg_column_size('(1,2)'::hex)")
+            .unwrap()
+            .unwrap();
+        assert_eq!(size, 8);
+    }
+
+    #[pg_test]
+    fn test_hex_table_roundtrip() {
+        Spi::run("create table tiles (coord hex)").unwrap();
+        Spi::run("insert into tiles values ('(1,-2)'), ('(-2147483648,2147483647)'), (null)")
+            .unwrap();
+        let value = Spi::get_one::<Hex>("select coord from tiles where coord = '(1,-2)'")
+            .unwrap()
+            .unwrap();
+        assert_eq!(value, Hex { q: 1, r: -2 });
+        let value = Spi::get_one::<String>("select string_agg(coord::text, ' ') from tiles")
+            .unwrap()
+            .unwrap();
+        assert_eq!(value, "(1,-2) (-2147483648,2147483647)");
+    }
+
+    #[pg_test]
+    fn test_hex_send() {
+        let value = Spi::get_one::<Vec<u8>>("select hex_send('(1,-2)')")
+            .unwrap()
+            .unwrap();
+        assert_eq!(value, vec![0, 0, 0, 1, 0xff, 0xff, 0xff, 0xfe]);
+    }
+
+    #[pg_test]
+    fn test_hex_binary_copy_roundtrip() {
+        Spi::run("create table copy_src (coord hex)").unwrap();
+        Spi::run("create table copy_dst (coord hex)").unwrap();
+        Spi::run(
+            "insert into copy_src values ('(0,0)'), ('(1,-2)'), ('(-2147483648,2147483647)'), (null)",
+        )
+        .unwrap();
+        Spi::run(
+            "do $$
+            declare
+                path text := current_setting('data_directory') || '/pghex_copy_test.bin';
+            begin
+                execute format('copy copy_src to %L with (format binary)', path);
+                execute format('copy copy_dst from %L with (format binary)', path);
+            end
+            $$",
+        )
+        .unwrap();
+
+        let value = Spi::get_one::<String>(
+            "select string_agg(coalesce(coord::text, 'null'), ' ') from copy_dst",
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(value, "(0,0) (1,-2) (-2147483648,2147483647) null");
+    }
+
+    #[pg_test]
+    fn test_order_by_hex() {
+        let value = Spi::get_one::<String>(
+            "select string_agg(coord::text, ' ' order by coord)
+            from unnest(array['(1,0)', '(0,1)', '(-1,1)', '(0,0)', '(5,-1)']::hex[]) coord",
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(value, "(5,-1) (0,0) (1,0) (-1,1) (0,1)");
+    }
+
+    #[pg_test]
+    fn test_compare_hex() {
+        let value = Spi::get_one::<bool>(
+            "select '(1,0)'::hex < '(0,1)'::hex
+                and '(0,1)'::hex <= '(0,1)'::hex
+                and '(1,1)'::hex > '(0,1)'::hex
+                and '(1,1)'::hex >= '(1,1)'::hex
+                and '(1,1)'::hex <> '(1,2)'::hex
+                and not '(1,1)'::hex <> '(1,1)'::hex",
+        )
+        .unwrap()
+        .unwrap();
+        assert!(value);
+    }
+
+    #[pg_test]
+    fn test_hex_eq_can_hash_and_merge() {
+        let (hashes, merges) = Spi::get_two::<bool, bool>(
+            "select oprcanhash, oprcanmerge from pg_operator
+            where oprname = '=' and oprleft = 'hex'::regtype and oprright = 'hex'::regtype",
+        )
+        .unwrap();
+        assert_eq!(hashes, Some(true));
+        assert_eq!(merges, Some(true));
+    }
+
+    #[pg_test]
+    fn test_group_by_hex() {
+        let value = Spi::get_one::<i64>(
+            "select count(*) from (
+                select coord from unnest(array['(1,0)', '(0,1)', '(1,0)', '(1,0)']::hex[]) coord
+                group by coord
+            ) groups",
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(value, 2);
+    }
+
+    #[pg_test]
+    fn test_hash_join_hex() {
+        Spi::run("create table hash_join_a (coord hex)").unwrap();
+        Spi::run("create table hash_join_b (coord hex)").unwrap();
+        Spi::run("insert into hash_join_a select hexes_in_range('(0,0)', 3)").unwrap();
+        Spi::run("insert into hash_join_b select ring_path('(1,0)', 3)").unwrap();
+        Spi::run("set local enable_nestloop = off").unwrap();
+        Spi::run("set local enable_mergejoin = off").unwrap();
+        let value = Spi::get_one::<i64>(
+            "select count(*) from hash_join_a a join hash_join_b b on a.coord = b.coord",
+        )
+        .unwrap()
+        .unwrap();
+        let expected = Spi::get_one::<i64>(
+            "select count(*) from hash_join_b where hex_distance(coord, '(0,0)') <= 3",
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(value, expected);
+    }
+
+    #[pg_test(error = "duplicate key value violates unique constraint \"unique_tiles_pkey\"")]
+    fn test_hex_primary_key() {
+        Spi::run("create table unique_tiles (coord hex primary key)").unwrap();
+        Spi::run("insert into unique_tiles values ('(1,2)'), ('[1,2]')").unwrap();
+    }
+
     #[pg_test]
     fn test_add_hex() {
         let value = Spi::get_one::<Hex>("select '[1,2]'::hex + '[3,4]'::hex")
@@ -127,6 +792,73 @@ mod tests {
         assert_eq!(value, Hex { q: 4, r: 6 });
     }
 
+    #[pg_test]
+    fn test_scale_hex() {
+        let value = Spi::get_one::<String>(
+            "select concat_ws(' ', '(1,-2)'::hex * 3, -2 * '(1,-2)'::hex, -'(1,-2)'::hex)",
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(value, "(3,-6) (-2,4) (-1,2)");
+    }
+
+    #[pg_test]
+    fn test_divide_hex() {
+        let value = Spi::get_one::<String>(
+            "select concat_ws(' ', '(4,-6)'::hex / 2, '(5,-3)'::hex / 3, '(1,2)'::hex / 100)",
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(value, "(2,-3) (2,-1) (0,0)");
+    }
+
+    #[pg_test]
+    fn test_wrap_hex() {
+        let value = Spi::get_one::<String>(
+            "select concat_ws(' ', '(6,-1)'::hex % 5, '(-5,10)'::hex % 5, '(1,2)'::hex % 5)",
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(value, "(1,4) (0,0) (1,2)");
+    }
+
+    #[pg_test]
+    fn test_wrap_hex_negative() {
+        let value =
+            Spi::get_one::<String>("select concat_ws(' ', '(6,-1)'::hex % -5, '(-4,2)'::hex % -3)")
+                .unwrap()
+                .unwrap();
+        assert_eq!(value, "(1,4) (2,2)");
+    }
+
+    #[pg_test]
+    fn test_wrap_hex_overflow() {
+        let value = Spi::get_one::<Hex>("select '(1,-2147483648)'::hex % -1")
+            .unwrap()
+            .unwrap();
+        assert_eq!(value, Hex { q: 0, r: 0 });
+    }
+
+    #[pg_test(error = "hex out of range")]
+    fn test_add_hex_overflow() {
+        Spi::get_one::<Hex>("select '(2147483647,0)'::hex + '(1,0)'::hex").unwrap();
+    }
+
+    #[pg_test(error = "hex out of range")]
+    fn test_scale_hex_overflow() {
+        Spi::get_one::<Hex>("select '(1073741824,0)'::hex * 2").unwrap();
+    }
+
+    #[pg_test(error = "hex out of range")]
+    fn test_negate_hex_overflow() {
+        Spi::get_one::<Hex>("select -'(-2147483648,0)'::hex").unwrap();
+    }
+
+    #[pg_test(error = "division by zero")]
+    fn test_divide_hex_by_zero() {
+        Spi::get_one::<Hex>("select '(1,2)'::hex / 0").unwrap();
+    }
+
     #[pg_test]
     /// N.B. unfortunately at the moment I can only work out how to get the first result...
     fn test_neighbors() {
@@ -162,6 +894,55 @@ mod tests {
         assert_eq!(result, Hex { q: -3, r: 0 })
     }
 
+    #[pg_test]
+    fn test_linedraw_tie_break() {
+        let line = |query: &str| {
+            Spi::get_one::<String>(&format!(
+                "select string_agg(hex::text, ' ') from {} hex",
+                query
+            ))
+            .unwrap()
+            .unwrap()
+        };
+        assert_eq!(line("linedraw('(0,0)', '(1,1)')"), "(0,0) (0,1) (1,1)");
+        assert_eq!(
+            line("linedraw('(0,0)', '(1,1)', 'left')"),
+            "(0,0) (1,0) (1,1)"
+        );
+        assert_eq!(
+            line("linedraw('(1,1)', '(0,0)', 'left')"),
+            "(1,1) (0,1) (0,0)"
+        );
+        assert_eq!(line("linedraw('(5,5)', '(5,5)')"), "(5,5)");
+    }
+
+    #[pg_test(error = "tie_break must be symmetric, left or right, but is up")]
+    fn test_linedraw_bad_tie_break() {
+        Spi::run("select linedraw('(0,0)', '(1,1)', 'up')").unwrap();
+    }
+
+    #[pg_test]
+    fn test_linedraw_supercover() {
+        let value = Spi::get_one::<String>(
+            "select string_agg(hex::text, ' ') from linedraw_supercover('(0,0)', '(2,-1)') hex",
+        )
+        .unwrap();
+        assert_eq!(value, Some("(0,0) (1,-1) (1,0) (2,-1)".into()));
+    }
+
+    #[pg_test]
+    fn test_linedraw_both_sides() {
+        let value = Spi::get_one::<String>(
+            "select string_agg(format('%s:%s:%s', step, left_hex, right_hex), ' ')
+            from linedraw_both_sides('(0,0)', '(2,-1)')",
+        )
+        .unwrap();
+        assert_eq!(
+            value,
+            Some("0:(0,0):(0,0) 1:(1,-1):(1,0) 2:(2,-1):(2,-1)".into())
+        );
+    }
+
     #[pg_test]
     /// N.B. unfortunately at the moment I can only work out how to get the first result...
     fn test_hexes_in_range() {
@@ -0,0 +1,644 @@
+//! Pathfinding and movement ranges over terrain that blocks some hexes and
+//! makes others costlier to enter.
+//!
+//! Each function takes its terrain either as arrays, or as a query returning
+//! each hex with a cost of its own and that cost, where a null or infinite
+//! cost blocks the hex. Hexes without a cost of their own cost 1 to enter.
+
+use pgrx::prelude::*;
+
+use crate::direction::HexDir;
+use crate::facing::HexFacing;
+use crate::hex_alg::{astar, astar_facing, reachable, Maneuvering, Terrain};
+use crate::{hex_values, query_hex_values, Hex};
+
+/// Give `hex` its own movement cost, blocking it if that's null
+fn add_cost(terrain: &mut Terrain, hex: Hex, cost: Option<f64>) {
+    let Some(cost) = cost else {
+        terrain.block(hex.into());
+        return;
+    };
+    if cost.is_nan() || cost < 0.0 {
+        ereport!(
+            ERROR,
+            PgSqlErrorCode::ERRCODE_INVALID_PARAMETER_VALUE,
+            format!(
+                "movement cost of {} must not be negative, but is {}",
+                hex, cost
+            )
+        );
+    }
+    terrain.set_cost(hex.into(), cost);
+}
+
+/// The terrain of `blocked` hexes, and each of `cost_hexes` costing the
+/// corresponding element of `costs` to enter
+fn array_terrain(blocked: Array<Hex>, cost_hexes: Array<Hex>, costs: Array<f64>) -> Terrain {
+    let mut terrain = Terrain::default();
+    for (hex, cost) in hex_values(&cost_hexes, &costs, ["cost_hexes", "costs"]) {
+        add_cost(&mut terrain, hex, cost);
+    }
+    for hex in blocked.iter().flatten() {
+        terrain.block(hex.into());
+    }
+    terrain
+}
+
+/// The terrain returned by `query`, from its first two columns
+fn query_terrain(query: &str) -> Terrain {
+    let mut terrain = Terrain::default();
+    for (hex, cost) in query_hex_values(query, "terrain") {
+        add_cost(&mut terrain, hex, cost);
+    }
+    terrain
+}
+
+fn max_expansions_arg(max_expansions: i32) -> usize {
+    if max_expansions < 0 {
+        ereport!(
+            ERROR,
+            PgSqlErrorCode::ERRCODE_INVALID_PARAMETER_VALUE,
+            format!(
+                "max_expansions must not be negative, but is {}",
+                max_expansions
+            )
+        );
+    }
+    max_expansions as usize
+}
+
+/// Rows of each step along the cheapest path, starting from step 0 at `start`
+fn path_rows(
+    start: Hex,
+    goal: Hex,
+    terrain: &Terrain,
+    max_expansions: i32,
+) -> Vec<(i32, Hex, f64)> {
+    let max_expansions = max_expansions_arg(max_expansions);
+    astar(start.into(), goal.into(), terrain, max_expansions)
+        .unwrap_or_default()
+        .into_iter()
+        .enumerate()
+        .map(|(step, (coord, cost))| (step as i32, coord.into(), cost))
+        .collect()
+}
+
+/// The cheapest path from `start` to `goal`, avoiding `blocked` hexes and
+/// paying `costs` to enter the corresponding `cost_hexes`. No rows if there's
+/// no path, or if finding one takes expanding more than `max_expansions`
+/// hexes.
+#[pg_extern(immutable, strict, parallel_safe)]
+fn hex_astar(
+    start: Hex,
+    goal: Hex,
+    blocked: default!(Array<Hex>, "'{}'"),
+    cost_hexes: default!(Array<Hex>, "'{}'"),
+    costs: default!(Array<f64>, "'{}'"),
+    max_expansions: default!(i32, 100000),
+) -> TableIterator<
+    'static,
+    (
+        name!(step, i32),
+        name!(hex, Hex),
+        name!(cumulative_cost, f64),
+    ),
+> {
+    let terrain = array_terrain(blocked, cost_hexes, costs);
+    TableIterator::new(path_rows(start, goal, &terrain, max_expansions))
+}
+
+/// `hex_astar` over the terrain returned by `terrain_query`
+#[pg_extern(stable, strict)]
+fn hex_astar_query(
+    start: Hex,
+    goal: Hex,
+    terrain_query: &str,
+    max_expansions: default!(i32, 100000),
+) -> TableIterator<
+    'static,
+    (
+        name!(step, i32),
+        name!(hex, Hex),
+        name!(cumulative_cost, f64),
+    ),
+> {
+    let terrain = query_terrain(terrain_query);
+    TableIterator::new(path_rows(start, goal, &terrain, max_expansions))
+}
+
+/// The costs of maneuvering, each of which must not be negative, and can be
+/// infinite to rule that maneuver out, and the turn radius, which must not be
+/// negative either
+fn maneuvering_args(
+    forward_cost: f64,
+    reverse_cost: f64,
+    turn_cost: f64,
+    turn_radius: i32,
+) -> Maneuvering {
+    for (name, cost) in [
+        ("forward_cost", forward_cost),
+        ("reverse_cost", reverse_cost),
+        ("turn_cost", turn_cost),
+    ] {
+        if cost.is_nan() || cost < 0.0 {
+            ereport!(
+                ERROR,
+                PgSqlErrorCode::ERRCODE_INVALID_PARAMETER_VALUE,
+                format!("{} must not be negative, but is {}", name, cost)
+            );
+        }
+    }
+    if turn_radius < 0 {
+        ereport!(
+            ERROR,
+            PgSqlErrorCode::ERRCODE_INVALID_PARAMETER_VALUE,
+            format!("turn_radius must not be negative, but is {}", turn_radius)
+        );
+    }
+    Maneuvering {
+        forward: forward_cost,
+        reverse: reverse_cost,
+        turn: turn_cost,
+        turn_radius: turn_radius as u32,
+    }
+}
+
+/// Rows of each step along the cheapest path for the unit `start`, starting
+/// from step 0 where it stands
+fn facing_path_rows(
+    start: HexFacing,
+    goal: Hex,
+    terrain: &Terrain,
+    maneuvering: &Maneuvering,
+    max_expansions: i32,
+) -> Vec<(i32, Hex, HexDir, f64)> {
+    let max_expansions = max_expansions_arg(max_expansions);
+    astar_facing(
+        start.position.into(),
+        start.facing.index() / 2,
+        goal.into(),
+        terrain,
+        maneuvering,
+        max_expansions,
+    )
+    .unwrap_or_default()
+    .into_iter()
+    .enumerate()
+    .map(|(step, (coord, facing, cost))| {
+        (
+            step as i32,
+            coord.into(),
+            HexDir::from_index(facing * 2),
+            cost,
+        )
+    })
+    .collect()
+}
+
+/// The cheapest path for the unit `start` to reach `goal` facing any way,
+/// with the way it faces at each step, over terrain as for `hex_astar`. The
+/// unit can only move forward into the hex it faces, for `forward_cost`
+/// times the cost of entering it, or in reverse into the hex behind it, for
+/// `reverse_cost` times that, and turns in place for `turn_cost` a sixth of a
+/// turn, each sixth a step of its own. An infinite cost rules that maneuver
+/// out. After each turn, the unit must move `turn_radius` hexes before it
+/// can turn again, though it can turn straight away at `start`. No rows if
+/// there's no path, or if finding one takes expanding more than
+/// `max_expansions` hexes and facings.
+#[pg_extern(immutable, strict, parallel_safe, requires = ["hexfacing_type"])]
+#[allow(clippy::too_many_arguments)]
+fn hex_astar_facing(
+    start: pgrx::composite_type!("hexfacing"),
+    goal: Hex,
+    blocked: default!(Array<Hex>, "'{}'"),
+    cost_hexes: default!(Array<Hex>, "'{}'"),
+    costs: default!(Array<f64>, "'{}'"),
+    forward_cost: default!(f64, 1.0),
+    reverse_cost: default!(f64, 1.0),
+    turn_cost: default!(f64, 1.0),
+    turn_radius: default!(i32, 0),
+    max_expansions: default!(i32, 100000),
+) -> TableIterator<
+    'static,
+    (
+        name!(step, i32),
+        name!(hex, Hex),
+        name!(facing, HexDir),
+        name!(cumulative_cost, f64),
+    ),
+> {
+    let start = HexFacing::from_tuple(start);
+    let maneuvering = maneuvering_args(forward_cost, reverse_cost, turn_cost, turn_radius);
+    let terrain = array_terrain(blocked, cost_hexes, costs);
+    TableIterator::new(facing_path_rows(
+        start,
+        goal,
+        &terrain,
+        &maneuvering,
+        max_expansions,
+    ))
+}
+
+/// `hex_astar_facing` over the terrain returned by `terrain_query`
+#[pg_extern(stable, strict, requires = ["hexfacing_type"])]
+#[allow(clippy::too_many_arguments)]
+fn hex_astar_facing_query(
+    start: pgrx::composite_type!("hexfacing"),
+    goal: Hex,
+    terrain_query: &str,
+    forward_cost: default!(f64, 1.0),
+    reverse_cost: default!(f64, 1.0),
+    turn_cost: default!(f64, 1.0),
+    turn_radius: default!(i32, 0),
+    max_expansions: default!(i32, 100000),
+) -> TableIterator<
+    'static,
+    (
+        name!(step, i32),
+        name!(hex, Hex),
+        name!(facing, HexDir),
+        name!(cumulative_cost, f64),
+    ),
+> {
+    let start = HexFacing::from_tuple(start);
+    let maneuvering = maneuvering_args(forward_cost, reverse_cost, turn_cost, turn_radius);
+    let terrain = query_terrain(terrain_query);
+    TableIterator::new(facing_path_rows(
+        start,
+        goal,
+        &terrain,
+        &maneuvering,
+        max_expansions,
+    ))
+}
+
+fn budget_arg(budget: i32) -> f64 {
+    if budget < 0 {
+        ereport!(
+            ERROR,
+            PgSqlErrorCode::ERRCODE_INVALID_PARAMETER_VALUE,
+            format!("budget must not be negative, but is {}", budget)
+        );
+    }
+    budget as f64
+}
+
+/// Rows of each hex reachable for at most `budget`, in order of cost
+fn reachable_rows(
+    origin: Hex,
+    budget: i32,
+    terrain: &Terrain,
+    max_expansions: i32,
+) -> Vec<(Hex, f64, Option<Hex>)> {
+    let max_expansions = max_expansions_arg(max_expansions);
+    reachable(origin.into(), budget_arg(budget), terrain, max_expansions)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(coord, cost, previous)| (coord.into(), cost, previous.map(Hex::from)))
+        .collect()
+}
+
+/// Every hex that can be reached from `origin` for at most `budget`, with the
+/// cost of the cheapest path to it and the hex before it on that path, which
+/// is null for `origin` itself. The terrain is as for `hex_astar`. No rows if
+/// finding them takes expanding more than `max_expansions` hexes.
+#[pg_extern(immutable, strict, parallel_safe)]
+fn hexes_reachable(
+    origin: Hex,
+    budget: i32,
+    blocked: default!(Array<Hex>, "'{}'"),
+    cost_hexes: default!(Array<Hex>, "'{}'"),
+    costs: default!(Array<f64>, "'{}'"),
+    max_expansions: default!(i32, 100000),
+) -> TableIterator<
+    'static,
+    (
+        name!(hex, Hex),
+        name!(cost, f64),
+        name!(previous, Option<Hex>),
+    ),
+> {
+    let terrain = array_terrain(blocked, cost_hexes, costs);
+    TableIterator::new(reachable_rows(origin, budget, &terrain, max_expansions))
+}
+
+/// `hexes_reachable` over the terrain returned by `terrain_query`
+#[pg_extern(stable, strict)]
+fn hexes_reachable_query(
+    origin: Hex,
+    budget: i32,
+    terrain_query: &str,
+    max_expansions: default!(i32, 100000),
+) -> TableIterator<
+    'static,
+    (
+        name!(hex, Hex),
+        name!(cost, f64),
+        name!(previous, Option<Hex>),
+    ),
+> {
+    let terrain = query_terrain(terrain_query);
+    TableIterator::new(reachable_rows(origin, budget, &terrain, max_expansions))
+}
+
+/// The hexes that can be reached from `origin` within each of the ascending
+/// `thresholds`, as the 1-based `band` of the first threshold each is within
+/// and the cost of reaching it. No rows if finding them takes expanding more
+/// than `max_expansions` hexes.
+#[pg_extern(immutable, strict, parallel_safe)]
+fn hexes_reachable_bands(
+    origin: Hex,
+    thresholds: Array<i32>,
+    blocked: default!(Array<Hex>, "'{}'"),
+    cost_hexes: default!(Array<Hex>, "'{}'"),
+    costs: default!(Array<f64>, "'{}'"),
+    max_expansions: default!(i32, 100000),
+) -> TableIterator<'static, (name!(hex, Hex), name!(band, i32), name!(cost, f64))> {
+    let thresholds = thresholds
+        .iter()
+        .map(|threshold| {
+            threshold.unwrap_or_else(|| {
+                ereport!(
+                    ERROR,
+                    PgSqlErrorCode::ERRCODE_NULL_VALUE_NOT_ALLOWED,
+                    "thresholds must not contain nulls"
+                )
+            })
+        })
+        .collect::<Vec<_>>();
+    if !thresholds.windows(2).all(|pair| pair[0] < pair[1]) {
+        ereport!(
+            ERROR,
+            PgSqlErrorCode::ERRCODE_INVALID_PARAMETER_VALUE,
+            "thresholds must be in ascending order"
+        );
+    }
+    let Some(&budget) = thresholds.last() else {
+        return TableIterator::empty();
+    };
+
+    let terrain = array_terrain(blocked, cost_hexes, costs);
+    let rows = reachable_rows(origin, budget, &terrain, max_expansions)
+        .into_iter()
+        .map(move |(hex, cost, _)| {
+            let band = thresholds.partition_point(|&threshold| (threshold as f64) < cost);
+            (hex, band as i32 + 1, cost)
+        });
+    TableIterator::new(rows)
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use pgrx::prelude::*;
+
+    fn path(query: &str) -> String {
+        Spi::get_one::<String>(&format!(
+            "select string_agg(hex::text, ' ' order by step) from {}",
+            query
+        ))
+        .unwrap()
+        .unwrap_or_default()
+    }
+
+    #[pg_test]
+    fn test_astar_open() {
+        let (steps, cost) = Spi::get_two::<i64, f64>(
+            "select count(*), max(cumulative_cost) from hex_astar('(0,0)', '(3,-1)')",
+        )
+        .unwrap();
+        assert_eq!(steps, Some(4));
+        assert_eq!(cost, Some(3.0));
+    }
+
+    #[pg_test]
+    fn test_astar_blocked() {
+        let value = path("hex_astar('(0,0)', '(2,0)', array['(1,0)', '(1,-1)']::hex[])");
+        assert_eq!(value, "(0,0) (0,1) (1,1) (2,0)");
+    }
+
+    #[pg_test]
+    fn test_astar_costs() {
+        let value = path(
+            "hex_astar('(0,0)', '(2,0)',
+                cost_hexes => array['(1,0)', '(1,-1)', '(2,-1)']::hex[],
+                costs => array[5, 0.25, 0.25])",
+        );
+        assert_eq!(value, "(0,0) (1,-1) (2,-1) (2,0)");
+
+        let cost = Spi::get_one::<f64>(
+            "select cumulative_cost from hex_astar('(0,0)', '(2,0)',
+                cost_hexes => array['(1,0)', '(1,-1)', '(2,-1)']::hex[],
+                costs => array[5, 0.25, 0.25])
+            where step = 3",
+        )
+        .unwrap();
+        assert_eq!(cost, Some(1.5));
+    }
+
+    #[pg_test]
+    fn test_astar_walled_off() {
+        let value = Spi::get_one::<i64>(
+            "select count(*) from hex_astar('(0,0)', '(5,0)',
+                array(select ring_path('(5,0)', 1)), max_expansions => 1000)",
+        )
+        .unwrap();
+        assert_eq!(value, Some(0));
+    }
+
+    #[pg_test]
+    fn test_astar_query() {
+        Spi::run("create table terrain (position hex, cost int)").unwrap();
+        Spi::run("insert into terrain values ('(1,0)', null), ('(1,-1)', 2), ('(0,1)', 3)")
+            .unwrap();
+        let value = path("hex_astar_query('(0,0)', '(2,0)', 'select position, cost from terrain')");
+        assert_eq!(value, "(0,0) (1,-1) (2,-1) (2,0)");
+    }
+
+    #[pg_test(error = "cost_hexes and costs must be the same length, but have 1 and 2 elements")]
+    fn test_astar_mismatched_costs() {
+        Spi::run(
+            "select * from hex_astar('(0,0)', '(2,0)', '{}', array['(1,0)']::hex[], array[1, 2])",
+        )
+        .unwrap();
+    }
+
+    #[pg_test(error = "movement cost of (1,0) must not be negative, but is -1")]
+    fn test_astar_negative_cost() {
+        Spi::run(
+            "select * from hex_astar('(0,0)', '(2,0)', '{}', array['(1,0)']::hex[], array[-1])",
+        )
+        .unwrap();
+    }
+
+    fn facing_path(query: &str) -> String {
+        Spi::get_one::<String>(&format!(
+            "select string_agg(format('%s:%s', hex, facing), ' ' order by step) from {}",
+            query
+        ))
+        .unwrap()
+        .unwrap_or_default()
+    }
+
+    #[pg_test]
+    fn test_astar_facing() {
+        let value = facing_path("hex_astar_facing(hexfacing('(0,0)', 'E'), '(1,-1)')");
+        assert_eq!(value, "(0,0):E (0,0):NE (1,-1):NE");
+
+        let cost = Spi::get_one::<f64>(
+            "select max(cumulative_cost) from hex_astar_facing(hexfacing('(0,0)', 'E'), '(-2,0)',
+                reverse_cost => 1.5, turn_cost => 2)",
+        )
+        .unwrap();
+        assert_eq!(cost, Some(3.0));
+    }
+
+    #[pg_test]
+    fn test_astar_facing_no_reverse() {
+        let value = facing_path(
+            "hex_astar_facing(hexfacing('(0,0)', 'E'), '(-1,1)',
+                reverse_cost => 'infinity', turn_cost => 0.5)",
+        );
+        assert_eq!(value, "(0,0):E (0,0):SE (0,0):SW (-1,1):SW");
+    }
+
+    #[pg_test]
+    fn test_astar_facing_terrain() {
+        // The swamp ahead costs less to wade through than going round it
+        let value = facing_path(
+            "hex_astar_facing(hexfacing('(0,0)', 'E'), '(2,0)',
+                cost_hexes => array['(1,0)']::hex[], costs => array[4],
+                reverse_cost => 'infinity')",
+        );
+        assert_eq!(value, "(0,0):E (1,0):E (2,0):E");
+
+        let count = Spi::get_one::<i64>(
+            "select count(*) from hex_astar_facing(hexfacing('(0,0)', 'E'), '(2,0)',
+                array['(2,0)']::hex[])",
+        )
+        .unwrap();
+        assert_eq!(count, Some(0));
+    }
+
+    #[pg_test]
+    fn test_astar_facing_query() {
+        Spi::run("create table terrain (position hex, cost int)").unwrap();
+        Spi::run("insert into terrain values ('(1,0)', null), ('(0,1)', null)").unwrap();
+        let value = facing_path(
+            "hex_astar_facing_query(hexfacing('(0,0)', 'E'), '(2,0)',
+                'select position, cost from terrain',
+                reverse_cost => 'infinity', turn_cost => 0.25)",
+        );
+        assert_eq!(
+            value,
+            "(0,0):E (0,0):NE (1,-1):NE (1,-1):E (2,-1):E (2,-1):SE (2,0):SE"
+        );
+    }
+
+    #[pg_test]
+    fn test_astar_facing_turn_radius() {
+        let value = facing_path(
+            "hex_astar_facing(hexfacing('(0,0)', 'E'), '(0,-3)',
+                reverse_cost => 'infinity', turn_radius => 1)",
+        );
+        assert_eq!(
+            value,
+            "(0,0):E (0,0):NE (1,-1):NE (1,-1):NW (1,-2):NW (1,-3):NW (1,-3):W (0,-3):W"
+        );
+    }
+
+    #[pg_test(error = "turn_radius must not be negative, but is -2")]
+    fn test_astar_facing_negative_turn_radius() {
+        Spi::run(
+            "select * from hex_astar_facing(hexfacing('(0,0)', 'E'), '(2,0)', turn_radius => -2)",
+        )
+        .unwrap();
+    }
+
+    #[pg_test(error = "turn_cost must not be negative, but is -1")]
+    fn test_astar_facing_negative_turn() {
+        Spi::run(
+            "select * from hex_astar_facing(hexfacing('(0,0)', 'E'), '(2,0)', turn_cost => -1)",
+        )
+        .unwrap();
+    }
+
+    #[pg_test]
+    fn test_reachable_open() {
+        let (count, max_cost) =
+            Spi::get_two::<i64, f64>("select count(*), max(cost) from hexes_reachable('(1,1)', 3)")
+                .unwrap();
+        assert_eq!(count, Some(37));
+        assert_eq!(max_cost, Some(3.0));
+
+        let value = Spi::get_one::<bool>(
+            "select bool_and(
+                case when previous is null then hex = '(1,1)'
+                else hex_distance(hex, previous) = 1 end
+            ) from hexes_reachable('(1,1)', 3)",
+        )
+        .unwrap();
+        assert_eq!(value, Some(true));
+    }
+
+    #[pg_test]
+    fn test_reachable_max_expansions() {
+        let value = Spi::get_two::<i64, i64>(
+            "select
+                (select count(*) from hexes_reachable('(0,0)', 3, max_expansions => 36)),
+                (select count(*) from hexes_reachable('(0,0)', 3, max_expansions => 37))",
+        )
+        .unwrap();
+        assert_eq!(value, (Some(0), Some(37)));
+    }
+
+    #[pg_test]
+    fn test_reachable_terrain() {
+        // Walled in on all sides but (1,0), whose neighbors are swamp
+        let value = Spi::get_one::<String>(
+            "select string_agg(format('%s:%s', hex, cost), ' ' order by cost, hex)
+            from hexes_reachable('(0,0)', 3,
+                array(select ring_path('(0,0)', 1) except select '(1,0)'),
+                array['(2,0)', '(2,-1)', '(1,1)']::hex[],
+                array[2, 2, 2])",
+        )
+        .unwrap();
+        assert_eq!(
+            value,
+            Some("(0,0):0 (1,0):1 (2,-1):3 (2,0):3 (1,1):3".into())
+        );
+    }
+
+    #[pg_test]
+    fn test_reachable_query() {
+        Spi::run("create table terrain (position hex, cost float8)").unwrap();
+        Spi::run(
+            "insert into terrain select hex, null from ring_path('(0,0)', 1) hex where hex <> '(1,0)'",
+        )
+        .unwrap();
+        let value = Spi::get_one::<i64>(
+            "select count(*) from hexes_reachable_query('(0,0)', 2,
+                'select position, cost from terrain')",
+        )
+        .unwrap();
+        // The origin, the gap, and the gap's three neighbors outside the wall
+        assert_eq!(value, Some(5));
+    }
+
+    #[pg_test]
+    fn test_reachable_bands() {
+        let value = Spi::get_one::<String>(
+            "select string_agg(format('%s:%s', band, n), ' ' order by band) from (
+                select band, count(*) n from hexes_reachable_bands('(0,0)', array[1, 2, 4])
+                group by band
+            ) bands",
+        )
+        .unwrap();
+        assert_eq!(value, Some("1:7 2:12 3:42".into()));
+    }
+
+    #[pg_test(error = "thresholds must be in ascending order")]
+    fn test_reachable_bands_unordered() {
+        Spi::run("select * from hexes_reachable_bands('(0,0)', array[2, 1])").unwrap();
+    }
+}
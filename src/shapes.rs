@@ -0,0 +1,250 @@
+//! Areas of hexes shaped for breath weapons, firing arcs, sensor sweeps,
+//! minimum ranges and the like.
+
+use pgrx::prelude::*;
+
+use crate::hex_alg::{self, CubeCoord};
+use crate::{same_length, Hex};
+
+/// Raise an error unless `value` of the argument `name` is finite
+fn finite_arg(name: &str, value: f64) -> f64 {
+    if !value.is_finite() {
+        ereport!(
+            ERROR,
+            PgSqlErrorCode::ERRCODE_INVALID_PARAMETER_VALUE,
+            format!("{} must be finite, but is {}", name, value)
+        );
+    }
+    value
+}
+
+fn cone(origin: Hex, direction: f64, half_angle: f64, radius: i32) -> Vec<Hex> {
+    hex_alg::cone(
+        origin.into(),
+        finite_arg("direction", direction),
+        finite_arg("half_angle", half_angle),
+        radius,
+    )
+    .into_iter()
+    .map(Hex::from)
+    .collect()
+}
+
+/// The wedge facing the neighbor numbered `direction`, counting round again
+/// past 5 and back from 0
+fn wedge(origin: Hex, direction: i32, radius: i32) -> Vec<Hex> {
+    hex_alg::wedge(origin.into(), direction.rem_euclid(6) as usize, radius)
+        .into_iter()
+        .map(Hex::from)
+        .collect()
+}
+
+/// Every hex within `radius` of `origin` whose center is within `half_angle`
+/// degrees either side of `direction`, in degrees counterclockwise from the
+/// direction of `(1,0)` as for `hex_raycast`. Hexes exactly on the edges of
+/// the cone are in it, and so is `origin`.
+#[pg_extern(immutable, strict, parallel_safe)]
+fn hexes_in_cone(
+    origin: Hex,
+    direction: f64,
+    half_angle: f64,
+    radius: i32,
+) -> SetOfIterator<'static, Hex> {
+    SetOfIterator::new(cone(origin, direction, half_angle, radius))
+}
+
+/// `hexes_in_cone` as an array
+#[pg_extern(immutable, strict, parallel_safe)]
+fn hexes_in_cone_array(origin: Hex, direction: f64, half_angle: f64, radius: i32) -> Vec<Hex> {
+    cone(origin, direction, half_angle, radius)
+}
+
+/// Every hex within `radius` of `origin` in the 60° wedge facing its
+/// neighbor in `direction`, where the neighbors are numbered from 0 in the
+/// order `neighbors` returns them. The hexes along the diagonals at the edges
+/// are in the wedges on both sides of them.
+#[pg_extern(immutable, strict, parallel_safe)]
+fn hexes_in_wedge(origin: Hex, direction: i32, radius: i32) -> SetOfIterator<'static, Hex> {
+    SetOfIterator::new(wedge(origin, direction, radius))
+}
+
+/// `hexes_in_wedge` as an array
+#[pg_extern(immutable, strict, parallel_safe)]
+fn hexes_in_wedge_array(origin: Hex, direction: i32, radius: i32) -> Vec<Hex> {
+    wedge(origin, direction, radius)
+}
+
+/// Every hex from `min_r` to `max_r` away from `center`, in the same order as
+/// `hexes_in_range`
+#[pg_extern(immutable, strict, parallel_safe)]
+fn hexes_in_annulus(center: Hex, min_r: i32, max_r: i32) -> SetOfIterator<'static, Hex> {
+    SetOfIterator::new(CubeCoord::from(center).annulus(min_r, max_r).map(Hex::from))
+}
+
+/// Each of `centers` with the radius at the same position in `radii`,
+/// skipping null centers and radii
+fn disks(centers: Array<Hex>, radii: Array<i32>) -> Vec<(CubeCoord, i32)> {
+    same_length([centers.len(), radii.len()], ["centers", "radii"]);
+    centers
+        .iter()
+        .zip(radii.iter())
+        .filter_map(|(center, radius)| Some((center?.into(), radius?)))
+        .collect()
+}
+
+/// Every hex within the radius at the same position in `radii` of any of
+/// `centers`, once each, in the same order as `hexes_in_range`. Null centers
+/// and radii are skipped.
+#[pg_extern(immutable, strict, parallel_safe)]
+fn hexes_in_range_union(centers: Array<Hex>, radii: Array<i32>) -> SetOfIterator<'static, Hex> {
+    let disks = disks(centers, radii);
+    SetOfIterator::new(hex_alg::range_union(&disks).into_iter().map(Hex::from))
+}
+
+/// Every hex within the radius at the same position in `radii` of all of
+/// `centers`, in the same order as `hexes_in_range`, found directly rather
+/// than by going through each range. Null centers and radii are skipped, and
+/// there are no hexes if there are no centers.
+#[pg_extern(immutable, strict, parallel_safe)]
+fn hexes_in_range_intersection(
+    centers: Array<Hex>,
+    radii: Array<i32>,
+) -> SetOfIterator<'static, Hex> {
+    let disks = disks(centers, radii);
+    SetOfIterator::new(hex_alg::range_intersection(&disks).map(Hex::from))
+}
+
+/// Whether `hexes_in_range_intersection` has any hexes, found without going
+/// through them
+#[pg_extern(immutable, strict, parallel_safe)]
+fn ranges_intersect(centers: Array<Hex>, radii: Array<i32>) -> bool {
+    hex_alg::ranges_intersect(&disks(centers, radii))
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use crate::*;
+
+    fn hexes(query: &str) -> String {
+        Spi::get_one::<String>(&format!(
+            "select string_agg(hex::text, ' ') from {} hex",
+            query
+        ))
+        .unwrap()
+        .unwrap_or_default()
+    }
+
+    #[pg_test]
+    fn test_hexes_in_cone() {
+        assert_eq!(
+            hexes("hexes_in_cone('(0,0)', 0, 30, 2)"),
+            "(0,0) (1,0) (1,1) (2,-1) (2,0)"
+        );
+        assert_eq!(hexes("hexes_in_cone('(0,0)', 0, -1, 2)"), "");
+        let (count, array) = Spi::get_two::<i64, i64>(
+            "select count(*), cardinality(hexes_in_cone_array('(3,-1)', 135, 70, 4))::bigint
+            from hexes_in_cone('(3,-1)', 135, 70, 4)",
+        )
+        .unwrap();
+        assert_eq!(count, array);
+    }
+
+    #[pg_test]
+    fn test_hexes_in_wedge() {
+        assert_eq!(
+            hexes("hexes_in_wedge('(0,0)', 0, 2)"),
+            "(0,0) (1,0) (1,1) (2,-1) (2,0)"
+        );
+        assert_eq!(
+            hexes("hexes_in_wedge('(0,0)', 6, 2)"),
+            hexes("hexes_in_wedge('(0,0)', 0, 2)")
+        );
+        assert_eq!(
+            hexes("unnest(hexes_in_wedge_array('(0,0)', -1, 2))"),
+            hexes("hexes_in_wedge('(0,0)', 5, 2)")
+        );
+    }
+
+    #[pg_test(error = "half_angle must be finite, but is inf")]
+    fn test_hexes_in_cone_infinite() {
+        Spi::run("select hexes_in_cone('(0,0)', 0, 'infinity', 2)").unwrap();
+    }
+
+    #[pg_test]
+    fn test_hexes_in_annulus() {
+        assert_eq!(
+            hexes("hexes_in_annulus('(0,0)', 1, 1)"),
+            "(-1,0) (-1,1) (0,-1) (0,1) (1,-1) (1,0)"
+        );
+        let count = Spi::get_one::<i64>(
+            "select count(*) from hexes_in_annulus('(2,-4)', 3, 6) hex
+            where hex <-> '(2,-4)' between 3 and 6",
+        )
+        .unwrap();
+        assert_eq!(count, Some(127 - 19));
+        assert_eq!(hexes("hexes_in_annulus('(0,0)', 3, 2)"), "");
+    }
+
+    #[pg_test]
+    fn test_hexes_in_range_union() {
+        assert_eq!(
+            hexes("hexes_in_range_union(array['(0,0)', '(1,0)', null]::hex[], array[1, 0, 5])"),
+            hexes("hexes_in_range('(0,0)', 1)")
+        );
+        let (count, distinct) = Spi::get_two::<i64, i64>(
+            "select count(*), count(distinct hex)
+            from hexes_in_range_union(
+                array(select hex from hexes_in_range('(0,0)', 2) hex),
+                array_fill(3, array[19])
+            ) hex",
+        )
+        .unwrap();
+        assert_eq!(count, Some(91));
+        assert_eq!(distinct, Some(91));
+    }
+
+    #[pg_test(error = "centers and radii must be the same length, but have 2 and 1 elements")]
+    fn test_hexes_in_range_union_mismatched() {
+        Spi::run("select hexes_in_range_union(array['(0,0)', '(1,0)']::hex[], array[1])").unwrap();
+    }
+
+    #[pg_test]
+    fn test_hexes_in_range_intersection() {
+        assert_eq!(
+            hexes(
+                "hexes_in_range_intersection(array['(0,0)', '(3,0)', null]::hex[], array[2, 2, 0])"
+            ),
+            "(1,0) (1,1) (2,-1) (2,0)"
+        );
+        let (count, expected) = Spi::get_two::<i64, i64>(
+            "select
+                (select count(*) from hexes_in_range_intersection(
+                    array['(0,0)', '(2,2)', '(-1,4)']::hex[], array[3, 5, 4])),
+                (select count(*) from hexes_in_range('(0,0)', 3) hex
+                    where hex <-> '(2,2)' <= 5 and hex <-> '(-1,4)' <= 4)",
+        )
+        .unwrap();
+        assert_eq!(count, expected);
+        assert_eq!(hexes("hexes_in_range_intersection('{}', '{}')"), "");
+        assert_eq!(
+            hexes("hexes_in_range_intersection(array['(0,0)', '(-3,7)']::hex[], array[5, 1])"),
+            ""
+        );
+    }
+
+    #[pg_test]
+    fn test_ranges_intersect() {
+        let intersect = |centers: &str, radii: &str| {
+            Spi::get_one::<bool>(&format!(
+                "select ranges_intersect('{}'::hex[], '{}'::int[])",
+                centers, radii
+            ))
+            .unwrap()
+            .unwrap()
+        };
+        assert!(intersect("{\"(0,0)\",\"(4,0)\"}", "{2,2}"));
+        assert!(!intersect("{\"(0,0)\",\"(5,0)\"}", "{2,2}"));
+        assert!(!intersect("{}", "{}"));
+    }
+}
@@ -0,0 +1,376 @@
+//! SP-GiST operator class for `hex`, a k-d tree over cube coordinates.
+//!
+//! Each inner tuple splits its part of the grid in two at a value of one cube
+//! axis, cycling through q, r and s by tree level: node 0 holds the hexes at or
+//! below the split value and node 1 the hexes at or above it. Leaf tuples are
+//! the hexes themselves, so leaf checks are exact.
+//!
+//! A page of copies of one hex can't be split by value, so `picksplit` puts
+//! them all in one node and Postgres spreads them over an `allTheSame` inner
+//! tuple instead. Its nodes all cover the same part of the grid, so inserts
+//! go to any of them and scans visit every one, at the same level.
+
+use std::ffi::c_int;
+use std::mem::{size_of, size_of_val};
+
+use pgrx::prelude::*;
+use pgrx::Internal;
+
+use crate::gist::consistent;
+use crate::hex_alg::{CubeBounds, CubeCoord};
+use crate::Hex;
+
+/// The cube axis (0, 1 and 2 being q, r and s) split on at a tree level
+fn level_axis(level: c_int) -> usize {
+    (level % 3) as usize
+}
+
+fn coord_from_datum(datum: pg_sys::Datum) -> CubeCoord {
+    Hex::from_datum_bits(datum.value() as u64).into()
+}
+
+/// The scan keys or order-bys passed to a consistent function
+unsafe fn scan_keys<'a>(keys: pg_sys::ScanKey, n: c_int) -> &'a [pg_sys::ScanKeyData] {
+    if n == 0 {
+        &[]
+    } else {
+        unsafe { std::slice::from_raw_parts(keys, n as usize) }
+    }
+}
+
+/// Distances from the bounds to each order-by query, in a newly `palloc`ed
+/// array
+unsafe fn distances(bounds: &CubeBounds, orderbys: &[pg_sys::ScanKeyData]) -> *mut f64 {
+    unsafe {
+        let distances = pg_sys::palloc(orderbys.len() * size_of::<f64>()) as *mut f64;
+        for (i, orderby) in orderbys.iter().enumerate() {
+            let distance = bounds.min_dist(coord_from_datum(orderby.sk_argument));
+            distances.add(i).write(distance as f64);
+        }
+        distances
+    }
+}
+
+#[pg_extern(immutable, strict, parallel_safe)]
+fn hex_spgist_config(input: Internal, mut output: Internal) {
+    unsafe {
+        let input = input
+            .get::<pg_sys::spgConfigIn>()
+            .expect("input is not null");
+        let output = output
+            .get_mut::<pg_sys::spgConfigOut>()
+            .expect("output is not null");
+        output.prefixType = pg_sys::INT4OID;
+        output.labelType = pg_sys::VOIDOID;
+        output.leafType = input.attType;
+        output.canReturnData = true;
+        output.longValuesOK = false;
+    }
+}
+
+#[pg_extern(immutable, strict, parallel_safe)]
+fn hex_spgist_choose(input: Internal, mut output: Internal) {
+    unsafe {
+        let input = input
+            .get::<pg_sys::spgChooseIn>()
+            .expect("input is not null");
+        let output = output
+            .get_mut::<pg_sys::spgChooseOut>()
+            .expect("output is not null");
+        if input.allTheSame {
+            // Postgres picks the node
+            output.resultType = pg_sys::spgChooseResultType_spgMatchNode;
+            output.result.matchNode.levelAdd = 0;
+            output.result.matchNode.restDatum = input.datum;
+            return;
+        }
+        if !input.hasPrefix || input.nNodes != 2 {
+            error!("hex SP-GiST inner tuples always split in two at a prefix");
+        }
+
+        let split = input.prefixDatum.value() as i32;
+        let value = coord_from_datum(input.datum).components()[level_axis(input.level)];
+
+        output.resultType = pg_sys::spgChooseResultType_spgMatchNode;
+        output.result.matchNode.nodeN = if value > split { 1 } else { 0 };
+        output.result.matchNode.levelAdd = 1;
+        output.result.matchNode.restDatum = input.datum;
+    }
+}
+
+#[pg_extern(immutable, strict, parallel_safe)]
+fn hex_spgist_picksplit(input: Internal, mut output: Internal) {
+    unsafe {
+        let input = input
+            .get::<pg_sys::spgPickSplitIn>()
+            .expect("input is not null");
+        let output = output
+            .get_mut::<pg_sys::spgPickSplitOut>()
+            .expect("output is not null");
+
+        let datums = std::slice::from_raw_parts(input.datums, input.nTuples as usize);
+        let axis = level_axis(input.level);
+        let mut order = (0..datums.len()).collect::<Vec<_>>();
+        order.sort_by_key(|&i| coord_from_datum(datums[i]).components()[axis]);
+
+        // Split at the median, so both halves get the same number of hexes even
+        // if some share the median value
+        let middle = order.len() / 2;
+        let split = coord_from_datum(datums[order[middle]]).components()[axis];
+
+        output.hasPrefix = true;
+        output.prefixDatum = pg_sys::Datum::from(split);
+        output.nNodes = 2;
+        output.nodeLabels = std::ptr::null_mut();
+        output.mapTuplesToNodes = pg_sys::palloc(datums.len() * size_of::<c_int>()) as *mut c_int;
+        output.leafTupleDatums = pg_sys::palloc(size_of_val(datums)) as *mut pg_sys::Datum;
+
+        // Putting copies of one hex all in node 0 has Postgres spread them over
+        // an allTheSame inner tuple, rather than halving them again at every
+        // level below
+        let first = coord_from_datum(datums[0]);
+        let all_same = datums.iter().all(|&datum| coord_from_datum(datum) == first);
+
+        for (rank, &i) in order.iter().enumerate() {
+            let node = if rank < middle || all_same { 0 } else { 1 };
+            output.mapTuplesToNodes.add(i).write(node);
+            output.leafTupleDatums.add(i).write(datums[i]);
+        }
+    }
+}
+
+#[pg_extern(immutable, strict, parallel_safe)]
+fn hex_spgist_inner_consistent(input: Internal, mut output: Internal) {
+    unsafe {
+        let input = input
+            .get::<pg_sys::spgInnerConsistentIn>()
+            .expect("input is not null");
+        let output = output
+            .get_mut::<pg_sys::spgInnerConsistentOut>()
+            .expect("output is not null");
+        if !input.allTheSame && (!input.hasPrefix || input.nNodes != 2) {
+            error!("hex SP-GiST inner tuples always split in two at a prefix");
+        }
+
+        // The bounds of this inner tuple are passed down from its parent
+        let bounds = match (input.traversalValue as *const CubeBounds).as_ref() {
+            Some(bounds) => *bounds,
+            None => CubeBounds::unbounded(),
+        };
+        // The nodes of an allTheSame inner tuple all have its bounds, and stay at
+        // its level
+        let (children, level_add) = if input.allTheSame {
+            (vec![bounds; input.nNodes as usize], 0)
+        } else {
+            let split = input.prefixDatum.value() as i32;
+            (bounds.split_at(level_axis(input.level), split).to_vec(), 1)
+        };
+
+        let scankeys = scan_keys(input.scankeys, input.nkeys);
+        let orderbys = scan_keys(input.orderbys, input.norderbys);
+
+        let n_nodes = children.len();
+        output.nNodes = 0;
+        output.nodeNumbers = pg_sys::palloc(n_nodes * size_of::<c_int>()) as *mut c_int;
+        output.levelAdds = pg_sys::palloc(n_nodes * size_of::<c_int>()) as *mut c_int;
+        output.traversalValues = pg_sys::palloc(n_nodes * size_of::<*mut std::ffi::c_void>())
+            as *mut *mut std::ffi::c_void;
+        if !orderbys.is_empty() {
+            output.distances = pg_sys::palloc(n_nodes * size_of::<*mut f64>()) as *mut *mut f64;
+        }
+
+        for (node, child) in children.iter().enumerate() {
+            let matches = scankeys
+                .iter()
+                .all(|key| consistent(child, key.sk_argument, key.sk_strategy));
+            if !matches {
+                continue;
+            }
+
+            let n = output.nNodes as usize;
+            output.nodeNumbers.add(n).write(node as c_int);
+            output.levelAdds.add(n).write(level_add);
+
+            let traversal =
+                pg_sys::MemoryContextAlloc(input.traversalMemoryContext, size_of::<CubeBounds>())
+                    as *mut CubeBounds;
+            traversal.write(*child);
+            output
+                .traversalValues
+                .add(n)
+                .write(traversal as *mut std::ffi::c_void);
+
+            if !orderbys.is_empty() {
+                output.distances.add(n).write(distances(child, orderbys));
+            }
+            output.nNodes += 1;
+        }
+    }
+}
+
+#[pg_extern(immutable, strict, parallel_safe)]
+fn hex_spgist_leaf_consistent(input: Internal, mut output: Internal) -> bool {
+    unsafe {
+        let input = input
+            .get::<pg_sys::spgLeafConsistentIn>()
+            .expect("input is not null");
+        let output = output
+            .get_mut::<pg_sys::spgLeafConsistentOut>()
+            .expect("output is not null");
+
+        let leaf = CubeBounds::from_point(coord_from_datum(input.leafDatum));
+        output.leafValue = input.leafDatum;
+        output.recheck = false;
+        output.recheckDistances = false;
+
+        let matches = scan_keys(input.scankeys, input.nkeys)
+            .iter()
+            .all(|key| consistent(&leaf, key.sk_argument, key.sk_strategy));
+
+        let orderbys = scan_keys(input.orderbys, input.norderbys);
+        if matches && !orderbys.is_empty() {
+            output.distances = distances(&leaf, orderbys);
+        }
+
+        matches
+    }
+}
+
+extension_sql!(
+    r#"
+CREATE OPERATOR CLASS hex_spgist_ops
+    DEFAULT FOR TYPE hex USING spgist AS
+        OPERATOR 8 <@ (hex, hexrange),
+        OPERATOR 15 <-> (hex, hex) FOR ORDER BY pg_catalog.integer_ops,
+        OPERATOR 18 = (hex, hex),
+        FUNCTION 1 hex_spgist_config(internal, internal),
+        FUNCTION 2 hex_spgist_choose(internal, internal),
+        FUNCTION 3 hex_spgist_picksplit(internal, internal),
+        FUNCTION 4 hex_spgist_inner_consistent(internal, internal),
+        FUNCTION 5 hex_spgist_leaf_consistent(internal, internal);
+"#,
+    name = "hex_spgist_ops",
+    requires = [
+        "hex_type",
+        "hexrange_type",
+        hex_eq,
+        hex_within,
+        hex_distance,
+        hex_spgist_config,
+        hex_spgist_choose,
+        hex_spgist_picksplit,
+        hex_spgist_inner_consistent,
+        hex_spgist_leaf_consistent
+    ],
+);
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use crate::tests::explain;
+    use pgrx::prelude::*;
+
+    fn create_indexed_units() {
+        Spi::run("create table units (position hex)").unwrap();
+        // A crowded city, with many units on the same tiles, and sparse wilderness
+        Spi::run(
+            "insert into units
+            select hexes_in_range('(10,10)', 3) from generate_series(1, 20)
+            union all
+            select hexes_in_range('(0,0)', 40)",
+        )
+        .unwrap();
+        Spi::run("create index units_position_spgist on units using spgist (position)").unwrap();
+        Spi::run("analyze units").unwrap();
+        Spi::run("set local enable_seqscan = off").unwrap();
+    }
+
+    #[pg_test]
+    fn test_spgist_within() {
+        create_indexed_units();
+
+        let query = "select count(*) from units where position <@ hexrange('(9,11)', 2)";
+        assert!(explain(query).contains("units_position_spgist"));
+        let value = Spi::get_one::<i64>(query).unwrap().unwrap();
+        // 19 hexes, all inside the city so each appears 21 times
+        assert_eq!(value, 19 * 21);
+    }
+
+    #[pg_test]
+    fn test_spgist_equal() {
+        create_indexed_units();
+
+        let query = "select count(*) from units where position = '(-30,12)'";
+        assert!(explain(query).contains("units_position_spgist"));
+        let value = Spi::get_one::<i64>(query).unwrap().unwrap();
+        assert_eq!(value, 1);
+
+        let value = Spi::get_one::<i64>("select count(*) from units where position = '(10,10)'")
+            .unwrap()
+            .unwrap();
+        assert_eq!(value, 21);
+    }
+
+    #[pg_test]
+    fn test_spgist_knn() {
+        create_indexed_units();
+
+        let query = "select position from units order by position <-> '(-20,5)' limit 7";
+        assert!(explain(query).contains("units_position_spgist"));
+        let value = Spi::get_one::<String>(&format!(
+            "select string_agg(hex_distance(position, '(-20,5)')::text, ',') from ({}) nearest",
+            query
+        ))
+        .unwrap()
+        .unwrap();
+        assert_eq!(value, "0,1,1,1,1,1,1");
+    }
+
+    #[pg_test]
+    fn test_spgist_duplicates() {
+        // A stack of thousands of units on one tile
+        Spi::run("create table stack (position hex)").unwrap();
+        Spi::run("insert into stack select '(4,-2)' from generate_series(1, 5000)").unwrap();
+        Spi::run("create index stack_position_spgist on stack using spgist (position)").unwrap();
+        Spi::run("set local enable_seqscan = off").unwrap();
+
+        // Inserting one more reads a page at each level it passes, so this
+        // stays small as long as the tree does
+        let fetched = || {
+            Spi::get_one::<i64>(
+                "select pg_stat_get_xact_blocks_fetched('stack_position_spgist'::regclass)",
+            )
+            .unwrap()
+            .unwrap()
+        };
+        let before = fetched();
+        Spi::run("insert into stack values ('(4,-2)')").unwrap();
+        let depth = fetched() - before;
+        assert!(depth <= 8, "read {} pages to insert", depth);
+
+        let query = "select count(*) from stack where position = '(4,-2)'";
+        assert!(explain(query).contains("stack_position_spgist"));
+        let value = Spi::get_one::<i64>(query).unwrap().unwrap();
+        assert_eq!(value, 5001);
+        let value = Spi::get_one::<i64>(
+            "select count(*) from stack where position <@ hexrange('(5,-2)', 1)",
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(value, 5001);
+    }
+
+    #[pg_test]
+    fn test_spgist_matches_seqscan() {
+        create_indexed_units();
+
+        let query = "select string_agg(position::text, ' ' order by position) from units
+            where position <@ hexrange('(12,7)', 6)";
+        let indexed = Spi::get_one::<String>(query).unwrap().unwrap();
+        Spi::run("set local enable_seqscan = on").unwrap();
+        Spi::run("set local enable_indexscan = off").unwrap();
+        Spi::run("set local enable_bitmapscan = off").unwrap();
+        let scanned = Spi::get_one::<String>(query).unwrap().unwrap();
+        assert_eq!(indexed, scanned);
+    }
+}
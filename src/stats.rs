@@ -0,0 +1,434 @@
+//! Statistics and selectivity estimation for `hex` columns.
+//!
+//! `ANALYZE` collects Postgres's standard statistics, which `=` and `<>`
+//! estimate from with `eqsel` and `neqsel`, plus a `HexHistogram` of where the
+//! hexes are on the grid, which the distance conditions estimate from.
+
+use std::ffi::{c_int, c_void};
+use std::mem::size_of;
+
+use pgrx::prelude::*;
+use pgrx::{is_a, Internal, PgList};
+
+use crate::gist::datum;
+use crate::hex_alg::{CubeCoord, HexHistogram};
+use crate::hexrange::HexRange;
+use crate::support::hexrange_function;
+use crate::Hex;
+
+/// `pg_statistic` slot kind of the histogram, chosen at random from the range
+/// Postgres leaves for private use
+const STATISTIC_KIND_HEX_HISTOGRAM: i16 = 17_236;
+
+/// Selectivity of `<@` without statistics, the same as `contsel`
+const DEFAULT_WITHIN_SELECTIVITY: f64 = 0.001;
+
+/// What `hex_typanalyze` needs to run the standard statistics computation
+/// before its own
+struct HexAnalyzeData {
+    std_compute_stats: pg_sys::AnalyzeAttrComputeStatsFunc,
+    std_extra_data: *mut c_void,
+}
+
+#[pg_extern(immutable, strict, parallel_safe, sql = false)]
+fn hex_typanalyze(stats: Internal) -> bool {
+    unsafe {
+        let stats = datum(stats).cast_mut_ptr::<pg_sys::VacAttrStats>();
+        if !pg_sys::std_typanalyze(stats) {
+            return false;
+        }
+
+        let data = pg_sys::palloc(size_of::<HexAnalyzeData>()) as *mut HexAnalyzeData;
+        data.write(HexAnalyzeData {
+            std_compute_stats: (*stats).compute_stats,
+            std_extra_data: (*stats).extra_data,
+        });
+        (*stats).extra_data = data as *mut c_void;
+        (*stats).compute_stats = Some(compute_hex_stats);
+        true
+    }
+}
+
+/// Compute the standard statistics, then add the histogram in a free slot
+#[pg_guard]
+unsafe extern "C" fn compute_hex_stats(
+    stats: pg_sys::VacAttrStatsP,
+    fetchfunc: pg_sys::AnalyzeAttrFetchFunc,
+    samplerows: c_int,
+    totalrows: f64,
+) {
+    unsafe {
+        let data = ((*stats).extra_data as *mut HexAnalyzeData).read();
+        (*stats).extra_data = data.std_extra_data;
+        if let Some(std_compute_stats) = data.std_compute_stats {
+            std_compute_stats(stats, fetchfunc, samplerows, totalrows);
+        }
+        if !(*stats).stats_valid {
+            return;
+        }
+        let Some(slot) = (*stats).stakind.iter().position(|&kind| kind == 0) else {
+            return;
+        };
+
+        let fetch = fetchfunc.expect("ANALYZE passes a fetch function");
+        let mut coords = Vec::with_capacity(samplerows as usize);
+        for row in 0..samplerows {
+            let mut isnull = false;
+            let value = fetch(stats, row, &mut isnull);
+            if !isnull {
+                coords.push(CubeCoord::from(Hex::from_datum_bits(value.value() as u64)));
+            }
+        }
+        let Some(histogram) = HexHistogram::new(&coords, samplerows as usize) else {
+            return;
+        };
+
+        // The statistics have to outlive this function, in the analyze context
+        let numbers = histogram.to_numbers();
+        let stanumbers =
+            pg_sys::MemoryContextAlloc((*stats).anl_context, numbers.len() * size_of::<f32>())
+                as *mut f32;
+        stanumbers.copy_from_nonoverlapping(numbers.as_ptr(), numbers.len());
+
+        (*stats).stakind[slot] = STATISTIC_KIND_HEX_HISTOGRAM;
+        (*stats).staop[slot] = pg_sys::InvalidOid;
+        (*stats).stanumbers[slot] = stanumbers;
+        (*stats).numnumbers[slot] = numbers.len() as c_int;
+    }
+}
+
+/// The histogram of a variable, if `ANALYZE` collected one
+unsafe fn vardata_histogram(vardata: &pg_sys::VariableStatData) -> Option<HexHistogram> {
+    if vardata.statsTuple.is_null() {
+        return None;
+    }
+    unsafe {
+        let mut slot = std::mem::zeroed::<pg_sys::AttStatsSlot>();
+        let found = pg_sys::get_attstatsslot(
+            &mut slot,
+            vardata.statsTuple,
+            STATISTIC_KIND_HEX_HISTOGRAM as c_int,
+            pg_sys::InvalidOid,
+            pg_sys::ATTSTATSSLOT_NUMBERS as c_int,
+        );
+        if !found {
+            return None;
+        }
+        let numbers = std::slice::from_raw_parts(slot.numbers, slot.nnumbers as usize);
+        let histogram = HexHistogram::from_numbers(numbers);
+        pg_sys::free_attstatsslot(&mut slot);
+        histogram
+    }
+}
+
+/// Release what `examine_variable` looked up, like `ReleaseVariableStats`
+unsafe fn release_vardata(vardata: &pg_sys::VariableStatData) {
+    if !vardata.statsTuple.is_null() {
+        if let Some(freefunc) = vardata.freefunc {
+            unsafe { freefunc(vardata.statsTuple) };
+        }
+    }
+}
+
+/// For a restriction clause with two arguments, the histogram of the one
+/// that's a variable, the other one if it's a non-null constant, and whether
+/// the variable is on the left
+pub(crate) unsafe fn restriction_histogram(
+    root: *mut pg_sys::PlannerInfo,
+    args: *mut pg_sys::List,
+    var_relid: c_int,
+) -> Option<(HexHistogram, pg_sys::Datum, bool)> {
+    unsafe {
+        let mut vardata = std::mem::zeroed::<pg_sys::VariableStatData>();
+        let mut other = std::ptr::null_mut();
+        let mut var_on_left = false;
+        if !pg_sys::get_restriction_variable(
+            root,
+            args,
+            var_relid,
+            &mut vardata,
+            &mut other,
+            &mut var_on_left,
+        ) {
+            return None;
+        }
+
+        let histogram = vardata_histogram(&vardata);
+        release_vardata(&vardata);
+
+        if !is_a(other, pg_sys::NodeTag::T_Const) {
+            return None;
+        }
+        let other = &*(other as *mut pg_sys::Const);
+        if other.constisnull {
+            return None;
+        }
+        Some((histogram?, other.constvalue, var_on_left))
+    }
+}
+
+/// The histogram of an expression in a join clause
+pub(crate) unsafe fn join_histogram(
+    root: *mut pg_sys::PlannerInfo,
+    node: *mut pg_sys::Node,
+) -> Option<HexHistogram> {
+    unsafe {
+        let mut vardata = std::mem::zeroed::<pg_sys::VariableStatData>();
+        pg_sys::examine_variable(root, node, 0, &mut vardata);
+        let histogram = vardata_histogram(&vardata);
+        release_vardata(&vardata);
+        histogram
+    }
+}
+
+/// Selectivity of `hex <@ hexrange` or `hexrange @> hex`, where the hex is on
+/// the left if `hex_on_left`
+unsafe fn within_selectivity(
+    root: Internal,
+    args: Internal,
+    var_relid: i32,
+    hex_on_left: bool,
+) -> f64 {
+    let root = datum(root).cast_mut_ptr::<pg_sys::PlannerInfo>();
+    let args = datum(args).cast_mut_ptr::<pg_sys::List>();
+    match unsafe { restriction_histogram(root, args, var_relid) } {
+        Some((histogram, range, var_on_left)) if var_on_left == hex_on_left => {
+            let range = unsafe { HexRange::from_datum(range, false) }.expect("range is not null");
+            histogram.fraction_within(range.center.into(), range.radius)
+        }
+        _ => DEFAULT_WITHIN_SELECTIVITY,
+    }
+}
+
+/// Restriction selectivity of `<@`
+#[pg_extern(immutable, strict, parallel_safe)]
+fn hex_within_sel(root: Internal, _operator: pg_sys::Oid, args: Internal, var_relid: i32) -> f64 {
+    unsafe { within_selectivity(root, args, var_relid, true) }
+}
+
+/// Restriction selectivity of `@>`
+#[pg_extern(immutable, strict, parallel_safe)]
+fn hexrange_contains_sel(
+    root: Internal,
+    _operator: pg_sys::Oid,
+    args: Internal,
+    var_relid: i32,
+) -> f64 {
+    unsafe { within_selectivity(root, args, var_relid, false) }
+}
+
+/// The center and radius of `node` if it's a call to `hexrange(hex, integer)`
+/// with a radius the planner can work out, whose statistics are those of the
+/// center. `operator` finds the function in the extension's schema.
+unsafe fn hexrange_call(
+    root: *mut pg_sys::PlannerInfo,
+    operator: pg_sys::Oid,
+    node: *mut pg_sys::Node,
+    hex_type: pg_sys::Oid,
+) -> Option<(*mut pg_sys::Node, i32)> {
+    unsafe {
+        if !is_a(node, pg_sys::NodeTag::T_FuncExpr) {
+            return None;
+        }
+        let call = &*(node as *mut pg_sys::FuncExpr);
+        if Some(call.funcid) != hexrange_function(pg_sys::get_opcode(operator), hex_type) {
+            return None;
+        }
+        let args = PgList::<pg_sys::Node>::from_pg(call.args);
+        let radius = pg_sys::estimate_expression_value(root, args.get_ptr(1)?);
+        if !is_a(radius, pg_sys::NodeTag::T_Const) {
+            return None;
+        }
+        let radius = &*(radius as *mut pg_sys::Const);
+        if radius.constisnull {
+            return None;
+        }
+        Some((args.get_ptr(0)?, i32::from_datum(radius.constvalue, false)?))
+    }
+}
+
+/// Join selectivity of `hex <@ hexrange` or `hexrange @> hex`, where the hex is
+/// on the left if `hex_on_left`. Only ranges made by `hexrange` have
+/// statistics, from their centers.
+unsafe fn within_join_selectivity(
+    root: Internal,
+    operator: pg_sys::Oid,
+    args: Internal,
+    hex_on_left: bool,
+) -> f64 {
+    let root = datum(root).cast_mut_ptr::<pg_sys::PlannerInfo>();
+    let args = unsafe { PgList::<pg_sys::Node>::from_pg(datum(args).cast_mut_ptr()) };
+    let (hex, range) = if hex_on_left { (0, 1) } else { (1, 0) };
+    let fraction = || unsafe {
+        let hex = args.get_ptr(hex)?;
+        let (center, radius) =
+            hexrange_call(root, operator, args.get_ptr(range)?, pg_sys::exprType(hex))?;
+        Some(
+            join_histogram(root, hex)?
+                .fraction_pairs_within(&join_histogram(root, center)?, radius),
+        )
+    };
+    fraction().unwrap_or(DEFAULT_WITHIN_SELECTIVITY)
+}
+
+/// Join selectivity of `<@`
+#[pg_extern(immutable, strict, parallel_safe)]
+fn hex_within_joinsel(
+    root: Internal,
+    operator: pg_sys::Oid,
+    args: Internal,
+    _join_type: i16,
+    _join_info: Internal,
+) -> f64 {
+    unsafe { within_join_selectivity(root, operator, args, true) }
+}
+
+/// Join selectivity of `@>`
+#[pg_extern(immutable, strict, parallel_safe)]
+fn hexrange_contains_joinsel(
+    root: Internal,
+    operator: pg_sys::Oid,
+    args: Internal,
+    _join_type: i16,
+    _join_info: Internal,
+) -> f64 {
+    unsafe { within_join_selectivity(root, operator, args, false) }
+}
+
+extension_sql!(
+    r#"
+ALTER OPERATOR <@ (hex, hexrange) SET (RESTRICT = hex_within_sel, JOIN = hex_within_joinsel);
+ALTER OPERATOR @> (hexrange, hex) SET (
+    RESTRICT = hexrange_contains_sel,
+    JOIN = hexrange_contains_joinsel
+);
+"#,
+    name = "hex_within_selectivity",
+    requires = [
+        hex_within,
+        hexrange_contains,
+        hex_within_sel,
+        hexrange_contains_sel,
+        hex_within_joinsel,
+        hexrange_contains_joinsel
+    ],
+);
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use crate::tests::estimated_rows;
+    use pgrx::prelude::*;
+
+    /// A town of 1261 units, a village of 37, and 200 units in reserve off
+    /// the grid
+    fn create_analyzed_units() {
+        Spi::run("create table units (position hex)").unwrap();
+        Spi::run(
+            "insert into units
+            select hexes_in_range('(0,0)', 20)
+            union all
+            select hexes_in_range('(100,-50)', 3)
+            union all
+            select null from generate_series(1, 200)",
+        )
+        .unwrap();
+        Spi::run("analyze units").unwrap();
+    }
+
+    fn assert_estimate(query: &str, expected: i64) {
+        let estimate = estimated_rows(query);
+        assert!(
+            (estimate - expected).abs() <= expected / 5 + 2,
+            "estimated {} rows rather than {} for {}",
+            estimate,
+            expected,
+            query
+        );
+    }
+
+    #[pg_test]
+    fn test_analyze_collects_histogram() {
+        create_analyzed_units();
+        let kinds = Spi::get_one::<String>(
+            "select concat_ws(',', stakind1, stakind2, stakind3, stakind4, stakind5)
+            from pg_statistic
+            where starelid = 'units'::regclass",
+        )
+        .unwrap()
+        .unwrap();
+        assert!(kinds.split(',').any(|kind| kind == "17236"), "{}", kinds);
+    }
+
+    #[pg_test]
+    fn test_within_selectivity() {
+        create_analyzed_units();
+        assert_estimate(
+            "select * from units where position <@ hexrange('(0,0)', 10)",
+            331,
+        );
+        assert_estimate(
+            "select * from units where hexrange('(5,-3)', 4) @> position",
+            61,
+        );
+        assert_estimate(
+            "select * from units where position <@ hexrange('(100,-50)', 5)",
+            37,
+        );
+        assert_estimate(
+            "select * from units where position <@ hexrange('(50,-25)', 5)",
+            0,
+        );
+    }
+
+    #[pg_test]
+    fn test_equality_selectivity() {
+        create_analyzed_units();
+        assert_estimate("select * from units where position = '(3,4)'", 1);
+        assert_estimate("select * from units where position <> '(3,4)'", 1297);
+    }
+
+    #[pg_test]
+    fn test_dwithin_selectivity() {
+        create_analyzed_units();
+        assert_estimate(
+            "select * from units where hex_dwithin(position, '(0,0)', 10)",
+            331,
+        );
+        assert_estimate(
+            "select * from units where hex_dwithin('(100,-50)', position, 1)",
+            7,
+        );
+    }
+
+    #[pg_test]
+    fn test_dwithin_join_selectivity() {
+        create_analyzed_units();
+        Spi::run("create table towers (position hex)").unwrap();
+        Spi::run("insert into towers values ('(0,0)'), ('(5,5)')").unwrap();
+        Spi::run("analyze towers").unwrap();
+
+        // Each tower has 19 units within 2 of it
+        assert_estimate(
+            "select * from towers t join units u on hex_dwithin(u.position, t.position, 2)",
+            38,
+        );
+    }
+
+    #[pg_test]
+    fn test_within_join_selectivity() {
+        create_analyzed_units();
+        Spi::run("create table towers (position hex)").unwrap();
+        Spi::run("insert into towers values ('(0,0)'), ('(5,5)')").unwrap();
+        Spi::run("analyze towers").unwrap();
+
+        assert_estimate(
+            "select * from towers t join units u on u.position <@ hexrange(t.position, 2)",
+            38,
+        );
+        assert_estimate(
+            "select * from towers t join units u on hexrange(t.position, 1 + 1) @> u.position",
+            38,
+        );
+    }
+}
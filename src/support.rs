@@ -0,0 +1,486 @@
+//! Planner support functions.
+//!
+//! Postgres only asks the support function of the outermost function or
+//! operator of a condition, so `hex_distance(a, b) <= n` can't be rewritten
+//! into an index condition. `hex_dwithin(a, b, n)` asks the same question as
+//! a single function call, and its support function can.
+//!
+//! Its support function also estimates its selectivity from the statistics
+//! collected by `stats`.
+//!
+//! The set-returning functions have support functions estimating how many rows
+//! they return, as their sizes only depend on arguments that are usually
+//! constants.
+
+use pgrx::prelude::*;
+use pgrx::{is_a, Internal, PgList};
+
+use crate::gist::datum;
+use crate::hex_alg::{
+    CubeCoord, HexAnnulusIter, HexLineDrawIter, HexRangeIter, HexRingPathIter, HexSpiralPathIter,
+};
+use crate::stats::{join_histogram, restriction_histogram};
+use crate::Hex;
+
+/// What a support function returns when it can't help with a request: a null
+/// pointer, rather than SQL null
+fn no_support() -> Internal {
+    Internal::from(Some(pg_sys::Datum::from(0usize)))
+}
+
+/// Whether `a` and `b` are within `distance` of each other. Unlike
+/// `hex_distance(a, b) <= distance`, this can use an index on either hex.
+#[pg_extern(immutable, strict, parallel_safe)]
+fn hex_dwithin(a: Hex, b: Hex, distance: i32) -> bool {
+    if distance < 0 {
+        ereport!(
+            ERROR,
+            PgSqlErrorCode::ERRCODE_INVALID_PARAMETER_VALUE,
+            format!(
+                "hex_dwithin distance must not be negative, but is {}",
+                distance
+            )
+        );
+    }
+    CubeCoord::from(a).dist(CubeCoord::from(b)) <= distance
+}
+
+#[pg_extern(immutable, strict, parallel_safe)]
+fn hex_dwithin_support(request: Internal) -> Internal {
+    let node = datum(request).cast_mut_ptr::<pg_sys::Node>();
+    unsafe {
+        if is_a(node, pg_sys::NodeTag::T_SupportRequestIndexCondition) {
+            let request = &mut *(node as *mut pg_sys::SupportRequestIndexCondition);
+            if let Some(conditions) = index_condition(request) {
+                return Internal::from(Some(pg_sys::Datum::from(conditions)));
+            }
+        } else if is_a(node, pg_sys::NodeTag::T_SupportRequestSelectivity) {
+            let request = &mut *(node as *mut pg_sys::SupportRequestSelectivity);
+            if let Some(selectivity) = dwithin_selectivity(request) {
+                request.selectivity = selectivity;
+                return Internal::from(Some(pg_sys::Datum::from(node)));
+            }
+        }
+    }
+    no_support()
+}
+
+/// Estimate how many rows `hex_dwithin(a, b, distance)` is true for from the
+/// statistics of `a` and `b`, if `distance` is a constant
+unsafe fn dwithin_selectivity(request: &pg_sys::SupportRequestSelectivity) -> Option<f64> {
+    let args = unsafe { PgList::<pg_sys::Node>::from_pg(request.args) };
+    let (a, b) = (args.get_ptr(0)?, args.get_ptr(1)?);
+    unsafe {
+        let distance = pg_sys::estimate_expression_value(request.root, args.get_ptr(2)?);
+        if !is_a(distance, pg_sys::NodeTag::T_Const) {
+            return None;
+        }
+        let distance = &*(distance as *mut pg_sys::Const);
+        if distance.constisnull {
+            return None;
+        }
+        let distance = distance.constvalue.value() as i32;
+
+        if request.is_join {
+            let a = join_histogram(request.root, a)?;
+            let b = join_histogram(request.root, b)?;
+            Some(a.fraction_pairs_within(&b, distance))
+        } else {
+            let mut pair = PgList::<pg_sys::Node>::new();
+            pair.push(a);
+            pair.push(b);
+            let (histogram, center, _) =
+                restriction_histogram(request.root, pair.into_pg(), request.varRelid)?;
+            let center = Hex::from_datum_bits(center.value() as u64);
+            Some(histogram.fraction_within(center.into(), distance))
+        }
+    }
+}
+
+/// Rewrite `hex_dwithin(position, center, distance)`, where `position` is
+/// indexed, into `position <@ hexrange(center, distance)`
+unsafe fn index_condition(
+    request: &mut pg_sys::SupportRequestIndexCondition,
+) -> Option<*mut pg_sys::List> {
+    if !unsafe { is_a(request.node, pg_sys::NodeTag::T_FuncExpr) } {
+        return None;
+    }
+    let func = request.node as *mut pg_sys::FuncExpr;
+    let args = unsafe { PgList::<pg_sys::Node>::from_pg((*func).args) };
+    let (indexed, center) = match request.indexarg {
+        0 => (args.get_ptr(0)?, args.get_ptr(1)?),
+        1 => (args.get_ptr(1)?, args.get_ptr(0)?),
+        _ => return None,
+    };
+    let distance = args.get_ptr(2)?;
+    unsafe {
+        if !is_pseudo_constant(request, center) || !is_pseudo_constant(request, distance) {
+            return None;
+        }
+
+        let hex_type = pg_sys::exprType(indexed);
+        let hexrange_fn = hexrange_function(request.funcid, hex_type)?;
+        let hexrange_type = pg_sys::get_func_rettype(hexrange_fn);
+        let within = pg_sys::get_opfamily_member(
+            request.opfamily,
+            hex_type,
+            hexrange_type,
+            pg_sys::RTContainedByStrategyNumber as i16,
+        );
+        if within == pg_sys::InvalidOid {
+            return None;
+        }
+
+        let mut range_args = PgList::<pg_sys::Node>::new();
+        range_args.push(center);
+        range_args.push(distance);
+        let range = pg_sys::makeFuncExpr(
+            hexrange_fn,
+            hexrange_type,
+            range_args.into_pg(),
+            pg_sys::InvalidOid,
+            pg_sys::InvalidOid,
+            pg_sys::CoercionForm_COERCE_EXPLICIT_CALL,
+        );
+        let condition = pg_sys::make_opclause(
+            within,
+            pg_sys::BOOLOID,
+            false,
+            indexed as *mut pg_sys::Expr,
+            range as *mut pg_sys::Expr,
+            pg_sys::InvalidOid,
+            pg_sys::InvalidOid,
+        );
+
+        // hex_dwithin itself is still checked against each row the index finds
+        request.lossy = true;
+
+        let mut conditions = PgList::<pg_sys::Expr>::new();
+        conditions.push(condition);
+        Some(conditions.into_pg())
+    }
+}
+
+/// Whether `expr` can be computed once before scanning the index, i.e. it
+/// doesn't depend on the indexed table and isn't volatile
+unsafe fn is_pseudo_constant(
+    request: &pg_sys::SupportRequestIndexCondition,
+    expr: *mut pg_sys::Node,
+) -> bool {
+    #[cfg(any(feature = "pg12", feature = "pg13"))]
+    unsafe {
+        pg_sys::is_pseudo_constant_for_index(expr, request.index)
+    }
+    #[cfg(not(any(feature = "pg12", feature = "pg13")))]
+    unsafe {
+        pg_sys::is_pseudo_constant_for_index(request.root, expr, request.index)
+    }
+}
+
+/// The `hexrange(hex, integer)` function, looked up in the same schema as the
+/// function `sibling` so it's found wherever the extension is installed
+pub(crate) unsafe fn hexrange_function(
+    sibling: pg_sys::Oid,
+    hex_type: pg_sys::Oid,
+) -> Option<pg_sys::Oid> {
+    unsafe {
+        let schema = pg_sys::get_namespace_name(pg_sys::get_func_namespace(sibling));
+        let mut name = PgList::<pg_sys::Node>::new();
+        name.push(pg_sys::makeString(schema) as *mut pg_sys::Node);
+        name.push(pg_sys::makeString(pg_sys::pstrdup(c"hexrange".as_ptr())) as *mut pg_sys::Node);
+        let arg_types = [hex_type, pg_sys::INT4OID];
+        let oid = pg_sys::LookupFuncName(name.into_pg(), 2, arg_types.as_ptr(), true);
+        (oid != pg_sys::InvalidOid).then_some(oid)
+    }
+}
+
+extension_sql!(
+    r#"
+ALTER FUNCTION hex_dwithin(hex, hex, integer) SUPPORT hex_dwithin_support;
+"#,
+    name = "hex_dwithin_support",
+    requires = [hex_dwithin, hex_dwithin_support],
+);
+
+/// The arguments of the function call a support request is about, with `None`
+/// for any that aren't constants known while planning
+unsafe fn const_args(call: *mut pg_sys::Node) -> Vec<Option<pg_sys::Datum>> {
+    unsafe {
+        if !is_a(call, pg_sys::NodeTag::T_FuncExpr) {
+            return Vec::new();
+        }
+        let args = PgList::<pg_sys::Node>::from_pg((*(call as *mut pg_sys::FuncExpr)).args);
+        (0..args.len())
+            .map(|i| {
+                let arg = args.get_ptr(i)?;
+                if !is_a(arg, pg_sys::NodeTag::T_Const) {
+                    return None;
+                }
+                let arg = &*(arg as *mut pg_sys::Const);
+                (!arg.constisnull).then_some(arg.constvalue)
+            })
+            .collect()
+    }
+}
+
+fn hex_arg(args: &[Option<pg_sys::Datum>], i: usize) -> Option<Hex> {
+    let datum = (*args.get(i)?)?;
+    Some(Hex::from_datum_bits(datum.value() as u64))
+}
+
+fn int_arg(args: &[Option<pg_sys::Datum>], i: usize) -> Option<i32> {
+    let datum = (*args.get(i)?)?;
+    Some(datum.value() as i32)
+}
+
+/// Answer a `SupportRequestRows` with the number of rows computed by `rows`
+/// from the constant arguments, if it can be
+fn rows_support(
+    request: Internal,
+    rows: impl FnOnce(&[Option<pg_sys::Datum>]) -> Option<i64>,
+) -> Internal {
+    let node = datum(request).cast_mut_ptr::<pg_sys::Node>();
+    unsafe {
+        if is_a(node, pg_sys::NodeTag::T_SupportRequestRows) {
+            let request = &mut *(node as *mut pg_sys::SupportRequestRows);
+            if let Some(rows) = rows(&const_args(request.node)) {
+                request.rows = rows as f64;
+                return Internal::from(Some(pg_sys::Datum::from(node)));
+            }
+        }
+    }
+    no_support()
+}
+
+#[pg_extern(immutable, strict, parallel_safe)]
+fn hexes_in_range_support(request: Internal) -> Internal {
+    rows_support(request, |args| Some(HexRangeIter::size(int_arg(args, 1)?)))
+}
+
+#[pg_extern(immutable, strict, parallel_safe)]
+fn hexes_in_annulus_support(request: Internal) -> Internal {
+    rows_support(request, |args| {
+        Some(HexAnnulusIter::size(int_arg(args, 1)?, int_arg(args, 2)?))
+    })
+}
+
+#[pg_extern(immutable, strict, parallel_safe)]
+fn ring_path_support(request: Internal) -> Internal {
+    rows_support(request, |args| {
+        Some(HexRingPathIter::size(int_arg(args, 1)?))
+    })
+}
+
+#[pg_extern(immutable, strict, parallel_safe)]
+fn spiral_path_support(request: Internal) -> Internal {
+    rows_support(request, |args| {
+        Some(HexSpiralPathIter::size(int_arg(args, 1)?))
+    })
+}
+
+#[pg_extern(immutable, strict, parallel_safe)]
+fn linedraw_support(request: Internal) -> Internal {
+    rows_support(request, |args| {
+        let start = hex_arg(args, 0)?;
+        let end = hex_arg(args, 1)?;
+        Some(HexLineDrawIter::size(start.into(), end.into()))
+    })
+}
+
+// When the arguments aren't constants, the estimates are for a radius of 5 and
+// a line 10 long
+extension_sql!(
+    r#"
+ALTER FUNCTION hexes_in_range(hex, integer) ROWS 91 SUPPORT hexes_in_range_support;
+ALTER FUNCTION hexes_in_annulus(hex, integer, integer) ROWS 91 SUPPORT hexes_in_annulus_support;
+ALTER FUNCTION ring_path(hex, integer) ROWS 30 SUPPORT ring_path_support;
+ALTER FUNCTION spiral_path(hex, integer) ROWS 91 SUPPORT spiral_path_support;
+ALTER FUNCTION linedraw(hex, hex, text) ROWS 11 SUPPORT linedraw_support;
+ALTER FUNCTION linedraw_both_sides(hex, hex) ROWS 11 SUPPORT linedraw_support;
+ALTER FUNCTION neighbors(hex) ROWS 6;
+ALTER FUNCTION diagonals(hex) ROWS 6;
+"#,
+    name = "set_returning_rows",
+    requires = [
+        hexes_in_range,
+        hexes_in_annulus,
+        ring_path,
+        spiral_path,
+        linedraw,
+        linedraw_both_sides,
+        neighbors,
+        diagonals,
+        hexes_in_range_support,
+        hexes_in_annulus_support,
+        ring_path_support,
+        spiral_path_support,
+        linedraw_support
+    ],
+);
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use crate::tests::{estimated_rows, explain, explain_with};
+    use pgrx::prelude::*;
+
+    fn create_units(index_method: &str) {
+        Spi::run("create table units (position hex)").unwrap();
+        Spi::run("insert into units select hexes_in_range('(0,0)', 30)").unwrap();
+        Spi::run(&format!(
+            "create index units_position_idx on units using {} (position)",
+            index_method
+        ))
+        .unwrap();
+        Spi::run("analyze units").unwrap();
+        Spi::run("set local enable_seqscan = off").unwrap();
+    }
+
+    #[pg_test]
+    fn test_hex_dwithin() {
+        let value = Spi::get_one::<bool>(
+            "select hex_dwithin('(1,2)', '(3,1)', 2)
+                and not hex_dwithin('(1,2)', '(4,1)', 2)
+                and hex_dwithin('(1,2)', '(1,2)', 0)",
+        )
+        .unwrap()
+        .unwrap();
+        assert!(value);
+    }
+
+    #[pg_test(error = "hex_dwithin distance must not be negative, but is -1")]
+    fn test_hex_dwithin_negative() {
+        Spi::get_one::<bool>("select hex_dwithin('(1,2)', '(3,1)', -1)").unwrap();
+    }
+
+    #[pg_test]
+    fn test_hex_dwithin_uses_gist_index() {
+        create_units("gist");
+
+        let query = "select count(*) from units where hex_dwithin(position, '(5,5)', 3)";
+        let plan = explain(query);
+        assert!(plan.contains("units_position_idx"));
+        assert!(plan.contains("Index Cond: (position <@ hexrange('(5,5)'::hex, 3))"));
+        let value = Spi::get_one::<i64>(query).unwrap().unwrap();
+        assert_eq!(value, 37);
+
+        // Either argument can be the indexed one
+        let query = "select count(*) from units where hex_dwithin('(5,5)', position, 3)";
+        assert!(explain(query).contains("units_position_idx"));
+        let value = Spi::get_one::<i64>(query).unwrap().unwrap();
+        assert_eq!(value, 37);
+    }
+
+    #[pg_test]
+    fn test_hex_dwithin_uses_spgist_index() {
+        create_units("spgist");
+
+        let query = "select count(*) from units where hex_dwithin(position, '(-2,7)', 2)";
+        assert!(explain(query).contains("units_position_idx"));
+        let value = Spi::get_one::<i64>(query).unwrap().unwrap();
+        assert_eq!(value, 19);
+    }
+
+    #[pg_test]
+    fn test_hex_dwithin_join_uses_index() {
+        create_units("gist");
+        Spi::run("create table towers (position hex)").unwrap();
+        Spi::run("insert into towers values ('(0,0)'), ('(10,-4)')").unwrap();
+        Spi::run("analyze towers").unwrap();
+
+        let query =
+            "select count(*) from towers t join units u on hex_dwithin(u.position, t.position, 1)";
+        assert!(explain(query).contains("units_position_idx"));
+        let value = Spi::get_one::<i64>(query).unwrap().unwrap();
+        assert_eq!(value, 14);
+    }
+
+    #[pg_test]
+    fn test_hex_distance_comparison_is_not_indexed() {
+        create_units("gist");
+
+        // The planner only asks `<=` for its support function, so the
+        // comparison can't use the index even with sequential scans off,
+        // while `hex_dwithin` asking the same question can
+        let query = "select count(*) from units where hex_distance(position, '(5,5)') <= 3";
+        let plan = explain(query);
+        assert!(plan.contains("Seq Scan on units"));
+        assert!(!plan.contains("units_position_idx"));
+        let value = Spi::get_one::<i64>(query).unwrap().unwrap();
+        assert_eq!(value, 37);
+
+        let query = "select count(*) from units where hex_dwithin(position, '(5,5)', 3)";
+        let plan = explain(query);
+        assert!(!plan.contains("Seq Scan"));
+        assert!(plan.contains("units_position_idx"));
+    }
+
+    #[pg_test]
+    fn test_set_returning_rows_from_constants() {
+        assert_eq!(
+            estimated_rows("select * from hexes_in_range('(1,2)', 3)"),
+            37
+        );
+        assert_eq!(
+            estimated_rows("select * from hexes_in_range('(1,2)', 0)"),
+            1
+        );
+        assert_eq!(
+            estimated_rows("select * from hexes_in_annulus('(1,2)', 2, 3)"),
+            30
+        );
+        assert_eq!(estimated_rows("select * from ring_path('(1,2)', 4)"), 24);
+        assert_eq!(estimated_rows("select * from ring_path('(1,2)', 0)"), 1);
+        assert_eq!(estimated_rows("select * from spiral_path('(1,2)', 2)"), 19);
+        assert_eq!(
+            estimated_rows("select * from linedraw('(0,0)', '(3,-1)')"),
+            4
+        );
+        assert_eq!(estimated_rows("select * from neighbors('(1,2)')"), 6);
+        assert_eq!(estimated_rows("select * from diagonals('(1,2)')"), 6);
+    }
+
+    #[pg_test]
+    fn test_set_returning_rows_huge() {
+        // Counts past an i64 saturate rather than overflowing
+        let plan = explain_with(
+            "costs on",
+            "select * from hexes_in_range('(0,0)', 2000000000)",
+        );
+        assert!(plan.contains("rows=9223372036854775808 "));
+        assert_eq!(
+            estimated_rows("select * from hexes_in_annulus('(0,0)', 2000000000, 2000000001)"),
+            24000000006
+        );
+    }
+
+    #[pg_test]
+    fn test_set_returning_rows_from_center_column() {
+        // The size of a range doesn't depend on its center
+        Spi::run("create table units (position hex)").unwrap();
+        assert_eq!(
+            estimated_rows("select * from hexes_in_range((select position from units limit 1), 4)"),
+            61
+        );
+    }
+
+    #[pg_test]
+    fn test_set_returning_rows_defaults() {
+        assert_eq!(
+            estimated_rows("select * from hexes_in_range('(1,2)', (select 3))"),
+            91
+        );
+        assert_eq!(
+            estimated_rows("select * from ring_path('(1,2)', (select 3))"),
+            30
+        );
+        assert_eq!(
+            estimated_rows("select * from spiral_path('(1,2)', (select 3))"),
+            91
+        );
+        assert_eq!(
+            estimated_rows("select * from linedraw('(0,0)', (select '(3,-1)'::hex))"),
+            11
+        );
+    }
+}
@@ -0,0 +1,435 @@
+//! What can be seen from a hex past the walls and other blockers around it,
+//! over the terrain when it has heights, or through smoke and forests that
+//! obscure the view without blocking it.
+
+use std::collections::{HashMap, HashSet};
+
+use pgrx::prelude::*;
+
+use crate::hex_alg::{self, CubeCoord};
+use crate::{hex_values, query_hex_values, Hex};
+
+/// The hexes of an array, ignoring nulls
+fn hex_set(hexes: Array<Hex>) -> HashSet<CubeCoord> {
+    hexes.iter().flatten().map(CubeCoord::from).collect()
+}
+
+/// Every hex within `radius` of `origin` that can be seen from it past the
+/// `blockers`, nearest first: those that the line from the center of `origin`
+/// to their centers reaches without passing through the inside of a blocker,
+/// or between two blockers along the edge they share. Visibility is
+/// symmetric, so each of these can see `origin` too. Blockers in view are
+/// included unless `walls_visible` is false.
+#[pg_extern(immutable, strict, parallel_safe)]
+fn field_of_view(
+    origin: Hex,
+    radius: i32,
+    blockers: default!(Array<Hex>, "'{}'"),
+    walls_visible: default!(bool, true),
+) -> SetOfIterator<'static, Hex> {
+    let blockers = hex_set(blockers);
+    SetOfIterator::new(
+        hex_alg::field_of_view(origin.into(), radius, &blockers, walls_visible)
+            .into_iter()
+            .map(Hex::from),
+    )
+}
+
+/// Each hex a ray from `origin` passes through at `angle_degrees`,
+/// counterclockwise from the direction of `(1,0)` with `(1,-1)` at 60°, one
+/// step further from `origin` at a time. The ray stops at `max_range`, or at
+/// the first of the `blockers` it reaches, which is the last row and is
+/// `blocked`. A blocker at `origin` itself doesn't stop it.
+#[pg_extern(immutable, strict, parallel_safe)]
+fn hex_raycast(
+    origin: Hex,
+    angle_degrees: f64,
+    max_range: i32,
+    blockers: default!(Array<Hex>, "'{}'"),
+) -> TableIterator<'static, (name!(step, i32), name!(hex, Hex), name!(blocked, bool))> {
+    if !angle_degrees.is_finite() {
+        ereport!(
+            ERROR,
+            PgSqlErrorCode::ERRCODE_INVALID_PARAMETER_VALUE,
+            format!("angle_degrees must be finite, but is {}", angle_degrees)
+        );
+    }
+    let blockers = hex_set(blockers);
+    let mut rows = Vec::new();
+    for (step, coord) in CubeCoord::from(origin)
+        .ray(angle_degrees, max_range)
+        .enumerate()
+    {
+        let blocked = step > 0 && blockers.contains(&coord);
+        rows.push((step as i32, coord.into(), blocked));
+        if blocked {
+            break;
+        }
+    }
+    TableIterator::new(rows)
+}
+
+/// The elevation of each of `height_hexes` from `heights`, where null heights
+/// are ignored
+fn elevation(height_hexes: Array<Hex>, heights: Array<f64>) -> HashMap<CubeCoord, f64> {
+    hex_values(&height_hexes, &heights, ["height_hexes", "heights"])
+        .into_iter()
+        .filter_map(|(hex, height)| Some((hex.into(), height?)))
+        .collect()
+}
+
+/// Whether a viewer `from_height` above the ground at `from` can see a point
+/// `to_height` above the ground at `to`. The ground of each of `height_hexes`
+/// is at the corresponding element of `heights`, and of every other hex at 0.
+#[pg_extern(immutable, strict, parallel_safe)]
+fn line_of_sight_elevated(
+    from: Hex,
+    from_height: f64,
+    to: Hex,
+    to_height: f64,
+    height_hexes: default!(Array<Hex>, "'{}'"),
+    heights: default!(Array<f64>, "'{}'"),
+) -> bool {
+    let elevation = elevation(height_hexes, heights);
+    hex_alg::line_of_sight_elevated(from.into(), from_height, to.into(), to_height, &elevation)
+}
+
+/// Every hex within `radius` of `origin` where a point `target_height` above
+/// the ground can be seen by a viewer `viewer_height` above the ground at
+/// `origin`, nearest first, over the same terrain as `line_of_sight_elevated`.
+/// Each hex is checked along a line of its own, so the time this takes grows
+/// with the cube of `radius`.
+#[pg_extern(immutable, strict, parallel_safe)]
+fn field_of_view_elevated(
+    origin: Hex,
+    radius: i32,
+    viewer_height: f64,
+    height_hexes: default!(Array<Hex>, "'{}'"),
+    heights: default!(Array<f64>, "'{}'"),
+    target_height: default!(f64, 0.0),
+) -> SetOfIterator<'static, Hex> {
+    let elevation = elevation(height_hexes, heights);
+    SetOfIterator::new(
+        hex_alg::field_of_view_elevated(
+            origin.into(),
+            radius,
+            viewer_height,
+            target_height,
+            &elevation,
+        )
+        .into_iter()
+        .map(Hex::from),
+    )
+}
+
+/// The opacity of each hex, ignoring null opacities
+fn opacity(values: Vec<(Hex, Option<f64>)>) -> HashMap<CubeCoord, f64> {
+    values
+        .into_iter()
+        .filter_map(|(hex, opacity)| {
+            let opacity = opacity?;
+            if opacity.is_nan() || opacity < 0.0 {
+                ereport!(
+                    ERROR,
+                    PgSqlErrorCode::ERRCODE_INVALID_PARAMETER_VALUE,
+                    format!(
+                        "opacity of {} must not be negative, but is {}",
+                        hex, opacity
+                    )
+                );
+            }
+            Some((hex.into(), opacity))
+        })
+        .collect()
+}
+
+fn array_opacity(opacity_hexes: Array<Hex>, opacities: Array<f64>) -> HashMap<CubeCoord, f64> {
+    opacity(hex_values(
+        &opacity_hexes,
+        &opacities,
+        ["opacity_hexes", "opacities"],
+    ))
+}
+
+/// The row of the total opacity between two hexes, and whether it's below
+/// `threshold`
+fn obscurement_row(
+    from: Hex,
+    to: Hex,
+    opacity: &HashMap<CubeCoord, f64>,
+    threshold: f64,
+) -> (f64, bool) {
+    let total = hex_alg::obscurement(from.into(), to.into(), opacity);
+    (total, total < threshold)
+}
+
+/// Rows of the total opacity between `origin` and every hex within `radius`,
+/// nearest first, and whether it's below `threshold`
+fn obscurement_rows(
+    origin: Hex,
+    radius: i32,
+    opacity: &HashMap<CubeCoord, f64>,
+    threshold: f64,
+) -> Vec<(Hex, f64, bool)> {
+    hex_alg::obscurement_in_range(origin.into(), radius, opacity)
+        .into_iter()
+        .map(|(coord, total)| (coord.into(), total, total < threshold))
+        .collect()
+}
+
+/// The total opacity of the hexes between `from` and `to` along the line
+/// between them, where each of `opacity_hexes` has the corresponding element
+/// of `opacities` and every other hex is clear, and whether `to` is visible
+/// because the total is below `threshold`
+#[pg_extern(immutable, strict, parallel_safe)]
+fn line_of_sight_obscured(
+    from: Hex,
+    to: Hex,
+    opacity_hexes: default!(Array<Hex>, "'{}'"),
+    opacities: default!(Array<f64>, "'{}'"),
+    threshold: default!(f64, 1.0),
+) -> TableIterator<'static, (name!(obscurement, f64), name!(visible, bool))> {
+    let opacity = array_opacity(opacity_hexes, opacities);
+    TableIterator::once(obscurement_row(from, to, &opacity, threshold))
+}
+
+/// `line_of_sight_obscured` with the opacities returned by `opacity_query`,
+/// which returns each hex that isn't clear and its opacity
+#[pg_extern(stable, strict)]
+fn line_of_sight_obscured_query(
+    from: Hex,
+    to: Hex,
+    opacity_query: &str,
+    threshold: default!(f64, 1.0),
+) -> TableIterator<'static, (name!(obscurement, f64), name!(visible, bool))> {
+    let opacity = opacity(query_hex_values(opacity_query, "opacities"));
+    TableIterator::once(obscurement_row(from, to, &opacity, threshold))
+}
+
+/// `line_of_sight_obscured` from `origin` to every hex within `radius` of it,
+/// nearest first. The opacities along each line are added up afresh, so the
+/// time this takes grows with the cube of `radius`.
+#[pg_extern(immutable, strict, parallel_safe)]
+fn field_of_view_obscured(
+    origin: Hex,
+    radius: i32,
+    opacity_hexes: default!(Array<Hex>, "'{}'"),
+    opacities: default!(Array<f64>, "'{}'"),
+    threshold: default!(f64, 1.0),
+) -> TableIterator<
+    'static,
+    (
+        name!(hex, Hex),
+        name!(obscurement, f64),
+        name!(visible, bool),
+    ),
+> {
+    let opacity = array_opacity(opacity_hexes, opacities);
+    TableIterator::new(obscurement_rows(origin, radius, &opacity, threshold))
+}
+
+/// `field_of_view_obscured` with the opacities returned by `opacity_query`,
+/// taking time that grows with the cube of `radius` in the same way
+#[pg_extern(stable, strict)]
+fn field_of_view_obscured_query(
+    origin: Hex,
+    radius: i32,
+    opacity_query: &str,
+    threshold: default!(f64, 1.0),
+) -> TableIterator<
+    'static,
+    (
+        name!(hex, Hex),
+        name!(obscurement, f64),
+        name!(visible, bool),
+    ),
+> {
+    let opacity = opacity(query_hex_values(opacity_query, "opacities"));
+    TableIterator::new(obscurement_rows(origin, radius, &opacity, threshold))
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use pgrx::prelude::*;
+
+    #[pg_test]
+    fn test_field_of_view_open() {
+        let value = Spi::get_one::<i64>("select count(*) from field_of_view('(3,-2)', 4)").unwrap();
+        assert_eq!(value, Some(61));
+    }
+
+    #[pg_test]
+    fn test_field_of_view_behind_wall() {
+        let (hidden, beside, wall) = Spi::get_three::<bool, bool, bool>(
+            "with fov as (
+                select field_of_view('(0,0)', 4, array['(2,-1)', '(2,0)', '(2,-2)']::hex[]) hex
+            )
+            select
+                exists (select from fov where hex in ('(3,-1)', '(4,-2)')),
+                exists (select from fov where hex = '(2,1)'),
+                exists (select from fov where hex = '(2,0)')",
+        )
+        .unwrap();
+        assert_eq!(hidden, Some(false));
+        assert_eq!(beside, Some(true));
+        assert_eq!(wall, Some(true));
+    }
+
+    #[pg_test]
+    fn test_field_of_view_walls_invisible() {
+        let value = Spi::get_one::<i64>(
+            "select count(*) from field_of_view('(0,0)', 4,
+                array['(2,-1)', '(2,0)', '(2,-2)']::hex[], walls_visible => false)
+            where hex_distance(field_of_view, '(2,-1)') <= 1",
+        )
+        .unwrap();
+        // Of (2,-1) and its neighbors, only the two on the near side of the wall
+        assert_eq!(value, Some(2));
+    }
+
+    #[pg_test]
+    fn test_field_of_view_symmetric() {
+        Spi::run("create table walls (coord hex)").unwrap();
+        Spi::run(
+            "insert into walls values ('(1,-1)'), ('(2,0)'), ('(-1,2)'), ('(-2,0)'), ('(0,-3)')",
+        )
+        .unwrap();
+        let value = Spi::get_one::<i64>(
+            "with floors as (
+                select hexes_in_range('(0,0)', 3) coord
+                except select coord from walls
+            ),
+            seen as (
+                select a.coord a, b b
+                from floors a,
+                    field_of_view(a.coord, 6, array(select coord from walls), false) b
+            )
+            select count(*) from seen
+            where b in (select coord from floors)
+                and not exists (select from seen reverse where reverse.a = seen.b and reverse.b = seen.a)",
+        )
+        .unwrap();
+        assert_eq!(value, Some(0));
+    }
+
+    #[pg_test]
+    fn test_line_of_sight_elevated() {
+        let value = Spi::get_one::<String>(
+            "select concat_ws(' ',
+                line_of_sight_elevated('(0,0)', 1, '(4,0)', 0),
+                line_of_sight_elevated('(0,0)', 1, '(4,0)', 0, array['(2,0)']::hex[], array[2]),
+                line_of_sight_elevated('(0,0)', 5, '(4,0)', 0, array['(2,0)']::hex[], array[2]),
+                line_of_sight_elevated('(0,0)', 1, '(4,0)', 3, array['(2,0)']::hex[], array[2]))",
+        )
+        .unwrap();
+        assert_eq!(value, Some("true false true true".into()));
+    }
+
+    #[pg_test]
+    fn test_field_of_view_elevated() {
+        Spi::run("create table terrain (position hex, height float8)").unwrap();
+        Spi::run("insert into terrain select ring_path('(0,0)', 2), 2").unwrap();
+
+        let (low, high) = Spi::get_two::<i64, i64>(
+            "select
+                (select count(*) from field_of_view_elevated('(0,0)', 4, 1,
+                    array(select position from terrain), array(select height from terrain))),
+                (select count(*) from field_of_view_elevated('(0,0)', 4, 4,
+                    array(select position from terrain), array(select height from terrain)))",
+        )
+        .unwrap();
+        // Walled in, or seeing over the wall to all but the ring right behind it
+        assert_eq!(low, Some(19));
+        assert_eq!(high, Some(61 - 18));
+    }
+
+    #[pg_test(
+        error = "height_hexes and heights must be the same length, but have 1 and 0 elements"
+    )]
+    fn test_line_of_sight_elevated_mismatched_heights() {
+        Spi::run(
+            "select line_of_sight_elevated('(0,0)', 1, '(4,0)', 0, array['(2,0)']::hex[], '{}')",
+        )
+        .unwrap();
+    }
+
+    #[pg_test]
+    fn test_line_of_sight_obscured() {
+        let value = Spi::get_one::<String>(
+            "select string_agg(format('%s:%s', obscurement, visible::text), ' ')
+            from (values ('(4,0)'::hex), ('(2,0)'), ('(0,4)')) target (hex),
+                line_of_sight_obscured('(0,0)', hex,
+                    array['(1,0)', '(2,0)', '(3,0)']::hex[], array[0.25, 0.5, 0.5])",
+        )
+        .unwrap();
+        assert_eq!(value, Some("1.25:false 0.25:true 0:true".into()));
+    }
+
+    #[pg_test]
+    fn test_line_of_sight_obscured_query() {
+        Spi::run("create table smoke (position hex, density numeric)").unwrap();
+        Spi::run("insert into smoke values ('(1,0)', 0.25), ('(2,0)', 0.5), ('(3,0)', null)")
+            .unwrap();
+        let (obscurement, visible) = Spi::get_two::<f64, bool>(
+            "select * from line_of_sight_obscured_query('(0,0)', '(4,0)',
+                'select position, density from smoke', threshold => 0.5)",
+        )
+        .unwrap();
+        assert_eq!(obscurement, Some(0.75));
+        assert_eq!(visible, Some(false));
+    }
+
+    #[pg_test]
+    fn test_field_of_view_obscured() {
+        let (count, visible) = Spi::get_two::<i64, i64>(
+            "select count(*), count(*) filter (where visible)
+            from field_of_view_obscured('(0,0)', 3,
+                array(select ring_path('(0,0)', 1)), array_fill(1.0::float8, array[6]))",
+        )
+        .unwrap();
+        // A ring of forest, which can be seen into but not through
+        assert_eq!(count, Some(37));
+        assert_eq!(visible, Some(1 + 6));
+
+        let value = Spi::get_one::<i64>(
+            "select count(*) from field_of_view_obscured_query('(0,0)', 3,
+                'select ring_path(''(0,0)'', 1), 1', threshold => 2)
+            where visible",
+        )
+        .unwrap();
+        assert_eq!(value, Some(37));
+    }
+
+    #[pg_test(error = "opacity of (1,0) must not be negative, but is -0.5")]
+    fn test_line_of_sight_obscured_negative() {
+        Spi::run("select * from line_of_sight_obscured('(0,0)', '(4,0)', array['(1,0)']::hex[], array[-0.5])")
+            .unwrap();
+    }
+
+    #[pg_test]
+    fn test_hex_raycast() {
+        let ray = |query: &str| {
+            Spi::get_one::<String>(&format!(
+                "select string_agg(format('%s:%s:%s', step, hex, blocked::text), ' ') from {}",
+                query
+            ))
+            .unwrap()
+            .unwrap()
+        };
+        assert_eq!(
+            ray("hex_raycast('(1,1)', 60, 3)"),
+            "0:(1,1):false 1:(2,0):false 2:(3,-1):false 3:(4,-2):false"
+        );
+        assert_eq!(
+            ray("hex_raycast('(0,0)', 180, 10, array['(0,0)', '(-2,0)', '(-4,0)']::hex[])"),
+            "0:(0,0):false 1:(-1,0):false 2:(-2,0):true"
+        );
+        assert_eq!(ray("hex_raycast('(0,0)', 30, 0)"), "0:(0,0):false");
+    }
+
+    #[pg_test(error = "angle_degrees must be finite, but is NaN")]
+    fn test_hex_raycast_nan() {
+        Spi::run("select * from hex_raycast('(0,0)', 'nan', 3)").unwrap();
+    }
+}